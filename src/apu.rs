@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 use crate::interrupts::InterruptHandler;
 use crate::memory::Memory;
 use crate::SystemState;
@@ -7,22 +9,1032 @@ pub const SOUND_END: u16 = 0xFF26;
 pub const WAVE_START: u16 = 0xFF30;
 pub const WAVE_END: u16 = 0xFF3F;
 
-pub(crate) struct Apu {}
+const NR10: u16 = 0xFF10;
+const NR11: u16 = 0xFF11;
+const NR12: u16 = 0xFF12;
+const NR13: u16 = 0xFF13;
+const NR14: u16 = 0xFF14;
+const NR21: u16 = 0xFF16;
+const NR22: u16 = 0xFF17;
+const NR23: u16 = 0xFF18;
+const NR24: u16 = 0xFF19;
+const NR30: u16 = 0xFF1A;
+const NR31: u16 = 0xFF1B;
+const NR32: u16 = 0xFF1C;
+const NR33: u16 = 0xFF1D;
+const NR34: u16 = 0xFF1E;
+const NR41: u16 = 0xFF20;
+const NR42: u16 = 0xFF21;
+const NR43: u16 = 0xFF22;
+const NR44: u16 = 0xFF23;
+const NR50: u16 = 0xFF24;
+const NR51: u16 = 0xFF25;
+const NR52: u16 = 0xFF26;
+
+/// Bits which always read back as 1 for each register in `SOUND_START..=SOUND_END`. Write-only
+/// and unused registers read back as `0xFF`
+const READ_MASKS: [u8; (SOUND_END - SOUND_START + 1) as usize] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // Unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // Unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+/// The frame sequencer runs at 512Hz, i.e., every 8192 t-cycles
+const FRAME_SEQUENCER_PERIOD: u32 = 8192;
+
+/// The APU generates one stereo sample every `CYCLES_PER_SAMPLE` t-cycles by averaging the mixer
+/// output over those cycles
+pub const CYCLES_PER_SAMPLE: u32 = 64;
+/// Rate (in Hz) at which the APU generates samples
+pub const APU_SAMPLE_RATE: u32 = 4_194_304 / CYCLES_PER_SAMPLE;
+/// The sample buffer is capped to one second of audio in case nobody is consuming them
+const MAX_BUFFERED_SAMPLES: usize = APU_SAMPLE_RATE as usize;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct StereoSample {
+    pub left: f32,
+    pub right: f32,
+}
+
+/// Convert the digital output (0-15) of a channel to the analog output of its DAC
+fn dac_output(digital: u8) -> f32 {
+    1.0 - (digital as f32 / 7.5)
+}
+
+// Length Counter ----------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct LengthCounter {
+    counter: u16,
+    enabled: bool,
+    /// 64 for all channels but the wave channel which uses 256
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        Self {
+            counter: 0,
+            enabled: false,
+            max,
+        }
+    }
+
+    fn load(&mut self, length: u8) {
+        self.counter = self.max - length as u16;
+    }
+
+    /// Clock the counter returning `true` if the channel should be disabled
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+
+        false
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+}
+// END-Length Counter ------------------------------------------------------------------------------
+
+// Volume Envelope ---------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct VolumeEnvelope {
+    /// NRx2
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl VolumeEnvelope {
+    fn initial_volume(&self) -> u8 {
+        self.register >> 4
+    }
+
+    fn increasing(&self) -> bool {
+        self.register & 0x08 != 0
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.register & 0xF8 != 0
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume();
+        self.timer = self.period();
+    }
+
+    fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.increasing() && self.volume < 0x0F {
+                self.volume += 1;
+            } else if !self.increasing() && self.volume > 0x00 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+// END-Volume Envelope -----------------------------------------------------------------------------
+
+// Square Channel (Channels 1 & 2) -----------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Sweep {
+    /// NR10
+    register: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    timer: u8,
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.register & 0x70) >> 4
+    }
+
+    fn decreasing(&self) -> bool {
+        self.register & 0x08 != 0
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn reload_timer(&mut self) {
+        // A sweep period of 0 is treated as 8 by the timer
+        self.timer = if self.period() == 0 { 8 } else { self.period() };
+    }
+
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift();
+        if self.decreasing() {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct SquareChannel {
+    enabled: bool,
+    /// Only channel 1 has a frequency sweep
+    sweep: Option<Sweep>,
+    length: LengthCounter,
+    envelope: VolumeEnvelope,
+
+    duty: u8,
+    duty_position: usize,
+    frequency: u16,
+    frequency_timer: u32,
+}
+
+impl SquareChannel {
+    fn new(has_sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            sweep: has_sweep.then(Sweep::default),
+            length: LengthCounter::new(64),
+            envelope: Default::default(),
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            frequency_timer: 0,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => self.sweep.map_or(0x00, |sweep| sweep.register),
+            1 => self.duty << 6,
+            2 => self.envelope.register,
+            3 => 0x00,
+            4 => u8::from(self.length.enabled) << 6,
+            _ => panic!("Invalid register {} for square channel", register),
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.register = data & 0x7F;
+                }
+            }
+            1 => {
+                self.duty = data >> 6;
+                self.length.load(data & 0x3F);
+            }
+            2 => {
+                self.envelope.register = data;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((data as u16 & 0x07) << 8);
+                self.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("Invalid register {} for square channel", register),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.frequency_timer = self.period();
+        self.envelope.trigger();
+
+        if let Some(mut sweep) = self.sweep {
+            sweep.shadow_frequency = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            // The overflow check is performed immediately if the shift is non-zero
+            if sweep.shift() != 0 && sweep.next_frequency() > 0x7FF {
+                self.enabled = false;
+            }
+            self.sweep = Some(sweep);
+        }
+    }
+
+    fn step(&mut self) {
+        if self.frequency_timer > 0 {
+            self.frequency_timer -= 1;
+        }
+
+        if self.frequency_timer == 0 {
+            self.frequency_timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let Some(mut sweep) = self.sweep else {
+            return;
+        };
+
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+
+        if sweep.timer == 0 {
+            sweep.reload_timer();
+
+            if sweep.enabled && sweep.period() != 0 {
+                let new_frequency = sweep.next_frequency();
+                if new_frequency > 0x7FF {
+                    self.enabled = false;
+                } else if sweep.shift() != 0 {
+                    sweep.shadow_frequency = new_frequency;
+                    self.frequency = new_frequency;
+
+                    // The overflow check is run again with the new frequency
+                    if sweep.next_frequency() > 0x7FF {
+                        self.enabled = false;
+                    }
+                }
+            }
+        }
+
+        self.sweep = Some(sweep);
+    }
+
+    /// Digital output (0-15) of the channel
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        DUTY_PATTERNS[self.duty as usize][self.duty_position] * self.envelope.volume
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+}
+// END-Square Channel ------------------------------------------------------------------------------
+
+// Wave Channel (Channel 3) ------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    length: LengthCounter,
+
+    /// Volume code from NR32
+    volume_code: u8,
+    frequency: u16,
+    frequency_timer: u32,
+
+    wave_ram: [u8; (WAVE_END - WAVE_START + 1) as usize],
+    /// Index of the 4-bit sample (0-31) being played
+    position: usize,
+    sample_buffer: u8,
+}
+
+impl WaveChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            frequency_timer: 0,
+            wave_ram: [0x00; (WAVE_END - WAVE_START + 1) as usize],
+            position: 0,
+            sample_buffer: 0,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => u8::from(self.dac_enabled) << 7,
+            1 => 0x00,
+            2 => self.volume_code << 5,
+            3 => 0x00,
+            4 => u8::from(self.length.enabled) << 6,
+            _ => panic!("Invalid register {} for wave channel", register),
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = data & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(data),
+            2 => self.volume_code = (data >> 5) & 0b11,
+            3 => self.frequency = (self.frequency & 0x700) | data as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((data as u16 & 0x07) << 8);
+                self.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("Invalid register {} for wave channel", register),
+        }
+    }
+
+    /// While the channel is playing, wave RAM accesses go to the byte currently being played
+    fn wave_ram_index(&self, address: u16) -> usize {
+        if self.enabled {
+            self.position / 2
+        } else {
+            (address - WAVE_START) as usize
+        }
+    }
+
+    fn read_wave_ram(&self, address: u16) -> u8 {
+        self.wave_ram[self.wave_ram_index(address)]
+    }
+
+    fn write_wave_ram(&mut self, address: u16, data: u8) {
+        let index = self.wave_ram_index(address);
+        self.wave_ram[index] = data;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.frequency_timer = self.period();
+        self.position = 0;
+    }
+
+    fn step(&mut self) {
+        if self.frequency_timer > 0 {
+            self.frequency_timer -= 1;
+        }
+
+        if self.frequency_timer == 0 {
+            self.frequency_timer = self.period();
+            self.position = (self.position + 1) % 32;
+
+            let byte = self.wave_ram[self.position / 2];
+            // The upper nibble is played first
+            self.sample_buffer = if self.position & 0b1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample_buffer,
+            2 => self.sample_buffer >> 1,
+            3 => self.sample_buffer >> 2,
+            _ => panic!("Invalid volume code {} for wave channel", self.volume_code),
+        }
+    }
+}
+// END-Wave Channel --------------------------------------------------------------------------------
+
+// Noise Channel (Channel 4) -----------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct NoiseChannel {
+    enabled: bool,
+    length: LengthCounter,
+    envelope: VolumeEnvelope,
+
+    /// NR43
+    polynomial: u8,
+    frequency_timer: u32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Default::default(),
+            polynomial: 0,
+            frequency_timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        let divisor = NOISE_DIVISORS[(self.polynomial & 0x07) as usize];
+        divisor << (self.polynomial >> 4)
+    }
+
+    fn short_mode(&self) -> bool {
+        self.polynomial & 0x08 != 0
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        match register {
+            1 => 0x00,
+            2 => self.envelope.register,
+            3 => self.polynomial,
+            4 => u8::from(self.length.enabled) << 6,
+            _ => panic!("Invalid register {} for noise channel", register),
+        }
+    }
+
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            1 => self.length.load(data & 0x3F),
+            2 => {
+                self.envelope.register = data;
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.polynomial = data,
+            4 => {
+                self.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger();
+                }
+            }
+            _ => panic!("Invalid register {} for noise channel", register),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.frequency_timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn step(&mut self) {
+        if self.frequency_timer > 0 {
+            self.frequency_timer -= 1;
+        }
+
+        if self.frequency_timer == 0 {
+            self.frequency_timer = self.period();
+
+            let xor = (self.lfsr & 0b1) ^ ((self.lfsr & 0b10) >> 1);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.short_mode() {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        // The output is the inverted bit 0 of the LFSR
+        u8::from(self.lfsr & 0b1 == 0) * self.envelope.volume
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+}
+// END-Noise Channel -------------------------------------------------------------------------------
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Apu {
+    enabled: bool,
+
+    channel1: SquareChannel,
+    channel2: SquareChannel,
+    channel3: WaveChannel,
+    channel4: NoiseChannel,
+
+    /// NR50
+    master_volume: u8,
+    /// NR51
+    panning: u8,
+
+    frame_sequencer_cycles: u32,
+    frame_sequencer_step: u8,
+
+    sample_cycles: u32,
+    sample_accumulator: StereoSample,
+    // Generated output, not part of the machine state
+    #[serde(skip)]
+    samples: Vec<StereoSample>,
+}
 
 impl Apu {
     pub fn new() -> Self {
-        Apu {}
+        Apu {
+            enabled: false,
+            channel1: SquareChannel::new(true),
+            channel2: SquareChannel::new(false),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            master_volume: 0x00,
+            panning: 0x00,
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
+            sample_cycles: 0,
+            sample_accumulator: Default::default(),
+            samples: Vec::with_capacity(MAX_BUFFERED_SAMPLES),
+        }
     }
 
-    pub fn tick(&mut self, _system_state: &mut SystemState, _interrupts: &mut InterruptHandler) {
+    pub fn tick(&mut self, system_state: &mut SystemState, _interrupts: &mut InterruptHandler) {
         // The APU is not affected by CGB double speed mode
+        let cycles_to_tick = 4 / system_state.speed_divider() as u32;
+        for _ in 0..cycles_to_tick {
+            self.step();
+        }
+    }
+
+    /// Take all the samples generated since the last call
+    pub fn take_samples(&mut self) -> Vec<StereoSample> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn step(&mut self) {
+        if self.enabled {
+            self.frame_sequencer_cycles += 1;
+            if self.frame_sequencer_cycles == FRAME_SEQUENCER_PERIOD {
+                self.frame_sequencer_cycles = 0;
+                self.clock_frame_sequencer();
+            }
+
+            self.channel1.step();
+            self.channel2.step();
+            self.channel3.step();
+            self.channel4.step();
+        }
+
+        let sample = self.mix();
+        self.sample_accumulator.left += sample.left;
+        self.sample_accumulator.right += sample.right;
+        self.sample_cycles += 1;
+
+        if self.sample_cycles == CYCLES_PER_SAMPLE {
+            let sample = StereoSample {
+                left: self.sample_accumulator.left / CYCLES_PER_SAMPLE as f32,
+                right: self.sample_accumulator.right / CYCLES_PER_SAMPLE as f32,
+            };
+            if self.samples.len() < MAX_BUFFERED_SAMPLES {
+                self.samples.push(sample);
+            }
+
+            self.sample_cycles = 0;
+            self.sample_accumulator = Default::default();
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        // Step   Length Ctr  Vol Env     Sweep
+        // ---------------------------------------
+        // 0      Clock       -           -
+        // 1      -           -           -
+        // 2      Clock       -           Clock
+        // 3      -           -           -
+        // 4      Clock       -           -
+        // 5      -           -           -
+        // 6      Clock       -           Clock
+        // 7      -           Clock       -
+        match self.frame_sequencer_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.channel1.clock_sweep();
+            }
+            7 => {
+                self.channel1.envelope.clock();
+                self.channel2.envelope.clock();
+                self.channel4.envelope.clock();
+            }
+            _ => {}
+        }
+
+        self.frame_sequencer_step = (self.frame_sequencer_step + 1) % 8;
+    }
+
+    fn clock_lengths(&mut self) {
+        self.channel1.clock_length();
+        self.channel2.clock_length();
+        self.channel3.clock_length();
+        self.channel4.clock_length();
+    }
+
+    fn mix(&self) -> StereoSample {
+        if !self.enabled {
+            return Default::default();
+        }
+
+        // A disabled DAC outputs silence, an enabled DAC with a disabled channel outputs the
+        // analog value for digital 0
+        let outputs = [
+            self.channel1
+                .dac_enabled()
+                .then(|| dac_output(self.channel1.output())),
+            self.channel2
+                .dac_enabled()
+                .then(|| dac_output(self.channel2.output())),
+            self.channel3
+                .dac_enabled
+                .then(|| dac_output(self.channel3.output())),
+            self.channel4
+                .dac_enabled()
+                .then(|| dac_output(self.channel4.output())),
+        ];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            let Some(output) = output else {
+                continue;
+            };
+
+            if self.panning & (1 << (i + 4)) != 0 {
+                left += output;
+            }
+            if self.panning & (1 << i) != 0 {
+                right += output;
+            }
+        }
+
+        let left_volume = (((self.master_volume & 0x70) >> 4) + 1) as f32 / 8.0;
+        let right_volume = ((self.master_volume & 0x07) + 1) as f32 / 8.0;
+
+        StereoSample {
+            left: left / 4.0 * left_volume,
+            right: right / 4.0 * right_volume,
+        }
+    }
+
+    fn nr52(&self) -> u8 {
+        (u8::from(self.enabled) << 7)
+            | (u8::from(self.channel4.enabled) << 3)
+            | (u8::from(self.channel3.enabled) << 2)
+            | (u8::from(self.channel2.enabled) << 1)
+            | u8::from(self.channel1.enabled)
+    }
+
+    fn power_off(&mut self) {
+        // Powering off the APU clears all the sound registers. Wave RAM is left intact
+        let wave_ram = self.channel3.wave_ram;
+
+        self.channel1 = SquareChannel::new(true);
+        self.channel2 = SquareChannel::new(false);
+        self.channel3 = WaveChannel::new();
+        self.channel3.wave_ram = wave_ram;
+        self.channel4 = NoiseChannel::new();
+
+        self.master_volume = 0x00;
+        self.panning = 0x00;
+        self.enabled = false;
+    }
+
+    fn power_on(&mut self) {
+        self.enabled = true;
+        self.frame_sequencer_step = 0;
+        self.frame_sequencer_cycles = 0;
+    }
+
+    fn read_register(&self, address: u16) -> u8 {
+        match address {
+            NR10..=NR14 => self.channel1.read(address - NR10),
+            NR21..=NR24 => self.channel2.read(address - NR21 + 1),
+            NR30..=NR34 => self.channel3.read(address - NR30),
+            NR41..=NR44 => self.channel4.read(address - NR41 + 1),
+            NR50 => self.master_volume,
+            NR51 => self.panning,
+            NR52 => self.nr52(),
+            _ => 0x00,
+        }
     }
 }
 
 impl Memory for Apu {
-    fn read(&mut self, _address: u16) -> u8 {
-        0xFF
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            SOUND_START..=SOUND_END => {
+                self.read_register(address) | READ_MASKS[(address - SOUND_START) as usize]
+            }
+            WAVE_START..=WAVE_END => self.channel3.read_wave_ram(address),
+            _ => panic!("Invalid address {:#06X} for Apu::read", address),
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        if (WAVE_START..=WAVE_END).contains(&address) {
+            self.channel3.write_wave_ram(address, data);
+            return;
+        }
+
+        if address == NR52 {
+            let enable = data & 0x80 != 0;
+            if self.enabled && !enable {
+                self.power_off();
+            } else if !self.enabled && enable {
+                self.power_on();
+            }
+            return;
+        }
+
+        // All registers other than NR52 are read-only while the APU is off
+        if !self.enabled {
+            return;
+        }
+
+        match address {
+            NR10..=NR14 => self.channel1.write(address - NR10, data),
+            NR21..=NR24 => self.channel2.write(address - NR21 + 1, data),
+            NR30..=NR34 => self.channel3.write(address - NR30, data),
+            NR41..=NR44 => self.channel4.write(address - NR41 + 1, data),
+            NR50 => self.master_volume = data,
+            NR51 => self.panning = data,
+            0xFF15 | 0xFF1F => {}
+            _ => panic!("Invalid address {:#06X} for Apu::write", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick_cycles(apu: &mut Apu, t_cycles: u32) {
+        for _ in 0..t_cycles {
+            apu.step();
+        }
+    }
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(NR52, 0x80);
+        apu.write(NR50, 0x77);
+        apu.write(NR51, 0xFF);
+        apu
+    }
+
+    #[test]
+    fn test_register_read_masks() {
+        let mut apu = powered_apu();
+
+        for address in SOUND_START..=SOUND_END {
+            if address == NR52 {
+                continue;
+            }
+            apu.write(address, 0x00);
+        }
+        // Writing 0 to NR12/NR22/NR42/NR30 disables the DACs so no channel is playing
+        for address in SOUND_START..NR52 {
+            let expected = READ_MASKS[(address - SOUND_START) as usize];
+            assert_eq!(apu.read(address), expected, "Register {:#06X}", address);
+        }
+
+        assert_eq!(apu.read(NR52), 0xF0);
+        assert_eq!(apu.read(0xFF15), 0xFF);
+        assert_eq!(apu.read(0xFF1F), 0xFF);
     }
 
-    fn write(&mut self, _address: u16, _data: u8) {}
+    #[test]
+    fn test_register_read_back() {
+        let mut apu = powered_apu();
+
+        apu.write(NR10, 0x7F);
+        apu.write(NR11, 0xBF);
+        apu.write(NR12, 0xF3);
+        apu.write(NR32, 0x60);
+        apu.write(NR43, 0x5A);
+        apu.write(NR50, 0x35);
+        apu.write(NR51, 0xA5);
+
+        assert_eq!(apu.read(NR10), 0xFF);
+        assert_eq!(apu.read(NR11), 0xBF);
+        assert_eq!(apu.read(NR12), 0xF3);
+        assert_eq!(apu.read(NR13), 0xFF);
+        assert_eq!(apu.read(NR32), 0xFF);
+        assert_eq!(apu.read(NR43), 0x5A);
+        assert_eq!(apu.read(NR50), 0x35);
+        assert_eq!(apu.read(NR51), 0xA5);
+    }
+
+    #[test]
+    fn test_power_off_clears_registers_but_not_wave_ram() {
+        let mut apu = powered_apu();
+        apu.write(NR12, 0xF0);
+        apu.write(WAVE_START, 0x12);
+        apu.write(NR52, 0x00);
+
+        assert_eq!(apu.read(NR12), 0x00);
+        assert_eq!(apu.read(NR50), 0x00);
+        assert_eq!(apu.read(NR52), 0x70);
+        assert_eq!(apu.read(WAVE_START), 0x12);
+
+        // Registers cannot be written while the APU is off
+        apu.write(NR12, 0xF0);
+        assert_eq!(apu.read(NR12), 0x00);
+    }
+
+    #[test]
+    fn test_trigger_sets_channel_status() {
+        let mut apu = powered_apu();
+        apu.write(NR12, 0xF0);
+        apu.write(NR14, 0x80);
+        assert_eq!(apu.read(NR52), 0xF1);
+
+        // Disabling the DAC disables the channel
+        apu.write(NR12, 0x00);
+        assert_eq!(apu.read(NR52), 0xF0);
+    }
+
+    #[test]
+    fn test_length_counter_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write(NR22, 0xF0);
+        apu.write(NR21, 0x3E); // Length of 2
+        apu.write(NR24, 0xC0); // Trigger with length enabled
+        assert_eq!(apu.read(NR52), 0xF2);
+
+        // Length is clocked on steps 0 and 2 of the frame sequencer
+        tick_cycles(&mut apu, FRAME_SEQUENCER_PERIOD);
+        assert_eq!(apu.read(NR52), 0xF2);
+        tick_cycles(&mut apu, FRAME_SEQUENCER_PERIOD * 2);
+        assert_eq!(apu.read(NR52), 0xF0);
+    }
+
+    #[test]
+    fn test_sweep_overflow_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write(NR10, 0x11); // Period 1, increasing, shift 1
+        apu.write(NR12, 0xF0);
+        apu.write(NR13, 0xFF);
+        apu.write(NR14, 0x85); // Frequency 0x5FF. Next frequency 0x8FE overflows
+        assert_eq!(apu.read(NR52), 0xF0);
+
+        apu.write(NR14, 0x83); // Frequency 0x3FF. Next frequency 0x5FE
+        assert_eq!(apu.read(NR52), 0xF1);
+    }
+
+    #[test]
+    fn test_square_wave_output() {
+        let mut apu = powered_apu();
+        apu.write(NR51, 0x11); // Channel 1 on both sides
+        apu.write(NR11, 0x80); // 50% duty
+        apu.write(NR12, 0xF0); // Max volume, no envelope
+
+        // Frequency of 0x700 gives a period of 1024 t-cycles for each duty step
+        apu.write(NR13, 0x00);
+        apu.write(NR14, 0x87);
+        apu.take_samples();
+
+        // One full duty cycle of 8 steps
+        tick_cycles(&mut apu, 1024 * 8);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), (1024 * 8 / CYCLES_PER_SAMPLE) as usize);
+
+        // Each duty step spans 16 samples. Only the first channel is mixed, so the
+        // amplitude is a quarter of the DAC output
+        let high = dac_output(15) / 4.0;
+        let low = dac_output(0) / 4.0;
+        let steps: Vec<f32> = samples.chunks(16).map(|chunk| chunk[8].left).collect();
+        let expected = [1, 0, 0, 0, 0, 1, 1, 1].map(|bit| if bit == 1 { high } else { low });
+        assert_eq!(steps, expected);
+
+        for sample in samples {
+            assert_eq!(sample.left, sample.right);
+        }
+    }
+
+    #[test]
+    fn test_wave_channel_output() {
+        let mut apu = powered_apu();
+        apu.write(NR51, 0x44); // Channel 3 on both sides
+        for (i, address) in (WAVE_START..=WAVE_END).enumerate() {
+            apu.write(address, if i % 2 == 0 { 0xF0 } else { 0x0F });
+        }
+        apu.write(NR30, 0x80);
+        apu.write(NR32, 0x20); // 100% volume
+        apu.write(NR33, 0x00);
+        apu.write(NR34, 0x87); // Period of 512 t-cycles per sample
+        apu.take_samples();
+
+        tick_cycles(&mut apu, 512 * 5);
+        let samples = apu.take_samples();
+        // The sample buffer is empty until the first sample (the lower nibble of the first byte)
+        // is read. Each wave sample spans 8 output samples
+        let steps: Vec<f32> = samples.chunks(8).map(|chunk| chunk[4].left).collect();
+        let expected = [0, 0, 0, 15, 15].map(|value| dac_output(value) / 4.0);
+        assert_eq!(steps, expected);
+    }
+
+    #[test]
+    fn test_silent_when_powered_off() {
+        let mut apu = Apu::new();
+        tick_cycles(&mut apu, CYCLES_PER_SAMPLE * 10);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 10);
+        assert!(samples
+            .iter()
+            .all(|sample| *sample == StereoSample::default()));
+    }
 }
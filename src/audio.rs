@@ -0,0 +1,490 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SampleFormat, SizedSample};
+use thiserror::Error;
+
+use crate::apu::{StereoSample, APU_SAMPLE_RATE};
+
+/// Maximum deviation from the nominal output rate used by dynamic rate control. 0.5% is small
+/// enough that the pitch change is inaudible
+const MAX_RATE_DELTA: f64 = 0.005;
+
+/// Amount of audio (in milliseconds) the ring buffer between the emulation thread and the audio
+/// device can hold. Rate control tries to keep it half full
+const RING_BUFFER_LATENCY_MS: u32 = 100;
+
+#[derive(Error, Debug)]
+pub enum AudioError {
+    #[error("No audio output device available")]
+    NoDevice,
+    #[error("Unsupported sample format '{0}'")]
+    UnsupportedFormat(SampleFormat),
+    #[error("Audio device error: '{0}'")]
+    Device(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A destination for the samples generated by the APU. Samples are queued at the rate returned
+/// by `AudioSink::sample_rate`. Sinks that are played back in real time report how full their
+/// buffer is so that the producer can adjust its rate to neither underrun nor drift
+pub trait AudioSink {
+    /// Rate (in Hz) at which the sink consumes samples
+    fn sample_rate(&self) -> u32;
+
+    /// Number of samples waiting to be played
+    fn queued_samples(&self) -> usize;
+
+    /// Number of samples the sink can hold
+    fn capacity(&self) -> usize;
+
+    fn queue(&mut self, samples: &[StereoSample]);
+}
+
+// Null Sink ---------------------------------------------------------------------------------------
+/// Sink that discards all samples. Used when no audio device is available and for headless runs
+pub struct NullSink {
+    sample_rate: u32,
+}
+
+impl NullSink {
+    pub fn new(sample_rate: u32) -> Self {
+        Self { sample_rate }
+    }
+}
+
+impl Default for NullSink {
+    fn default() -> Self {
+        Self::new(APU_SAMPLE_RATE)
+    }
+}
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queued_samples(&self) -> usize {
+        // Always report a half full buffer so that rate control is a no-op
+        self.capacity() / 2
+    }
+
+    fn capacity(&self) -> usize {
+        ring_buffer_capacity(self.sample_rate)
+    }
+
+    fn queue(&mut self, _samples: &[StereoSample]) {}
+}
+// END-Null Sink -----------------------------------------------------------------------------------
+
+// WAV File Sink -----------------------------------------------------------------------------------
+const WAV_HEADER_SIZE: u32 = 44;
+
+/// Sink that writes all samples to a 16-bit stereo PCM WAV file
+pub struct WavFileSink {
+    writer: BufWriter<File>,
+    sample_rate: u32,
+    samples_written: u32,
+}
+
+impl WavFileSink {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> Result<Self, AudioError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        // The sizes in the header are filled in when the sink is dropped
+        Self::write_header(&mut writer, sample_rate, 0)?;
+
+        Ok(Self {
+            writer,
+            sample_rate,
+            samples_written: 0,
+        })
+    }
+
+    fn write_header<W: Write>(writer: &mut W, sample_rate: u32, samples: u32) -> io::Result<()> {
+        let channels: u16 = 2;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+        let data_size = samples * block_align as u32;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&bits_per_sample.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&data_size.to_le_bytes())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        Self::write_header(&mut self.writer, self.sample_rate, self.samples_written)?;
+        self.writer.flush()
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queued_samples(&self) -> usize {
+        self.capacity() / 2
+    }
+
+    fn capacity(&self) -> usize {
+        ring_buffer_capacity(self.sample_rate)
+    }
+
+    fn queue(&mut self, samples: &[StereoSample]) {
+        for sample in samples {
+            let left = i16::from_sample(sample.left.clamp(-1.0, 1.0));
+            let right = i16::from_sample(sample.right.clamp(-1.0, 1.0));
+            let result = self
+                .writer
+                .write_all(&left.to_le_bytes())
+                .and_then(|_| self.writer.write_all(&right.to_le_bytes()));
+            if let Err(e) = result {
+                log::error!("Failed to write audio samples: {}", e);
+                return;
+            }
+            self.samples_written += 1;
+        }
+    }
+}
+
+impl Drop for WavFileSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            log::error!("Failed to finalize WAV file: {}", e);
+        }
+    }
+}
+// END-WAV File Sink -------------------------------------------------------------------------------
+
+// cpal Sink ---------------------------------------------------------------------------------------
+/// Sink that plays samples on the default output device. Samples are handed over to the audio
+/// callback through a ring buffer
+pub struct CpalSink {
+    // Dropping the stream stops playback
+    _stream: cpal::Stream,
+    ring_buffer: Arc<Mutex<VecDeque<StereoSample>>>,
+    sample_rate: u32,
+    capacity: usize,
+}
+
+impl CpalSink {
+    pub fn new() -> Result<Self, AudioError> {
+        let host = cpal::default_host();
+        let device = host.default_output_device().ok_or(AudioError::NoDevice)?;
+        let supported_config = device
+            .default_output_config()
+            .map_err(|e| AudioError::Device(e.to_string()))?;
+
+        let sample_format = supported_config.sample_format();
+        let config: cpal::StreamConfig = supported_config.into();
+        let sample_rate = config.sample_rate.0;
+        let capacity = ring_buffer_capacity(sample_rate);
+        let ring_buffer = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+
+        let stream = match sample_format {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, ring_buffer.clone()),
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, ring_buffer.clone()),
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, ring_buffer.clone()),
+            format => return Err(AudioError::UnsupportedFormat(format)),
+        }?;
+        stream
+            .play()
+            .map_err(|e| AudioError::Device(e.to_string()))?;
+
+        log::info!(
+            "Opened audio device with sample rate {} Hz and {} channels",
+            sample_rate,
+            config.channels
+        );
+
+        Ok(Self {
+            _stream: stream,
+            ring_buffer,
+            sample_rate,
+            capacity,
+        })
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    ring_buffer: Arc<Mutex<VecDeque<StereoSample>>>,
+) -> Result<cpal::Stream, AudioError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    device
+        .build_output_stream(
+            config,
+            move |data: &mut [T], _| {
+                let mut ring_buffer = ring_buffer.lock().unwrap();
+                for frame in data.chunks_mut(channels) {
+                    // Play silence on underrun
+                    let sample = ring_buffer.pop_front().unwrap_or_default();
+                    match frame {
+                        [mono] => *mono = T::from_sample((sample.left + sample.right) / 2.0),
+                        [left, right, rest @ ..] => {
+                            *left = T::from_sample(sample.left);
+                            *right = T::from_sample(sample.right);
+                            for channel in rest {
+                                *channel = T::from_sample(0.0);
+                            }
+                        }
+                        [] => {}
+                    }
+                }
+            },
+            |e| log::error!("Audio stream error: {}", e),
+            None,
+        )
+        .map_err(|e| AudioError::Device(e.to_string()))
+}
+
+impl AudioSink for CpalSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn queued_samples(&self) -> usize {
+        self.ring_buffer.lock().unwrap().len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn queue(&mut self, samples: &[StereoSample]) {
+        let mut ring_buffer = self.ring_buffer.lock().unwrap();
+        for sample in samples {
+            // Drop the oldest samples on overrun
+            if ring_buffer.len() == self.capacity {
+                ring_buffer.pop_front();
+            }
+            ring_buffer.push_back(*sample);
+        }
+    }
+}
+// END-cpal Sink -----------------------------------------------------------------------------------
+
+fn ring_buffer_capacity(sample_rate: u32) -> usize {
+    (sample_rate * RING_BUFFER_LATENCY_MS / 1000) as usize
+}
+
+/// Linear interpolating resampler from the APU sample rate to the rate of an `AudioSink`
+pub struct Resampler {
+    input_rate: u32,
+    output_rate: u32,
+    /// Fractional position in the input stream of the next output sample. Position 0 is the last
+    /// sample of the previous input chunk
+    position: f64,
+    last_sample: StereoSample,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            input_rate,
+            output_rate,
+            position: 1.0,
+            last_sample: Default::default(),
+        }
+    }
+
+    /// Resample `input` appending the result to `output`. `rate_adjust` scales the output rate and
+    /// is used for dynamic rate control
+    pub fn process(
+        &mut self,
+        input: &[StereoSample],
+        rate_adjust: f64,
+        output: &mut Vec<StereoSample>,
+    ) {
+        let Some(last_input) = input.last() else {
+            return;
+        };
+
+        let step = self.input_rate as f64 / (self.output_rate as f64 * rate_adjust);
+        while self.position < input.len() as f64 {
+            let index = self.position as usize;
+            let fraction = (self.position - index as f64) as f32;
+
+            let current = if index == 0 {
+                self.last_sample
+            } else {
+                input[index - 1]
+            };
+            let next = input[index];
+
+            output.push(StereoSample {
+                left: current.left + (next.left - current.left) * fraction,
+                right: current.right + (next.right - current.right) * fraction,
+            });
+            self.position += step;
+        }
+
+        self.position -= input.len() as f64;
+        self.last_sample = *last_input;
+    }
+}
+
+/// Connects the APU output to an `AudioSink`, resampling to the rate of the sink. The output rate
+/// is nudged by up to `MAX_RATE_DELTA` based on how full the sink is, so that small differences
+/// between the emulation speed and the audio clock neither underrun nor overflow the sink
+pub struct AudioOutput {
+    sink: Box<dyn AudioSink>,
+    resampler: Resampler,
+    buffer: Vec<StereoSample>,
+}
+
+impl AudioOutput {
+    pub fn new(sink: Box<dyn AudioSink>) -> Self {
+        let resampler = Resampler::new(APU_SAMPLE_RATE, sink.sample_rate());
+        Self {
+            sink,
+            resampler,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Fill level of the sink between `0.0` (empty) and `1.0` (full)
+    pub fn fill_level(&self) -> f64 {
+        self.sink.queued_samples() as f64 / self.sink.capacity().max(1) as f64
+    }
+
+    pub fn queue(&mut self, samples: &[StereoSample]) {
+        // Produce fewer samples when the sink is more than half full and more when it is less
+        let rate_adjust = 1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * self.fill_level().min(1.0));
+
+        self.buffer.clear();
+        self.resampler
+            .process(samples, rate_adjust, &mut self.buffer);
+        self.sink.queue(&self.buffer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sink which records everything queued to it and reports a fixed fill level
+    struct RecordingSink {
+        samples: Arc<Mutex<Vec<StereoSample>>>,
+        queued: usize,
+    }
+
+    impl AudioSink for RecordingSink {
+        fn sample_rate(&self) -> u32 {
+            48000
+        }
+
+        fn queued_samples(&self) -> usize {
+            self.queued
+        }
+
+        fn capacity(&self) -> usize {
+            1000
+        }
+
+        fn queue(&mut self, samples: &[StereoSample]) {
+            self.samples.lock().unwrap().extend_from_slice(samples);
+        }
+    }
+
+    fn constant(value: f32, len: usize) -> Vec<StereoSample> {
+        vec![
+            StereoSample {
+                left: value,
+                right: -value,
+            };
+            len
+        ]
+    }
+
+    #[test]
+    fn test_resampler_output_length() {
+        let mut resampler = Resampler::new(APU_SAMPLE_RATE, 48000);
+        let mut output = Vec::new();
+        for _ in 0..16 {
+            resampler.process(
+                &constant(0.5, APU_SAMPLE_RATE as usize / 16),
+                1.0,
+                &mut output,
+            );
+        }
+
+        // One second of input produces one second of output
+        assert!((output.len() as i64 - 48000).abs() <= 1);
+    }
+
+    #[test]
+    fn test_resampler_interpolates() {
+        let mut resampler = Resampler::new(2, 4);
+        let input = [0.0, 1.0, 2.0].map(|value| StereoSample {
+            left: value,
+            right: value,
+        });
+        let mut output = Vec::new();
+        resampler.process(&input, 1.0, &mut output);
+
+        let left: Vec<f32> = output.iter().map(|sample| sample.left).collect();
+        assert_eq!(left, [0.0, 0.5, 1.0, 1.5]);
+    }
+
+    #[test]
+    fn test_rate_control() {
+        let produced = |queued| {
+            let samples = Arc::new(Mutex::new(Vec::new()));
+            let sink = RecordingSink {
+                samples: samples.clone(),
+                queued,
+            };
+            let mut output = AudioOutput::new(Box::new(sink));
+            output.queue(&constant(0.25, APU_SAMPLE_RATE as usize));
+            let len = samples.lock().unwrap().len();
+            len
+        };
+
+        let nominal = produced(500);
+        assert!((nominal as i64 - 48000).abs() <= 1);
+        // An empty sink receives more samples, a full sink receives fewer
+        assert!(produced(0) > nominal);
+        assert!(produced(1000) < nominal);
+        assert!(produced(1000) as f64 >= 48000.0 * (1.0 - MAX_RATE_DELTA) - 1.0);
+    }
+
+    #[test]
+    fn test_wav_file_sink() -> Result<(), AudioError> {
+        let path = std::env::temp_dir().join("gibi_test_wav_file_sink.wav");
+        {
+            let mut sink = WavFileSink::create(&path, 48000)?;
+            sink.queue(&constant(1.0, 100));
+        }
+
+        let data = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(data.len(), WAV_HEADER_SIZE as usize + 100 * 4);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 400);
+        assert_eq!(i16::from_le_bytes([data[44], data[45]]), i16::MAX);
+        assert_eq!(i16::from_le_bytes([data[46], data[47]]), i16::MIN);
+        Ok(())
+    }
+}
@@ -1,19 +1,40 @@
 use std::ops::Deref;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::savestate::{self, SaveStateError};
 use crate::{memory::Memory, min_number_of_bits, HardwareSupport};
 
+mod camera;
+mod huc;
+mod mbc7;
+
+pub use camera::{CameraError, SensorImage, CAMERA_HEIGHT, CAMERA_WIDTH};
+
 const CGB_FLAG_ADDRESS: u16 = 0x143;
+const SGB_FLAG_ADDRESS: u16 = 0x146;
 const CARTRIDGE_TYPE_ADDRESS: u16 = 0x147;
 const ROM_SIZE_ADDRESS: u16 = 0x148;
 const ROM_BANK_SIZE: usize = 1024 * 16;
 const RAM_SIZE_ADDRESS: u16 = 0x149;
 const RAM_BANK_SIZE: usize = 1024 * 8;
+const HEADER_CHECKSUM_ADDRESS: u16 = 0x14D;
+const GLOBAL_CHECKSUM_ADDRESS: u16 = 0x14E;
+
+const TITLE_START: u16 = 0x134;
+const TITLE_END: u16 = 0x143;
+const NEW_LICENSEE_CODE_ADDRESS: u16 = 0x144;
+const OLD_LICENSEE_CODE_ADDRESS: u16 = 0x14B;
 
 pub const BOOT_ROM_START: u16 = 0x0000;
 pub const BOOT_ROM_END: u16 = 0x08FF;
-pub const CGB_BOOT_ROM: &[u8; 0x900] = include_bytes!("../roms/cgb_boot.bin");
+/// Size of the CGB boot ROM, including the hole at 0x0100..=0x01FF where the cartridge header is
+/// visible
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+/// Size of the DMG and MGB boot ROMs
+pub const DMG_BOOT_ROM_SIZE: usize = 0x100;
 
 pub const CART_ROM_START: u16 = 0x0000;
 pub const CART_ROM_END: u16 = 0x7FFF;
@@ -32,15 +53,50 @@ pub trait Mbc: Memory {
     }
 
     fn savable(&self) -> bool;
-    fn save_ram(&self) -> Option<&Vec<u8>>;
+    /// Contents of the save file. Usually the RAM, followed by any extra battery-backed state
+    fn save_ram(&self) -> Option<Vec<u8>>;
+
+    /// Called every machine cycle for MBCs that keep time
+    fn tick(&mut self, _double_speed: bool) {}
+
+    fn set_rtc_mode(&mut self, _mode: RtcMode) {}
+
+    /// Image seen by the sensor of camera cartridges
+    fn set_camera_image(&mut self, _image: SensorImage) {}
+
+    /// Tilt seen by the accelerometer of MBC7 cartridges, in g
+    fn set_accelerometer(&mut self, _x: f32, _y: f32) {}
+
+    /// Serialize the banking state and RAM of the MBC for a save state. The ROM is not included
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError>;
+    /// Restore the state created by `save_state`. The MBC is left untouched if this fails
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError>;
 }
 
-#[derive(Clone, Debug)]
+/// Source of time for cartridges with a real-time clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum RtcMode {
+    /// Follow the time of the host, including the time passed while the emulator was closed
+    #[default]
+    WallClock,
+    /// Advance with emulated cycles. The clock stops while the emulator is paused or closed and
+    /// runs faster when emulation does
+    Emulated,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct CartridgeHeader {
     pub title: String,
     pub manufacturer_code: String,
     pub hardware_supported: HardwareSupport,
+    /// Whether the game uses the Super Game Boy functions. The SGB ignores the command packets of
+    /// other games
+    pub sgb_supported: bool,
     pub cart_type: String,
+    /// Byte at 0x147 the MBC is chosen by
+    cart_type_code: u8,
+    /// Checksum of the entire ROM. Only used to tell cartridges apart
+    pub global_checksum: u16,
     rom_size_and_banks: (usize, usize),
     ram_size_and_banks: (usize, usize),
 }
@@ -65,6 +121,11 @@ impl CartridgeHeader {
     pub fn ram_banks(&self) -> usize {
         self.ram_size_and_banks.1
     }
+
+    /// Whether the cartridge has the accelerometer of the MBC7
+    pub fn has_accelerometer(&self) -> bool {
+        self.cart_type_code == 0x22
+    }
 }
 
 #[derive(Error, Clone, Debug)]
@@ -82,10 +143,18 @@ pub struct Cartridge {
 
 impl Cartridge {
     pub fn new(rom: Vec<u8>, ram: Option<Vec<u8>>) -> Result<Self, String> {
-        let header = Cartridge::parse_header(&rom[0x100..0x150]).map_err(|e| e.to_string())?;
-        let mbc: Box<dyn Mbc> = match rom[CARTRIDGE_TYPE_ADDRESS as usize] {
+        let header = Cartridge::parse_header(&rom).map_err(|e| e.to_string())?;
+        let mbc: Box<dyn Mbc> = match header.cart_type_code {
             0x00 => Box::new(NoMbc::new(rom)),
             code @ (0x01..=0x03) => Box::new(Mbc1::new(rom, ram, code == 0x03, &header)),
+            code @ (0x05..=0x06) => Box::new(Mbc2::new(rom, ram, code == 0x06, &header)),
+            code @ (0x0F..=0x13) => Box::new(Mbc3::new(
+                rom,
+                ram,
+                code != 0x11 && code != 0x12,
+                code == 0x0F || code == 0x10,
+                &header,
+            )),
             code @ (0x19..=0x1E) => Box::new(Mbc5::new(
                 rom,
                 ram,
@@ -93,20 +162,85 @@ impl Cartridge {
                 code == 0x1C || code == 0x1D || code == 0x1E,
                 &header,
             )),
+            code @ (0x0B..=0x0D) => Box::new(Mmm01::new(rom, ram, code == 0x0D, &header)),
+            0x22 => Box::new(mbc7::Mbc7::new(rom, ram, &header)),
+            0xFC => Box::new(camera::PocketCamera::new(rom, ram, &header)),
+            0xFE => Box::new(huc::Huc3::new(rom, ram, &header)),
+            0xFF => Box::new(huc::Huc1::new(rom, ram, &header)),
             code => return Err(format!("Unsupported MBC with code: '{code}'")),
         };
 
         Ok(Self { mbc, header })
     }
 
-    fn parse_header(header: &[u8]) -> Result<CartridgeHeader, CartridgeError> {
-        if header.len() != 0x50 {
+    pub(crate) fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        self.mbc.save_state()
+    }
+
+    pub(crate) fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        self.mbc.load_state(state)
+    }
+
+    pub(crate) fn tick(&mut self, double_speed: bool) {
+        self.mbc.tick(double_speed)
+    }
+
+    pub(crate) fn set_rtc_mode(&mut self, mode: RtcMode) {
+        self.mbc.set_rtc_mode(mode)
+    }
+
+    pub(crate) fn set_camera_image(&mut self, image: SensorImage) {
+        self.mbc.set_camera_image(image)
+    }
+
+    pub(crate) fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.mbc.set_accelerometer(x, y)
+    }
+
+    /// Sum of the title bytes for games published by Nintendo. The CGB boot ROM uses it to pick
+    /// the colors of DMG games
+    pub(crate) fn nintendo_title_checksum(&mut self) -> Option<u8> {
+        let nintendo = match self.read(OLD_LICENSEE_CODE_ADDRESS) {
+            0x01 => true,
+            0x33 => {
+                self.read(NEW_LICENSEE_CODE_ADDRESS) == b'0'
+                    && self.read(NEW_LICENSEE_CODE_ADDRESS + 1) == b'1'
+            }
+            _ => false,
+        };
+
+        nintendo.then(|| {
+            (TITLE_START..=TITLE_END).fold(0u8, |sum, address| sum.wrapping_add(self.read(address)))
+        })
+    }
+
+    /// Fourth letter of the title, which the CGB boot ROM uses to tell apart games with the same
+    /// title checksum
+    pub(crate) fn title_fourth_letter(&mut self) -> u8 {
+        self.read(TITLE_START + 3)
+    }
+
+    /// Checksum of the header bytes, which sets the flags the DMG boot ROM leaves behind
+    pub(crate) fn header_checksum(&mut self) -> u8 {
+        self.read(HEADER_CHECKSUM_ADDRESS)
+    }
+
+    /// Parse the header of the ROM. This is the header of the first bank, except for MMM01
+    /// multicarts which boot into a menu stored in the last 32KB of the ROM
+    fn parse_header(rom: &[u8]) -> Result<CartridgeHeader, CartridgeError> {
+        if rom.len() < 0x150 {
             return Err(CartridgeError::Size {
-                expected: 0x50,
-                got: header.len(),
+                expected: 0x150,
+                got: rom.len(),
             });
         }
 
+        let menu_offset = rom.len().saturating_sub(ROM_BANK_SIZE * 2);
+        let header = match &rom[menu_offset + 0x100..menu_offset + 0x150] {
+            menu if menu_offset > 0 && Cartridge::is_mmm01_menu_header(menu) => menu,
+            _ => &rom[0x100..0x150],
+        };
+
         let title = match std::str::from_utf8(&header[0x034..0x03F]) {
             Ok(value) => value.to_string(),
             Err(e) => {
@@ -124,7 +258,10 @@ impl Cartridge {
 
         let hardware_supported =
             Cartridge::hardware_supported(header[CGB_FLAG_ADDRESS as usize - 0x100]);
-        let cart_type = match header[CARTRIDGE_TYPE_ADDRESS as usize - 0x100] {
+        let sgb_supported = header[SGB_FLAG_ADDRESS as usize - 0x100] == 0x03
+            && header[OLD_LICENSEE_CODE_ADDRESS as usize - 0x100] == 0x33;
+        let cart_type_code = header[CARTRIDGE_TYPE_ADDRESS as usize - 0x100];
+        let cart_type = match cart_type_code {
             0x00 => "no_mbc".to_string(),
             0x01 => "mbc1".to_string(),
             0x02 => "mbc1 | ram".to_string(),
@@ -164,18 +301,37 @@ impl Cartridge {
             Cartridge::rom_size_from_header(header[ROM_SIZE_ADDRESS as usize - 0x100]);
         let ram_size_and_banks =
             Cartridge::ram_size_from_header(header[RAM_SIZE_ADDRESS as usize - 0x100]);
+        let global_checksum = u16::from_be_bytes([
+            header[GLOBAL_CHECKSUM_ADDRESS as usize - 0x100],
+            header[GLOBAL_CHECKSUM_ADDRESS as usize + 1 - 0x100],
+        ]);
 
         Ok(CartridgeHeader {
             title,
             manufacturer_code,
             hardware_supported,
+            sgb_supported,
             cart_type,
+            cart_type_code,
+            global_checksum,
             rom_size_and_banks,
             ram_size_and_banks,
         })
     }
 
     // Helper methods
+    /// Whether the header belongs to the menu of an MMM01 multicart. The header checksum has to
+    /// match so that arbitrary data at the end of other ROMs is not taken for a header
+    fn is_mmm01_menu_header(header: &[u8]) -> bool {
+        let mut checksum: u8 = 0;
+        for byte in &header[0x34..=0x4C] {
+            checksum = checksum.wrapping_sub(*byte).wrapping_sub(1);
+        }
+
+        matches!(header[CARTRIDGE_TYPE_ADDRESS as usize - 0x100], 0x0B..=0x0D)
+            && checksum == header[HEADER_CHECKSUM_ADDRESS as usize - 0x100]
+    }
+
     /// Calculate the ROM size and number of ROM banks of the cartridge from the
     /// byte at 0x148. Return this information as a (size, banks) tuple
     fn rom_size_from_header(value: u8) -> (usize, usize) {
@@ -225,6 +381,17 @@ impl Memory for Cartridge {
     }
 }
 
+/// An empty cartridge without a ROM. Only used as a placeholder while the rest of the machine is
+/// restored from a save state
+impl Default for Cartridge {
+    fn default() -> Self {
+        Self {
+            mbc: Box::new(NoMbc::new(Vec::new())),
+            header: Default::default(),
+        }
+    }
+}
+
 impl Deref for Cartridge {
     type Target = Box<dyn Mbc>;
 
@@ -258,9 +425,18 @@ impl Mbc for NoMbc {
         false
     }
 
-    fn save_ram(&self) -> Option<&Vec<u8>> {
+    fn save_ram(&self) -> Option<Vec<u8>> {
         None
     }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        // Nothing to save without banking or RAM
+        Ok(Vec::new())
+    }
+
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), SaveStateError> {
+        Ok(())
+    }
 }
 
 impl Memory for NoMbc {
@@ -287,7 +463,9 @@ impl Memory for NoMbc {
 
 // MBC1 --------------------------------------------------------------------------------------------
 
+#[derive(Serialize, Deserialize)]
 struct Mbc1 {
+    #[serde(skip)]
     rom: Vec<u8>,
     ram: Option<Vec<u8>>,
 
@@ -386,8 +564,19 @@ impl Mbc for Mbc1 {
         self.savable
     }
 
-    fn save_ram(&self) -> Option<&Vec<u8>> {
-        self.ram.as_ref()
+    fn save_ram(&self) -> Option<Vec<u8>> {
+        self.ram.clone()
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        savestate::encode(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut restored: Self = savestate::decode(state)?;
+        restored.rom = std::mem::take(&mut self.rom);
+        *self = restored;
+        Ok(())
     }
 }
 
@@ -475,72 +664,68 @@ impl Memory for Mbc1 {
 }
 // END-MBC1 ----------------------------------------------------------------------------------------
 
-// MBC5 --------------------------------------------------------------------------------------------
+// MBC2 --------------------------------------------------------------------------------------------
 
-struct Mbc5 {
+/// MBC2 has 512 half-bytes of RAM built in. Each half-byte is stored (and saved) as a full byte
+const MBC2_RAM_SIZE: usize = 512;
+
+#[derive(Serialize, Deserialize)]
+struct Mbc2 {
+    #[serde(skip)]
     rom: Vec<u8>,
-    ram: Option<Vec<u8>>,
+    ram: Vec<u8>,
 
-    rom_bank: u16,
+    rom_bank: u8,
     total_rom_banks: usize,
 
-    ram_bank: u8,
     ram_enabled: bool,
 
     savable: bool,
-
-    has_rumble: bool,
-    rumble_active: bool,
 }
 
-impl Mbc5 {
+impl Mbc2 {
     pub fn new(
         rom: Vec<u8>,
-        mut ram: Option<Vec<u8>>,
+        ram: Option<Vec<u8>>,
         savable: bool,
-        has_rumble: bool,
         header: &CartridgeHeader,
     ) -> Self {
         let rom_bank = 0x01;
-        let ram_bank = 0x00;
         let ram_enabled = false;
-        let rumble_active = false;
 
-        let ram_size = header.ram_size();
-        if ram.is_none() && ram_size > 0 {
-            log::info!(
-                "No RAM provided. Initializing RAM of size {} bytes",
-                ram_size
-            );
-            ram = Some(vec![0xFF; ram_size as usize]);
-        } else if let Some(r) = ram.as_ref() {
-            if r.len() != ram_size as usize {
+        let ram = match ram {
+            Some(ram) if ram.len() == MBC2_RAM_SIZE => ram,
+            Some(ram) => {
                 log::error!(
                     "Provided RAM size {} does not match what was expected {}",
-                    r.len(),
-                    ram_size
+                    ram.len(),
+                    MBC2_RAM_SIZE
                 );
-                ram = Some(vec![0xFF; ram_size as usize]);
+                vec![0x0F; MBC2_RAM_SIZE]
             }
-        }
+            None => {
+                log::info!(
+                    "No RAM provided. Initializing RAM of size {} bytes",
+                    MBC2_RAM_SIZE
+                );
+                vec![0x0F; MBC2_RAM_SIZE]
+            }
+        };
 
-        Mbc5 {
+        Mbc2 {
             rom,
             ram,
             rom_bank,
-            ram_bank,
+            total_rom_banks: header.rom_banks(),
             ram_enabled,
             savable,
-            has_rumble,
-            rumble_active,
-            total_rom_banks: header.rom_banks(),
         }
     }
 }
 
-impl Mbc for Mbc5 {
+impl Mbc for Mbc2 {
     fn name(&self) -> String {
-        "MBC5".into()
+        "MBC2".into()
     }
 
     fn rom(&self) -> &Vec<u8> {
@@ -548,19 +733,30 @@ impl Mbc for Mbc5 {
     }
 
     fn ram(&self) -> Option<&Vec<u8>> {
-        self.ram.as_ref()
+        Some(&self.ram)
     }
 
     fn savable(&self) -> bool {
         self.savable
     }
 
-    fn save_ram(&self) -> Option<&Vec<u8>> {
-        self.ram.as_ref()
+    fn save_ram(&self) -> Option<Vec<u8>> {
+        Some(self.ram.clone())
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        savestate::encode(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut restored: Self = savestate::decode(state)?;
+        restored.rom = std::mem::take(&mut self.rom);
+        *self = restored;
+        Ok(())
     }
 }
 
-impl Memory for Mbc5 {
+impl Memory for Mbc2 {
     fn read(&mut self, address: u16) -> u8 {
         match address {
             0x0000..=0x3FFF => self.rom[address as usize],
@@ -569,20 +765,9 @@ impl Memory for Mbc5 {
                     + (address as usize - 0x4000)]
             }
             0xA000..=0xBFFF if !self.ram_enabled => 0xFF,
-            0xA000..=0xBFFF => {
-                if let Some(ram) = self.ram.as_ref() {
-                    let effective_address =
-                        0x2000 * self.ram_bank as usize + (address as usize - 0xA000);
-                    ram[effective_address]
-                } else {
-                    log::error!(
-                        "Read from RAM address {:#6X} for {} MBC with no RAM",
-                        address,
-                        self.name()
-                    );
-                    0xFF
-                }
-            }
+            // Only the lower 9 bits of the address are used, so the RAM is mirrored 16 times.
+            // The upper half of each byte is not connected and reads back as 1s
+            0xA000..=0xBFFF => self.ram[address as usize & 0x1FF] | 0xF0,
             _ => {
                 log::error!("Read from {:#6X} for {} MBC", address, self.name());
                 0xFF
@@ -592,24 +777,11 @@ impl Memory for Mbc5 {
 
     fn write(&mut self, address: u16, data: u8) {
         match address {
-            // Unlike MBC1 all bits of the written value matter for MBC5
-            0x0000..=0x1FFF => self.ram_enabled = data == 0x0A,
-            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
-            0x3000..=0x3FFF => self.rom_bank = ((data as u16 & 0b1) << 8) | (self.rom_bank & 0xFF),
-            0x4000..=0x5FFF => {
-                // The lower 4 bits of the written value are the RAM bank number
-                self.ram_bank = data & 0b1111;
-                // The bit 3 enables and disables rumble
-                // TODO: Take in a RUMBLE callback from the UI code
-                self.rumble_active = data & 0b1000 != 0;
-            }
-            0xA000..=0xBFFF if self.ram_enabled => {
-                let effective_address =
-                    0x2000 * self.ram_bank as usize + (address as usize - 0xA000);
-                if let Some(ram) = self.ram.as_mut() {
-                    ram[effective_address] = data;
-                }
-            }
+            // Bit 8 of the address selects between the RAM enable and the ROM bank registers
+            0x0000..=0x3FFF if address & 0x100 == 0 => self.ram_enabled = data & 0x0F == 0x0A,
+            0x0000..=0x3FFF => self.rom_bank = (data & 0x0F).max(0x01),
+            0x4000..=0x7FFF => {}
+            0xA000..=0xBFFF if self.ram_enabled => self.ram[address as usize & 0x1FF] = data & 0x0F,
             0xA000..=0xBFFF => {}
             _ => log::error!(
                 "Write to {:#6X} with {:#4X} for {} MBC",
@@ -620,4 +792,1137 @@ impl Memory for Mbc5 {
         }
     }
 }
-// END-MBC5 ----------------------------------------------------------------------------------------
+// END-MBC2 ----------------------------------------------------------------------------------------
+
+// MBC3 --------------------------------------------------------------------------------------------
+
+/// Size of the RTC trailer appended to the save file. The format is shared with BGB and VBA-M:
+/// the live and latched registers as 32-bit values, followed by a 64-bit UNIX timestamp
+const RTC_TRAILER_SIZE: usize = 48;
+/// Older emulators write the timestamp as a 32-bit value
+const RTC_TRAILER_SIZE_32BIT_TIMESTAMP: usize = 44;
+
+/// The RTC runs off a 32.768KHz crystal, independent of the CPU clock. Counted in single speed
+/// t-cycles
+const RTC_CYCLES_PER_SECOND: u32 = 4_194_304;
+
+const RTC_DAY_HIGH_HALT: u8 = 1 << 6;
+const RTC_DAY_HIGH_CARRY: u8 = 1 << 7;
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// The RTC registers as mapped to 0xA000..=0xBFFF by writing 0x08..=0x0C to 0x4000..=0x5FFF
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct RtcRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day_low: u8,
+    /// Bit 0: Bit 8 of the day counter, Bit 6: Halt, Bit 7: Day counter carry
+    day_high: u8,
+}
+
+impl RtcRegisters {
+    fn days(&self) -> u16 {
+        ((self.day_high as u16 & 0b1) << 8) | self.day_low as u16
+    }
+
+    fn set_days(&mut self, days: u16) {
+        self.day_low = days as u8;
+        self.day_high = (self.day_high & !0b1) | ((days >> 8) as u8 & 0b1);
+    }
+
+    fn halted(&self) -> bool {
+        self.day_high & RTC_DAY_HIGH_HALT != 0
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.day_low,
+            0x0C => self.day_high,
+            _ => 0xFF,
+        }
+    }
+
+    /// Registers can be written with out of range values. The counters then keep counting up
+    /// until they overflow their bits, without carrying into the next register
+    fn write(&mut self, register: u8, data: u8) {
+        match register {
+            0x08 => self.seconds = data & 0x3F,
+            0x09 => self.minutes = data & 0x3F,
+            0x0A => self.hours = data & 0x1F,
+            0x0B => self.day_low = data,
+            0x0C => self.day_high = data & 0xC1,
+            _ => {}
+        }
+    }
+
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    fn tick_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+
+        self.hours = 0;
+        self.advance_days(1);
+    }
+
+    fn advance_days(&mut self, days: u64) {
+        let days = self.days() as u64 + days;
+        if days > 0x1FF {
+            self.day_high |= RTC_DAY_HIGH_CARRY;
+        }
+        self.set_days((days % 0x200) as u16);
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        if self.halted() {
+            return;
+        }
+
+        // Count one second at a time until the registers hold a valid time again
+        while seconds > 0 && !self.in_range() {
+            self.tick_second();
+            seconds -= 1;
+        }
+
+        let time_of_day = self.seconds as u64 + self.minutes as u64 * 60 + self.hours as u64 * 3600;
+        let total = time_of_day + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        self.advance_days(total / 86400);
+    }
+
+    fn write_trailer(&self, trailer: &mut Vec<u8>) {
+        for value in [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.day_low,
+            self.day_high,
+        ] {
+            trailer.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+    }
+
+    fn from_trailer(trailer: &[u8]) -> Self {
+        let value = |index: usize| trailer[index * 4];
+        Self {
+            seconds: value(0) & 0x3F,
+            minutes: value(1) & 0x3F,
+            hours: value(2) & 0x1F,
+            day_low: value(3),
+            day_high: value(4) & 0xC1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Rtc {
+    mode: RtcMode,
+    registers: RtcRegisters,
+    latched: RtcRegisters,
+    /// Last value written to 0x6000..=0x7FFF. The registers are latched on a 0x00 -> 0x01 write
+    latch_value: u8,
+
+    /// Cycles into the current second when running in `RtcMode::Emulated`
+    cycles: u32,
+    /// UNIX time the registers were last brought up to date with when running in
+    /// `RtcMode::WallClock`
+    last_update: u64,
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Self {
+            mode: RtcMode::default(),
+            registers: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            latch_value: 0xFF,
+            cycles: 0,
+            last_update: unix_time(),
+        }
+    }
+
+    /// Restore the clock from the trailer of a save file. Time passed since the save was written
+    /// is caught up on when running in `RtcMode::WallClock`
+    fn from_trailer(trailer: &[u8]) -> Self {
+        let last_update = if trailer.len() == RTC_TRAILER_SIZE {
+            u64::from_le_bytes(trailer[40..48].try_into().unwrap())
+        } else {
+            u32::from_le_bytes(trailer[40..44].try_into().unwrap()) as u64
+        };
+
+        Self {
+            registers: RtcRegisters::from_trailer(&trailer[0..20]),
+            latched: RtcRegisters::from_trailer(&trailer[20..40]),
+            last_update,
+            ..Self::new()
+        }
+    }
+
+    fn trailer(&self) -> Vec<u8> {
+        let mut rtc = self.registers;
+        if self.mode == RtcMode::WallClock {
+            rtc.advance(unix_time().saturating_sub(self.last_update));
+        }
+
+        let mut trailer = Vec::with_capacity(RTC_TRAILER_SIZE);
+        rtc.write_trailer(&mut trailer);
+        self.latched.write_trailer(&mut trailer);
+        trailer.extend_from_slice(&unix_time().to_le_bytes());
+        trailer
+    }
+
+    fn set_mode(&mut self, mode: RtcMode) {
+        if mode != self.mode {
+            self.sync();
+            self.mode = mode;
+            self.last_update = unix_time();
+        }
+    }
+
+    fn tick(&mut self, double_speed: bool) {
+        if self.mode != RtcMode::Emulated {
+            return;
+        }
+
+        self.cycles += if double_speed { 2 } else { 4 };
+        if self.cycles >= RTC_CYCLES_PER_SECOND {
+            self.cycles -= RTC_CYCLES_PER_SECOND;
+            self.registers.advance(1);
+        }
+    }
+
+    /// Catch up with the wall clock
+    fn sync(&mut self) {
+        if self.mode != RtcMode::WallClock {
+            return;
+        }
+
+        let now = unix_time();
+        self.registers.advance(now.saturating_sub(self.last_update));
+        self.last_update = now;
+    }
+
+    fn latch(&mut self, data: u8) {
+        if self.latch_value == 0x00 && data == 0x01 {
+            self.sync();
+            self.latched = self.registers;
+        }
+        self.latch_value = data;
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        self.latched.read(register)
+    }
+
+    fn write(&mut self, register: u8, data: u8) {
+        self.sync();
+        if register == 0x08 {
+            // Writing the seconds resets the divider of the crystal
+            self.cycles = 0;
+        }
+        self.registers.write(register, data);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Mbc3 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Option<Vec<u8>>,
+    rtc: Option<Rtc>,
+
+    rom_bank: u8,
+    total_rom_banks: usize,
+
+    /// 0x00..=0x07 selects a RAM bank, 0x08..=0x0C an RTC register
+    ram_bank: u8,
+    total_ram_banks: usize,
+    ram_and_rtc_enabled: bool,
+
+    savable: bool,
+}
+
+impl Mbc3 {
+    pub fn new(
+        rom: Vec<u8>,
+        mut ram: Option<Vec<u8>>,
+        savable: bool,
+        has_rtc: bool,
+        header: &CartridgeHeader,
+    ) -> Self {
+        let rom_bank = 0x01;
+        let ram_bank = 0x00;
+        let ram_and_rtc_enabled = false;
+
+        let ram_size = header.ram_size();
+        let mut rtc = has_rtc.then(Rtc::new);
+
+        // The RTC state is appended to the save RAM
+        if let (Some(r), Some(rtc)) = (ram.as_mut(), rtc.as_mut()) {
+            let trailer_size = r.len().saturating_sub(ram_size);
+            if trailer_size == RTC_TRAILER_SIZE || trailer_size == RTC_TRAILER_SIZE_32BIT_TIMESTAMP
+            {
+                *rtc = Rtc::from_trailer(&r[ram_size..]);
+                r.truncate(ram_size);
+            }
+        }
+
+        if ram.is_none() && ram_size > 0 {
+            log::info!(
+                "No RAM provided. Initializing RAM of size {} bytes",
+                ram_size
+            );
+            ram = Some(vec![0xFF; ram_size]);
+        } else if let Some(r) = ram.as_ref() {
+            if r.len() != ram_size {
+                log::error!(
+                    "Provided RAM size {} does not match what was expected {}",
+                    r.len(),
+                    ram_size
+                );
+                ram = Some(vec![0xFF; ram_size]);
+            }
+        }
+
+        Mbc3 {
+            rom,
+            ram,
+            rtc,
+            rom_bank,
+            total_rom_banks: header.rom_banks(),
+            ram_bank,
+            total_ram_banks: header.ram_banks(),
+            ram_and_rtc_enabled,
+            savable,
+        }
+    }
+
+    fn effective_ram_address(&self, address: u16) -> usize {
+        0x2000 * (self.ram_bank as usize % self.total_ram_banks) + (address as usize - 0xA000)
+    }
+}
+
+impl Mbc for Mbc3 {
+    fn name(&self) -> String {
+        "MBC3".into()
+    }
+
+    fn rom(&self) -> &Vec<u8> {
+        &self.rom
+    }
+
+    fn ram(&self) -> Option<&Vec<u8>> {
+        self.ram.as_ref()
+    }
+
+    fn savable(&self) -> bool {
+        self.savable
+    }
+
+    fn save_ram(&self) -> Option<Vec<u8>> {
+        let mut save = self.ram.clone().unwrap_or_default();
+        if let Some(rtc) = self.rtc.as_ref() {
+            save.extend_from_slice(&rtc.trailer());
+        }
+
+        (!save.is_empty()).then_some(save)
+    }
+
+    fn tick(&mut self, double_speed: bool) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.tick(double_speed);
+        }
+    }
+
+    fn set_rtc_mode(&mut self, mode: RtcMode) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.set_mode(mode);
+        }
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        savestate::encode(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut restored: Self = savestate::decode(state)?;
+        restored.rom = std::mem::take(&mut self.rom);
+        *self = restored;
+        Ok(())
+    }
+}
+
+impl Memory for Mbc3 {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.rom[address as usize],
+            0x4000..=0x7FFF => {
+                self.rom[0x4000 * (self.rom_bank as usize % self.total_rom_banks)
+                    + (address as usize - 0x4000)]
+            }
+            0xA000..=0xBFFF if !self.ram_and_rtc_enabled => 0xFF,
+            0xA000..=0xBFFF if self.ram_bank >= 0x08 => match self.rtc.as_ref() {
+                Some(rtc) => rtc.read(self.ram_bank),
+                None => 0xFF,
+            },
+            0xA000..=0xBFFF => {
+                if let Some(ram) = self.ram.as_ref().filter(|_| self.total_ram_banks > 0) {
+                    ram[self.effective_ram_address(address)]
+                } else {
+                    log::error!(
+                        "Read from RAM address {:#6X} for {} MBC with no RAM",
+                        address,
+                        self.name()
+                    );
+                    0xFF
+                }
+            }
+            _ => {
+                log::error!("Read from {:#6X} for {} MBC", address, self.name());
+                0xFF
+            }
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_and_rtc_enabled = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // MBC30 carts with more than 2MB of ROM use all 8 bits
+                let rom_bank = if self.total_rom_banks > 128 {
+                    data
+                } else {
+                    data & 0x7F
+                };
+                self.rom_bank = rom_bank.max(0x01);
+            }
+            0x4000..=0x5FFF => self.ram_bank = data & 0x0F,
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.latch(data);
+                }
+            }
+            0xA000..=0xBFFF if !self.ram_and_rtc_enabled => {}
+            0xA000..=0xBFFF if self.ram_bank >= 0x08 => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write(self.ram_bank, data);
+                }
+            }
+            0xA000..=0xBFFF => {
+                if self.total_ram_banks > 0 {
+                    let effective_address = self.effective_ram_address(address);
+                    if let Some(ram) = self.ram.as_mut() {
+                        ram[effective_address] = data;
+                    }
+                }
+            }
+            _ => log::error!(
+                "Write to {:#6X} with {:#4X} for {} MBC",
+                address,
+                data,
+                self.name()
+            ),
+        }
+    }
+}
+// END-MBC3 ----------------------------------------------------------------------------------------
+
+// MBC5 --------------------------------------------------------------------------------------------
+
+#[derive(Serialize, Deserialize)]
+struct Mbc5 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Option<Vec<u8>>,
+
+    rom_bank: u16,
+    total_rom_banks: usize,
+
+    ram_bank: u8,
+    ram_enabled: bool,
+
+    savable: bool,
+
+    has_rumble: bool,
+    rumble_active: bool,
+}
+
+impl Mbc5 {
+    pub fn new(
+        rom: Vec<u8>,
+        mut ram: Option<Vec<u8>>,
+        savable: bool,
+        has_rumble: bool,
+        header: &CartridgeHeader,
+    ) -> Self {
+        let rom_bank = 0x01;
+        let ram_bank = 0x00;
+        let ram_enabled = false;
+        let rumble_active = false;
+
+        let ram_size = header.ram_size();
+        if ram.is_none() && ram_size > 0 {
+            log::info!(
+                "No RAM provided. Initializing RAM of size {} bytes",
+                ram_size
+            );
+            ram = Some(vec![0xFF; ram_size as usize]);
+        } else if let Some(r) = ram.as_ref() {
+            if r.len() != ram_size as usize {
+                log::error!(
+                    "Provided RAM size {} does not match what was expected {}",
+                    r.len(),
+                    ram_size
+                );
+                ram = Some(vec![0xFF; ram_size as usize]);
+            }
+        }
+
+        Mbc5 {
+            rom,
+            ram,
+            rom_bank,
+            ram_bank,
+            ram_enabled,
+            savable,
+            has_rumble,
+            rumble_active,
+            total_rom_banks: header.rom_banks(),
+        }
+    }
+}
+
+impl Mbc for Mbc5 {
+    fn name(&self) -> String {
+        "MBC5".into()
+    }
+
+    fn rom(&self) -> &Vec<u8> {
+        &self.rom
+    }
+
+    fn ram(&self) -> Option<&Vec<u8>> {
+        self.ram.as_ref()
+    }
+
+    fn savable(&self) -> bool {
+        self.savable
+    }
+
+    fn save_ram(&self) -> Option<Vec<u8>> {
+        self.ram.clone()
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        savestate::encode(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut restored: Self = savestate::decode(state)?;
+        restored.rom = std::mem::take(&mut self.rom);
+        *self = restored;
+        Ok(())
+    }
+}
+
+impl Memory for Mbc5 {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.rom[address as usize],
+            0x4000..=0x7FFF => {
+                self.rom[0x4000 * (self.rom_bank as usize % self.total_rom_banks)
+                    + (address as usize - 0x4000)]
+            }
+            0xA000..=0xBFFF if !self.ram_enabled => 0xFF,
+            0xA000..=0xBFFF => {
+                if let Some(ram) = self.ram.as_ref() {
+                    let effective_address =
+                        0x2000 * self.ram_bank as usize + (address as usize - 0xA000);
+                    ram[effective_address]
+                } else {
+                    log::error!(
+                        "Read from RAM address {:#6X} for {} MBC with no RAM",
+                        address,
+                        self.name()
+                    );
+                    0xFF
+                }
+            }
+            _ => {
+                log::error!("Read from {:#6X} for {} MBC", address, self.name());
+                0xFF
+            }
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            // Unlike MBC1 all bits of the written value matter for MBC5
+            0x0000..=0x1FFF => self.ram_enabled = data == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            0x3000..=0x3FFF => self.rom_bank = ((data as u16 & 0b1) << 8) | (self.rom_bank & 0xFF),
+            0x4000..=0x5FFF => {
+                // The lower 4 bits of the written value are the RAM bank number
+                self.ram_bank = data & 0b1111;
+                // The bit 3 enables and disables rumble
+                // TODO: Take in a RUMBLE callback from the UI code
+                self.rumble_active = data & 0b1000 != 0;
+            }
+            0xA000..=0xBFFF if self.ram_enabled => {
+                let effective_address =
+                    0x2000 * self.ram_bank as usize + (address as usize - 0xA000);
+                if let Some(ram) = self.ram.as_mut() {
+                    ram[effective_address] = data;
+                }
+            }
+            0xA000..=0xBFFF => {}
+            _ => log::error!(
+                "Write to {:#6X} with {:#4X} for {} MBC",
+                address,
+                data,
+                self.name()
+            ),
+        }
+    }
+}
+// END-MBC5 ----------------------------------------------------------------------------------------
+
+// MMM01 -------------------------------------------------------------------------------------------
+
+/// MMM01. Used by multicarts that boot into a menu in the last 32KB of the ROM. The menu sets the
+/// base and size of the selected game's ROM and RAM and then maps the game in, after which the
+/// MMM01 behaves like an MBC1 confined to that part of the ROM and RAM
+#[derive(Serialize, Deserialize)]
+struct Mmm01 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Option<Vec<u8>>,
+
+    /// Once set, the game is mapped in and the base and mask bits are locked
+    mapped: bool,
+    ram_enabled: bool,
+
+    /// Bits 0-4 of the ROM bank. Bits covered by `rom_bank_mask` are locked once mapped
+    rom_bank_low: u8,
+    /// Bits 5-6 of the ROM bank
+    rom_bank_mid: u8,
+    /// Bits 7-8 of the ROM bank
+    rom_bank_high: u8,
+    /// Locks bits 1-4 of `rom_bank_low`, limiting the game to a part of the ROM
+    rom_bank_mask: u8,
+    total_rom_banks: usize,
+
+    /// Bits 0-1 of the RAM bank. Bits covered by `ram_bank_mask` are locked once mapped
+    ram_bank_low: u8,
+    /// Bits 2-3 of the RAM bank
+    ram_bank_high: u8,
+    ram_bank_mask: u8,
+    total_ram_banks: usize,
+
+    /// MBC1 banking mode. RAM banking is only possible in mode 1
+    ram_banking_mode: bool,
+    /// Keeps the game from changing the banking mode once mapped
+    banking_mode_locked: bool,
+    /// Swaps the roles of `rom_bank_mid` and `ram_bank_low`
+    multiplex: bool,
+
+    savable: bool,
+}
+
+impl Mmm01 {
+    pub fn new(
+        mut rom: Vec<u8>,
+        mut ram: Option<Vec<u8>>,
+        savable: bool,
+        header: &CartridgeHeader,
+    ) -> Self {
+        // Some dumps store the menu at the start of the ROM, which is where the header was found
+        // if the last 32KB have none. Move it back to the end, where the MMM01 expects it
+        let menu_size = ROM_BANK_SIZE * 2;
+        if rom.len() > menu_size {
+            let menu_offset = rom.len() - menu_size;
+            if !Cartridge::is_mmm01_menu_header(&rom[menu_offset + 0x100..menu_offset + 0x150]) {
+                rom.rotate_left(menu_size);
+            }
+        }
+
+        let ram_size = header.ram_size();
+        if ram.is_none() && ram_size > 0 {
+            log::info!(
+                "No RAM provided. Initializing RAM of size {} bytes",
+                ram_size
+            );
+            ram = Some(vec![0xFF; ram_size]);
+        } else if let Some(r) = ram.as_ref() {
+            if r.len() != ram_size {
+                log::error!(
+                    "Provided RAM size {} does not match what was expected {}",
+                    r.len(),
+                    ram_size
+                );
+                ram = Some(vec![0xFF; ram_size]);
+            }
+        }
+
+        Mmm01 {
+            // The header of the menu does not always describe the whole multicart
+            total_rom_banks: rom.len() / ROM_BANK_SIZE,
+            rom,
+            ram,
+            mapped: false,
+            ram_enabled: false,
+            rom_bank_low: 0x01,
+            rom_bank_mid: 0x00,
+            rom_bank_high: 0x00,
+            rom_bank_mask: 0x00,
+            ram_bank_low: 0x00,
+            ram_bank_high: 0x00,
+            ram_bank_mask: 0x00,
+            total_ram_banks: header.ram_banks(),
+            ram_banking_mode: false,
+            banking_mode_locked: false,
+            multiplex: false,
+            savable,
+        }
+    }
+
+    /// Bits of `rom_bank_low` locked by the mask
+    fn locked_rom_bits(&self) -> u8 {
+        if self.mapped {
+            self.rom_bank_mask << 1
+        } else {
+            0x00
+        }
+    }
+
+    fn locked_ram_bits(&self) -> u8 {
+        if self.mapped {
+            self.ram_bank_mask
+        } else {
+            0x00
+        }
+    }
+
+    /// The ROM bank mid bits and RAM bank low bits, swapped when multiplexing
+    fn rom_mid_and_ram_low(&self) -> (u8, u8) {
+        if self.multiplex {
+            (self.ram_bank_low, self.rom_bank_mid)
+        } else {
+            (self.rom_bank_mid, self.ram_bank_low)
+        }
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        // Before the game is mapped in, every bank bit above bit 0 is forced high so that the
+        // menu in the last 32KB is visible
+        if !self.mapped {
+            let bank = match address {
+                0x0000..=0x3FFF => 0x1FE,
+                _ => 0x1FE | (self.rom_bank_low.max(0x01) as usize & 0b1),
+            };
+            return bank % self.total_rom_banks;
+        }
+
+        let locked = self.locked_rom_bits();
+        let low = match address {
+            // Bank 0 of the game is at the base set by the locked bits
+            0x0000..=0x3FFF => self.rom_bank_low & locked,
+            // The 00->01 translation only looks at the bits the game can change
+            _ if self.rom_bank_low & !locked & 0x1F == 0 => self.rom_bank_low | 0x01,
+            _ => self.rom_bank_low,
+        };
+        let (mid, _) = self.rom_mid_and_ram_low();
+        let bank = ((self.rom_bank_high as usize) << 7) | ((mid as usize) << 5) | low as usize;
+        bank % self.total_rom_banks
+    }
+
+    fn effective_ram_address(&self) -> Option<usize> {
+        if self.total_ram_banks == 0 {
+            return None;
+        }
+
+        let (_, mut low) = self.rom_mid_and_ram_low();
+        if !self.ram_banking_mode {
+            // Only the base set by the locked bits is used
+            low &= self.locked_ram_bits();
+        }
+        let bank = ((self.ram_bank_high as usize) << 2) | low as usize;
+        Some(0x2000 * (bank % self.total_ram_banks))
+    }
+}
+
+impl Mbc for Mmm01 {
+    fn name(&self) -> String {
+        "MMM01".into()
+    }
+
+    fn rom(&self) -> &Vec<u8> {
+        &self.rom
+    }
+
+    fn ram(&self) -> Option<&Vec<u8>> {
+        self.ram.as_ref()
+    }
+
+    fn savable(&self) -> bool {
+        self.savable
+    }
+
+    fn save_ram(&self) -> Option<Vec<u8>> {
+        self.ram.clone()
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        savestate::encode(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut restored: Self = savestate::decode(state)?;
+        restored.rom = std::mem::take(&mut self.rom);
+        *self = restored;
+        Ok(())
+    }
+}
+
+impl Memory for Mmm01 {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF => {
+                self.rom[0x4000 * self.rom_bank(address) + (address as usize & 0x3FFF)]
+            }
+            0xA000..=0xBFFF if !self.ram_enabled => 0xFF,
+            0xA000..=0xBFFF => match (self.ram.as_ref(), self.effective_ram_address()) {
+                (Some(ram), Some(base)) => ram[base + (address as usize - 0xA000)],
+                _ => 0xFF,
+            },
+            _ => {
+                log::error!("Read from {:#6X} for {} MBC", address, self.name());
+                0xFF
+            }
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                self.ram_enabled = data & 0x0F == 0x0A;
+                if !self.mapped {
+                    self.ram_bank_mask = (data >> 4) & 0b11;
+                    self.mapped = data & 0x40 != 0;
+                }
+            }
+            0x2000..=0x3FFF => {
+                let locked = self.locked_rom_bits();
+                self.rom_bank_low = (self.rom_bank_low & locked) | (data & 0x1F & !locked);
+                if !self.mapped {
+                    self.rom_bank_mid = (data >> 5) & 0b11;
+                }
+            }
+            0x4000..=0x5FFF => {
+                let locked = self.locked_ram_bits();
+                self.ram_bank_low = (self.ram_bank_low & locked) | (data & 0b11 & !locked);
+                if !self.mapped {
+                    self.ram_bank_high = (data >> 2) & 0b11;
+                    self.rom_bank_high = (data >> 4) & 0b11;
+                    self.banking_mode_locked = data & 0x40 != 0;
+                }
+            }
+            0x6000..=0x7FFF => {
+                if !(self.mapped && self.banking_mode_locked) {
+                    self.ram_banking_mode = data & 0b1 != 0;
+                }
+                if !self.mapped {
+                    self.rom_bank_mask = (data >> 2) & 0x0F;
+                    self.multiplex = data & 0x40 != 0;
+                }
+            }
+            0xA000..=0xBFFF if self.ram_enabled => {
+                if let (Some(base), Some(ram)) = (self.effective_ram_address(), self.ram.as_mut()) {
+                    ram[base + (address as usize - 0xA000)] = data;
+                }
+            }
+            0xA000..=0xBFFF => {}
+            _ => log::error!(
+                "Write to {:#6X} with {:#4X} for {} MBC",
+                address,
+                data,
+                self.name()
+            ),
+        }
+    }
+}
+// END-MMM01 ---------------------------------------------------------------------------------------
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// ROM where every byte of a bank holds the number of that bank
+    pub(crate) fn test_rom(cart_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let banks = 2 << rom_size;
+        let mut rom: Vec<u8> = (0..banks)
+            .flat_map(|bank| [bank as u8; ROM_BANK_SIZE])
+            .collect();
+        rom[0x134..0x143].fill(b'A');
+        rom[CGB_FLAG_ADDRESS as usize] = 0x80;
+        rom[CARTRIDGE_TYPE_ADDRESS as usize] = cart_type;
+        rom[ROM_SIZE_ADDRESS as usize] = rom_size;
+        rom[RAM_SIZE_ADDRESS as usize] = ram_size;
+        rom
+    }
+
+    fn write_rtc(cart: &mut Cartridge, register: u8, data: u8) {
+        cart.write(0x4000, register);
+        cart.write(0xA000, data);
+    }
+
+    fn read_rtc(cart: &mut Cartridge, register: u8) -> u8 {
+        cart.write(0x4000, register);
+        cart.read(0xA000)
+    }
+
+    fn latch_rtc(cart: &mut Cartridge) {
+        cart.write(0x6000, 0x00);
+        cart.write(0x6000, 0x01);
+    }
+
+    fn run_seconds(cart: &mut Cartridge, seconds: u32) {
+        for _ in 0..seconds * RTC_CYCLES_PER_SECOND / 4 {
+            cart.tick(false);
+        }
+    }
+
+    /// MMM01 multicart of 64 banks, with the menu header in the last 32KB
+    fn mmm01_rom() -> Vec<u8> {
+        let mut rom = test_rom(0x01, 0x05, 0x00);
+        let menu = rom.len() - ROM_BANK_SIZE * 2;
+        rom.copy_within(0x100..0x150, menu + 0x100);
+        rom[menu + CARTRIDGE_TYPE_ADDRESS as usize] = 0x0D;
+        rom[menu + RAM_SIZE_ADDRESS as usize] = 0x03;
+
+        let mut checksum: u8 = 0;
+        for byte in &rom[menu + 0x134..=menu + 0x14C] {
+            checksum = checksum.wrapping_sub(*byte).wrapping_sub(1);
+        }
+        rom[menu + HEADER_CHECKSUM_ADDRESS as usize] = checksum;
+        rom
+    }
+
+    #[test]
+    fn test_mmm01_header_detection() {
+        let cart = Cartridge::new(mmm01_rom(), None).unwrap();
+        assert_eq!(cart.name(), "MMM01");
+        assert_eq!(cart.header.ram_size(), RAM_BANK_SIZE * 4);
+
+        // A broken checksum falls back to the header of the first bank
+        let mut rom = mmm01_rom();
+        let menu = rom.len() - ROM_BANK_SIZE * 2;
+        rom[menu + HEADER_CHECKSUM_ADDRESS as usize] ^= 0xFF;
+        let cart = Cartridge::new(rom, None).unwrap();
+        assert_eq!(cart.name(), "MBC1");
+
+        // Menu stored at the start of the ROM
+        let mut rom = mmm01_rom();
+        rom.rotate_right(ROM_BANK_SIZE * 2);
+        let mut cart = Cartridge::new(rom, None).unwrap();
+        assert_eq!(cart.name(), "MMM01");
+        assert_eq!(cart.read(0x0000), 62);
+
+        // The menu is left at the end when the first bank claims to be an MMM01 too
+        let mut rom = mmm01_rom();
+        rom[CARTRIDGE_TYPE_ADDRESS as usize] = 0x0B;
+        let mut cart = Cartridge::new(rom, None).unwrap();
+        assert_eq!(cart.name(), "MMM01");
+        assert_eq!(cart.read(0x0000), 62);
+    }
+
+    #[test]
+    fn test_mmm01_menu_and_mapped_game() {
+        let mut cart = Cartridge::new(mmm01_rom(), None).unwrap();
+
+        // The menu is in the last 32KB
+        assert_eq!(cart.read(0x0000), 62);
+        assert_eq!(cart.read(0x4000), 63);
+
+        // Map in an 8 bank game at bank 8 with a single bank of RAM at bank 2
+        cart.write(0x2000, 0x08);
+        cart.write(0x4000, 0x02);
+        cart.write(0x6000, 0b1100 << 2);
+        cart.write(0x0000, 0x40 | (0b11 << 4));
+
+        assert_eq!(cart.read(0x0000), 8);
+        assert_eq!(cart.read(0x4000), 9);
+        cart.write(0x2000, 0x03);
+        assert_eq!(cart.read(0x4000), 11);
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.read(0x4000), 9);
+        // Locked bits can't be changed by the game
+        cart.write(0x2000, 0x76);
+        assert_eq!(cart.read(0x0000), 8);
+        assert_eq!(cart.read(0x4000), 14);
+
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x00);
+        cart.write(0xA123, 0x5A);
+        // Disabling RAM does not unmap the game
+        cart.write(0x0000, 0x00);
+        assert_eq!(cart.read(0x0000), 8);
+        assert_eq!(cart.read(0xA123), 0xFF);
+        assert_eq!(cart.save_ram().unwrap()[2 * RAM_BANK_SIZE + 0x123], 0x5A);
+    }
+
+    #[test]
+    fn test_mbc2_registers_and_ram() {
+        let mut cart = Cartridge::new(test_rom(0x06, 0x03, 0x00), None).unwrap();
+
+        // Address bit 8 selects the ROM bank register
+        cart.write(0x2100, 0x0B);
+        assert_eq!(cart.read(0x4000), 0x0B);
+        cart.write(0x3FFF, 0xF0);
+        assert_eq!(cart.read(0x4000), 0x01);
+        cart.write(0x0100, 0x0A);
+        assert_eq!(cart.read(0xA000), 0xFF);
+
+        cart.write(0x00FF, 0x0A);
+        cart.write(0xA005, 0xA7);
+        assert_eq!(cart.read(0xA005), 0xF7);
+        // The 512 cells are mirrored across the whole RAM area
+        assert_eq!(cart.read(0xA205), 0xF7);
+        assert_eq!(cart.read(0xBE05), 0xF7);
+        cart.write(0xB1FF, 0x03);
+        assert_eq!(cart.read(0xA1FF), 0xF3);
+
+        let save = cart.save_ram().unwrap();
+        assert_eq!(save.len(), MBC2_RAM_SIZE);
+        let mut cart = Cartridge::new(test_rom(0x06, 0x03, 0x00), Some(save)).unwrap();
+        cart.write(0x0000, 0x0A);
+        assert_eq!(cart.read(0xA005), 0xF7);
+
+        // The RAM is reported without a battery too, like for the other MBCs
+        let cart = Cartridge::new(test_rom(0x05, 0x03, 0x00), None).unwrap();
+        assert_eq!(cart.save_ram().unwrap().len(), MBC2_RAM_SIZE);
+    }
+
+    #[test]
+    fn test_mbc3_rom_and_ram_banking() {
+        let mut cart = Cartridge::new(test_rom(0x13, 0x06, 0x03), None).unwrap();
+        assert_eq!(cart.read(0x0000), 0x00);
+        assert_eq!(cart.read(0x4000), 0x01);
+
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.read(0x4000), 0x01);
+        cart.write(0x2000, 0x7F);
+        assert_eq!(cart.read(0x7FFF), 0x7F);
+
+        cart.write(0x0000, 0x0A);
+        for bank in 0..4 {
+            cart.write(0x4000, bank);
+            cart.write(0xA123, 0x10 + bank);
+        }
+        for bank in 0..4 {
+            cart.write(0x4000, bank);
+            assert_eq!(cart.read(0xA123), 0x10 + bank);
+        }
+
+        cart.write(0x0000, 0x00);
+        assert_eq!(cart.read(0xA123), 0xFF);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_counts_and_latches() {
+        let mut cart = Cartridge::new(test_rom(0x10, 0x01, 0x03), None).unwrap();
+        cart.set_rtc_mode(RtcMode::Emulated);
+        cart.write(0x0000, 0x0A);
+
+        write_rtc(&mut cart, 0x08, 59);
+        write_rtc(&mut cart, 0x09, 59);
+        write_rtc(&mut cart, 0x0A, 23);
+        write_rtc(&mut cart, 0x0B, 0xFF);
+        write_rtc(&mut cart, 0x0C, 0x01);
+        run_seconds(&mut cart, 1);
+
+        // Reads return the latched values until the next latch
+        assert_eq!(read_rtc(&mut cart, 0x08), 0);
+        latch_rtc(&mut cart);
+        assert_eq!(read_rtc(&mut cart, 0x08), 0);
+        assert_eq!(read_rtc(&mut cart, 0x09), 0);
+        assert_eq!(read_rtc(&mut cart, 0x0A), 0);
+        assert_eq!(read_rtc(&mut cart, 0x0B), 0);
+        assert_eq!(read_rtc(&mut cart, 0x0C), RTC_DAY_HIGH_CARRY);
+
+        // Halted clocks don't count
+        write_rtc(&mut cart, 0x0C, RTC_DAY_HIGH_HALT);
+        run_seconds(&mut cart, 2);
+        latch_rtc(&mut cart);
+        assert_eq!(read_rtc(&mut cart, 0x08), 0);
+
+        // Out of range values overflow without carrying
+        write_rtc(&mut cart, 0x0C, 0x00);
+        write_rtc(&mut cart, 0x08, 63);
+        run_seconds(&mut cart, 1);
+        latch_rtc(&mut cart);
+        assert_eq!(read_rtc(&mut cart, 0x08), 0);
+        assert_eq!(read_rtc(&mut cart, 0x09), 0);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_save_trailer() {
+        let rom = test_rom(0x10, 0x01, 0x02);
+        let mut cart = Cartridge::new(rom.clone(), None).unwrap();
+        cart.set_rtc_mode(RtcMode::Emulated);
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x42);
+        write_rtc(&mut cart, 0x09, 12);
+        write_rtc(&mut cart, 0x0B, 3);
+
+        let save = cart.save_ram().unwrap();
+        assert_eq!(save.len(), RAM_BANK_SIZE + RTC_TRAILER_SIZE);
+
+        let mut cart = Cartridge::new(rom.clone(), Some(save.clone())).unwrap();
+        cart.set_rtc_mode(RtcMode::Emulated);
+        cart.write(0x0000, 0x0A);
+        latch_rtc(&mut cart);
+        assert_eq!(read_rtc(&mut cart, 0x09), 12);
+        assert_eq!(read_rtc(&mut cart, 0x0B), 3);
+        cart.write(0x4000, 0x00);
+        assert_eq!(cart.read(0xA000), 0x42);
+
+        // Time passed since the save was written is caught up on with the wall clock
+        let mut save = save;
+        let an_hour_ago = unix_time() - 3600;
+        save[RAM_BANK_SIZE + 40..].copy_from_slice(&an_hour_ago.to_le_bytes());
+        let mut cart = Cartridge::new(rom, Some(save)).unwrap();
+        cart.write(0x0000, 0x0A);
+        latch_rtc(&mut cart);
+        assert_eq!(read_rtc(&mut cart, 0x0A), 1);
+        assert_eq!(read_rtc(&mut cart, 0x09), 12);
+    }
+}
@@ -0,0 +1,505 @@
+use std::io;
+use std::path::Path;
+
+use image::imageops::{self, FilterType};
+use image::{GrayImage, ImageFormat};
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use thiserror::Error;
+
+use super::{CartridgeHeader, Mbc, RAM_BANK_SIZE};
+use crate::memory::Memory;
+use crate::savestate::{self, SaveStateError};
+
+/// Size of the image captured by the sensor
+pub const CAMERA_WIDTH: usize = 128;
+pub const CAMERA_HEIGHT: usize = 112;
+
+/// Number of sensor registers mapped to 0xA000..=0xA035 when bank 0x10 is selected
+const CAMERA_REGISTERS: usize = 0x36;
+/// Selecting this RAM bank maps the sensor registers instead of RAM
+const CAMERA_REGISTER_BANK: u8 = 0x10;
+
+const REGISTER_CONTROL: usize = 0x00;
+const REGISTER_GAIN: usize = 0x01;
+const REGISTER_EXPOSURE_HIGH: usize = 0x02;
+const REGISTER_EXPOSURE_LOW: usize = 0x03;
+const REGISTER_EDGE_INVERT: usize = 0x04;
+const REGISTER_DITHER_MATRIX: usize = 0x06;
+
+/// Captured images are written as tile data to the first RAM bank starting at this offset
+const IMAGE_RAM_OFFSET: usize = 0x100;
+
+/// Amplification of the sensor output for each value of the lower 5 bits of the gain register,
+/// relative to the default gain of 4
+const GAIN_VALUES: [f64; 32] = [
+    0.8809390, 0.9149149, 0.9457498, 0.9739758, 1.0000000, 1.0241412, 1.0466537, 1.0677433,
+    1.0875793, 1.1240310, 1.1568911, 1.1868043, 1.2142561, 1.2396208, 1.2743837, 1.3157323,
+    1.3525190, 1.3856512, 1.4157897, 1.4434418, 1.4689896, 1.4927159, 1.5149063, 1.5357388,
+    1.5553846, 1.5740202, 1.5917899, 1.6088011, 1.6251395, 1.6408719, 1.6560526, 1.6707262,
+];
+/// Edge enhancement ratios selected by bits 4-6 of register 4
+const EDGE_RATIOS: [f64; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+#[derive(Error, Debug)]
+pub enum CameraError {
+    #[error("Failed to read image. Error: '{0}'")]
+    Io(#[from] io::Error),
+    #[error("Invalid image. Error: '{0}'")]
+    Image(#[from] image::ImageError),
+    #[error("Invalid grayscale image size (expected '{expected}', got '{got}')")]
+    Size { expected: usize, got: usize },
+}
+
+/// The image seen by the camera sensor in place of a webcam. Pixels are 8-bit grayscale where
+/// 0 is black
+#[derive(Clone, Debug)]
+pub struct SensorImage {
+    pixels: GrayImage,
+}
+
+impl SensorImage {
+    /// Synthetic image used until one is provided: a diagonal gradient with a checkerboard in
+    /// the middle, so that contrast, dithering and edge enhancement all have something to show
+    pub fn test_pattern() -> Self {
+        let pixels = GrayImage::from_fn(CAMERA_WIDTH as u32, CAMERA_HEIGHT as u32, |x, y| {
+            let in_checkerboard = (32..96).contains(&x) && (24..88).contains(&y);
+            let value = if in_checkerboard {
+                if (x / 8 + y / 8) % 2 == 0 {
+                    0x00
+                } else {
+                    0xFF
+                }
+            } else {
+                ((x + y) * 0xFF / (CAMERA_WIDTH + CAMERA_HEIGHT - 2) as u32) as u8
+            };
+            image::Luma([value])
+        });
+
+        Self { pixels }
+    }
+
+    /// Build from raw grayscale pixels, row by row. The image is scaled to the sensor size
+    pub fn from_grayscale(width: u32, height: u32, pixels: Vec<u8>) -> Result<Self, CameraError> {
+        let expected = width as usize * height as usize;
+        let got = pixels.len();
+        match GrayImage::from_raw(width, height, pixels) {
+            Some(image) if expected > 0 => Ok(Self::from_image(image)),
+            _ => Err(CameraError::Size { expected, got }),
+        }
+    }
+
+    /// Decode a PNG image. Color images are converted to grayscale
+    pub fn from_png(bytes: &[u8]) -> Result<Self, CameraError> {
+        let image = image::load_from_memory_with_format(bytes, ImageFormat::Png)?;
+        Ok(Self::from_image(image.to_luma8()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CameraError> {
+        Self::from_png(&std::fs::read(path)?)
+    }
+
+    fn from_image(image: GrayImage) -> Self {
+        let pixels = if image.dimensions() == (CAMERA_WIDTH as u32, CAMERA_HEIGHT as u32) {
+            image
+        } else {
+            imageops::resize(
+                &image,
+                CAMERA_WIDTH as u32,
+                CAMERA_HEIGHT as u32,
+                FilterType::Triangle,
+            )
+        };
+
+        Self { pixels }
+    }
+
+    /// Pixels outside the image repeat the closest edge
+    fn pixel(&self, x: isize, y: isize) -> u8 {
+        let x = x.clamp(0, CAMERA_WIDTH as isize - 1) as u32;
+        let y = y.clamp(0, CAMERA_HEIGHT as isize - 1) as u32;
+        self.pixels.get_pixel(x, y).0[0]
+    }
+}
+
+impl Default for SensorImage {
+    fn default() -> Self {
+        Self::test_pattern()
+    }
+}
+
+/// Game Boy Camera (Pocket Camera). Its MBC banks 1MB of ROM and 128KB of RAM like an MBC3
+/// without the RTC. Selecting RAM bank 0x10 maps the registers of the M64282FP sensor instead
+#[derive(Serialize, Deserialize)]
+pub(super) struct PocketCamera {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    #[serde(skip)]
+    sensor_image: SensorImage,
+
+    rom_bank: u8,
+    total_rom_banks: usize,
+
+    ram_bank: u8,
+    total_ram_banks: usize,
+    /// Only writes to RAM are gated. RAM can always be read
+    ram_write_enabled: bool,
+
+    #[serde(with = "BigArray")]
+    registers: [u8; CAMERA_REGISTERS],
+    /// Machine cycles left until the capture in progress completes
+    capture_cycles: u32,
+}
+
+impl PocketCamera {
+    pub fn new(rom: Vec<u8>, ram: Option<Vec<u8>>, header: &CartridgeHeader) -> Self {
+        let rom_bank = 0x01;
+        let ram_bank = 0x00;
+        let ram_write_enabled = false;
+
+        let ram_size = header.ram_size();
+        let ram = match ram {
+            Some(ram) if ram.len() == ram_size => ram,
+            Some(ram) => {
+                log::error!(
+                    "Provided RAM size {} does not match what was expected {}",
+                    ram.len(),
+                    ram_size
+                );
+                vec![0xFF; ram_size]
+            }
+            None => {
+                log::info!(
+                    "No RAM provided. Initializing RAM of size {} bytes",
+                    ram_size
+                );
+                vec![0xFF; ram_size]
+            }
+        };
+
+        PocketCamera {
+            rom,
+            ram,
+            sensor_image: SensorImage::default(),
+            rom_bank,
+            total_rom_banks: header.rom_banks(),
+            ram_bank,
+            total_ram_banks: header.ram_banks(),
+            ram_write_enabled,
+            registers: [0x00; CAMERA_REGISTERS],
+            capture_cycles: 0,
+        }
+    }
+
+    fn effective_ram_address(&self, address: u16) -> usize {
+        RAM_BANK_SIZE * (self.ram_bank as usize % self.total_ram_banks)
+            + (address as usize - 0xA000)
+    }
+
+    fn capturing(&self) -> bool {
+        self.registers[REGISTER_CONTROL] & 0b1 != 0
+    }
+
+    fn write_register(&mut self, register: usize, data: u8) {
+        match register {
+            REGISTER_CONTROL => {
+                if data & 0b1 != 0 && !self.capturing() {
+                    self.capture_cycles = self.capture_duration();
+                }
+                self.registers[REGISTER_CONTROL] = data & 0b111;
+            }
+            _ if register < CAMERA_REGISTERS => self.registers[register] = data,
+            _ => {}
+        }
+    }
+
+    /// The capture time depends on the exposure time and on the N bit (bit 7 of the gain
+    /// register)
+    fn capture_duration(&self) -> u32 {
+        let exposure = u16::from_be_bytes([
+            self.registers[REGISTER_EXPOSURE_HIGH],
+            self.registers[REGISTER_EXPOSURE_LOW],
+        ]) as u32;
+        let n_cycles = if self.registers[REGISTER_GAIN] & 0x80 != 0 {
+            0
+        } else {
+            512
+        };
+
+        32446 + n_cycles + 16 * exposure
+    }
+
+    /// Process the sensor image through the gain, exposure, edge enhancement and dithering
+    /// stages, and write the result to RAM as 16x14 tiles
+    fn capture(&mut self) {
+        let gain = GAIN_VALUES[(self.registers[REGISTER_GAIN] & 0x1F) as usize];
+        let exposure = u16::from_be_bytes([
+            self.registers[REGISTER_EXPOSURE_HIGH],
+            self.registers[REGISTER_EXPOSURE_LOW],
+        ]) as f64;
+        // 2D edge enhancement is selected with N set and both VH bits set
+        let edge_enhancement = self.registers[REGISTER_GAIN] & 0xE0 == 0xE0;
+        let edge_ratio = EDGE_RATIOS[((self.registers[REGISTER_EDGE_INVERT] >> 4) & 0x07) as usize];
+        let invert = self.registers[REGISTER_EDGE_INVERT] & 0x08 != 0;
+
+        let sensor = |x: isize, y: isize| -> f64 {
+            self.sensor_image.pixel(x, y) as f64 * gain * exposure / 2048.0
+        };
+
+        for y in 0..CAMERA_HEIGHT {
+            for x in 0..CAMERA_WIDTH {
+                let (sx, sy) = (x as isize, y as isize);
+                let mut value = sensor(sx, sy);
+                if edge_enhancement {
+                    let neighbours = sensor(sx - 1, sy)
+                        + sensor(sx + 1, sy)
+                        + sensor(sx, sy - 1)
+                        + sensor(sx, sy + 1);
+                    value += (value * 4.0 - neighbours) * edge_ratio;
+                }
+                if invert {
+                    value = 255.0 - value;
+                }
+                let value = value.clamp(0.0, 255.0) as u8;
+
+                // Each pixel is compared against the 3 thresholds of its position in the 4x4
+                // dithering matrix
+                let matrix = REGISTER_DITHER_MATRIX + ((y % 4) * 4 + x % 4) * 3;
+                let thresholds = &self.registers[matrix..matrix + 3];
+                let color = if value < thresholds[0] {
+                    3
+                } else if value < thresholds[1] {
+                    2
+                } else if value < thresholds[2] {
+                    1
+                } else {
+                    0
+                };
+
+                let tile = (y / 8) * (CAMERA_WIDTH / 8) + x / 8;
+                let address = IMAGE_RAM_OFFSET + tile * 16 + (y % 8) * 2;
+                let bit = 7 - (x % 8);
+                for (plane, byte) in self.ram[address..address + 2].iter_mut().enumerate() {
+                    *byte = (*byte & !(1 << bit)) | (((color >> plane) & 0b1) << bit);
+                }
+            }
+        }
+    }
+}
+
+impl Mbc for PocketCamera {
+    fn name(&self) -> String {
+        "POCKET CAMERA".into()
+    }
+
+    fn rom(&self) -> &Vec<u8> {
+        &self.rom
+    }
+
+    fn ram(&self) -> Option<&Vec<u8>> {
+        Some(&self.ram)
+    }
+
+    fn savable(&self) -> bool {
+        true
+    }
+
+    fn save_ram(&self) -> Option<Vec<u8>> {
+        Some(self.ram.clone())
+    }
+
+    fn tick(&mut self, _double_speed: bool) {
+        if !self.capturing() {
+            return;
+        }
+
+        self.capture_cycles = self.capture_cycles.saturating_sub(1);
+        if self.capture_cycles == 0 {
+            self.capture();
+            self.registers[REGISTER_CONTROL] &= !0b1;
+        }
+    }
+
+    fn set_camera_image(&mut self, image: SensorImage) {
+        self.sensor_image = image;
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        savestate::encode(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut restored: Self = savestate::decode(state)?;
+        restored.rom = std::mem::take(&mut self.rom);
+        restored.sensor_image = std::mem::take(&mut self.sensor_image);
+        *self = restored;
+        Ok(())
+    }
+}
+
+impl Memory for PocketCamera {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.rom[address as usize],
+            0x4000..=0x7FFF => {
+                self.rom[0x4000 * (self.rom_bank as usize % self.total_rom_banks)
+                    + (address as usize - 0x4000)]
+            }
+            // Only the control register can be read back. The registers are mirrored every
+            // 0x80 bytes
+            0xA000..=0xBFFF if self.ram_bank & CAMERA_REGISTER_BANK != 0 => {
+                if address & 0x7F == REGISTER_CONTROL as u16 {
+                    self.registers[REGISTER_CONTROL]
+                } else {
+                    0x00
+                }
+            }
+            0xA000..=0xBFFF => self.ram[self.effective_ram_address(address)],
+            _ => {
+                log::error!("Read from {:#6X} for {} MBC", address, self.name());
+                0xFF
+            }
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_write_enabled = data & 0x0F == 0x0A,
+            // Unlike most MBCs bank 0 can be mapped to 0x4000..=0x7FFF
+            0x2000..=0x3FFF => self.rom_bank = data & 0x3F,
+            0x4000..=0x5FFF => self.ram_bank = data & 0x1F,
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF if self.ram_bank & CAMERA_REGISTER_BANK != 0 => {
+                self.write_register((address & 0x7F) as usize, data)
+            }
+            0xA000..=0xBFFF if self.ram_write_enabled => {
+                let effective_address = self.effective_ram_address(address);
+                self.ram[effective_address] = data;
+            }
+            0xA000..=0xBFFF => {}
+            _ => log::error!(
+                "Write to {:#6X} with {:#4X} for {} MBC",
+                address,
+                data,
+                self.name()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::tests::test_rom;
+    use crate::cartridge::Cartridge;
+
+    /// Thresholds of a plain 4 level quantization, the same for every matrix position
+    fn write_flat_matrix(cart: &mut Cartridge) {
+        for position in 0..16 {
+            let matrix = 0xA000 + (REGISTER_DITHER_MATRIX + position * 3) as u16;
+            cart.write(matrix, 0x40);
+            cart.write(matrix + 1, 0x80);
+            cart.write(matrix + 2, 0xC0);
+        }
+    }
+
+    fn capture(cart: &mut Cartridge) {
+        cart.write(0xA000, 0x01);
+        assert_eq!(cart.read(0xA000) & 0b1, 0b1);
+        while cart.read(0xA000) & 0b1 != 0 {
+            cart.tick(false);
+        }
+    }
+
+    /// Color of a pixel in the captured image
+    fn captured_pixel(cart: &mut Cartridge, x: usize, y: usize) -> u8 {
+        let tile = (y / 8) * 16 + x / 8;
+        let address = (0xA000 + IMAGE_RAM_OFFSET + tile * 16 + (y % 8) * 2) as u16;
+        let bit = 7 - (x % 8);
+        let low = (cart.read(address) >> bit) & 0b1;
+        let high = (cart.read(address + 1) >> bit) & 0b1;
+        (high << 1) | low
+    }
+
+    fn camera_cart(image: SensorImage) -> Cartridge {
+        let mut cart = Cartridge::new(test_rom(0xFC, 0x05, 0x04), None).unwrap();
+        cart.set_camera_image(image);
+        cart.write(0x4000, CAMERA_REGISTER_BANK);
+        // Unit gain and exposure
+        cart.write(0xA001, 0x04);
+        cart.write(0xA002, 0x08);
+        cart.write(0xA003, 0x00);
+        write_flat_matrix(&mut cart);
+        cart
+    }
+
+    #[test]
+    fn test_capture_quantizes_sensor_image() {
+        let pixels = (0..CAMERA_WIDTH * CAMERA_HEIGHT)
+            .map(|i| [0x20, 0x60, 0xA0, 0xE0][i % CAMERA_WIDTH / 32])
+            .collect();
+        let image =
+            SensorImage::from_grayscale(CAMERA_WIDTH as u32, CAMERA_HEIGHT as u32, pixels).unwrap();
+        let mut cart = camera_cart(image);
+        capture(&mut cart);
+
+        cart.write(0x4000, 0x00);
+        for y in [0, 57, 111] {
+            assert_eq!(captured_pixel(&mut cart, 0, y), 3);
+            assert_eq!(captured_pixel(&mut cart, 40, y), 2);
+            assert_eq!(captured_pixel(&mut cart, 70, y), 1);
+            assert_eq!(captured_pixel(&mut cart, 127, y), 0);
+        }
+    }
+
+    #[test]
+    fn test_capture_inverts_output() {
+        let image = SensorImage::from_grayscale(1, 1, vec![0x00]).unwrap();
+        let mut cart = camera_cart(image);
+        cart.write(0xA004, 0x08);
+        capture(&mut cart);
+
+        cart.write(0x4000, 0x00);
+        assert_eq!(captured_pixel(&mut cart, 64, 56), 0);
+    }
+
+    #[test]
+    fn test_registers_and_ram_banking() {
+        let mut cart = camera_cart(SensorImage::test_pattern());
+        // Registers other than the control register read back as 0
+        assert_eq!(cart.read(0xA001), 0x00);
+        assert_eq!(cart.read(0xA080), 0x00);
+
+        cart.write(0x4000, 0x03);
+        cart.write(0xA010, 0x42);
+        assert_eq!(cart.read(0xA010), 0xFF);
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA010, 0x42);
+        assert_eq!(cart.read(0xA010), 0x42);
+        cart.write(0x4000, 0x02);
+        assert_ne!(cart.read(0xA010), 0x42);
+
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.read(0x4000), 0x00);
+        cart.write(0x2000, 0x3F);
+        assert_eq!(cart.read(0x4000), 0x3F);
+    }
+
+    #[test]
+    fn test_sensor_image_from_png() {
+        let mut png = Vec::new();
+        GrayImage::from_pixel(256, 224, image::Luma([0x80]))
+            .write_to(&mut io::Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        let image = SensorImage::from_png(&png).unwrap();
+        assert_eq!(
+            image.pixels.dimensions(),
+            (CAMERA_WIDTH as u32, CAMERA_HEIGHT as u32)
+        );
+        assert_eq!(image.pixel(10, 10), 0x80);
+        assert!(SensorImage::from_png(&[0x00; 16]).is_err());
+        assert!(SensorImage::from_grayscale(2, 2, vec![0x00; 3]).is_err());
+    }
+}
@@ -0,0 +1,640 @@
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+
+use super::{unix_time, CartridgeHeader, Mbc, RtcMode, RTC_CYCLES_PER_SECOND};
+use crate::memory::Memory;
+use crate::savestate::{self, SaveStateError};
+
+/// Value read from the IR port when no light is received. Nothing is ever on the other end
+const IR_NO_LIGHT: u8 = 0xC0;
+
+/// Check the size of the provided RAM, or allocate it if there is none
+fn init_ram(ram: Option<Vec<u8>>, ram_size: usize) -> Vec<u8> {
+    match ram {
+        Some(ram) if ram.len() == ram_size => ram,
+        Some(ram) => {
+            log::error!(
+                "Provided RAM size {} does not match what was expected {}",
+                ram.len(),
+                ram_size
+            );
+            vec![0xFF; ram_size]
+        }
+        None => {
+            log::info!(
+                "No RAM provided. Initializing RAM of size {} bytes",
+                ram_size
+            );
+            vec![0xFF; ram_size]
+        }
+    }
+}
+
+fn ram_address(ram: &[u8], ram_bank: u8, address: u16) -> Option<usize> {
+    let total_ram_banks = ram.len() / 0x2000;
+    (total_ram_banks > 0)
+        .then(|| 0x2000 * (ram_bank as usize % total_ram_banks) + (address as usize - 0xA000))
+}
+
+// HuC1 --------------------------------------------------------------------------------------------
+
+/// Hudson HuC1. Banks like a simplified MBC1 and can map an infrared LED and receiver in place of
+/// the RAM
+#[derive(Serialize, Deserialize)]
+pub(super) struct Huc1 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+
+    rom_bank: u8,
+    total_rom_banks: usize,
+    ram_bank: u8,
+
+    /// 0xA000..=0xBFFF accesses the IR port instead of the RAM
+    ir_selected: bool,
+    ir_led_on: bool,
+}
+
+impl Huc1 {
+    pub fn new(rom: Vec<u8>, ram: Option<Vec<u8>>, header: &CartridgeHeader) -> Self {
+        Huc1 {
+            rom,
+            ram: init_ram(ram, header.ram_size()),
+            rom_bank: 0x01,
+            total_rom_banks: header.rom_banks(),
+            ram_bank: 0x00,
+            ir_selected: false,
+            ir_led_on: false,
+        }
+    }
+}
+
+impl Mbc for Huc1 {
+    fn name(&self) -> String {
+        "HuC1".into()
+    }
+
+    fn rom(&self) -> &Vec<u8> {
+        &self.rom
+    }
+
+    fn ram(&self) -> Option<&Vec<u8>> {
+        Some(&self.ram)
+    }
+
+    fn savable(&self) -> bool {
+        true
+    }
+
+    fn save_ram(&self) -> Option<Vec<u8>> {
+        (!self.ram.is_empty()).then(|| self.ram.clone())
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        savestate::encode(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut restored: Self = savestate::decode(state)?;
+        restored.rom = std::mem::take(&mut self.rom);
+        *self = restored;
+        Ok(())
+    }
+}
+
+impl Memory for Huc1 {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.rom[address as usize],
+            0x4000..=0x7FFF => {
+                self.rom[0x4000 * (self.rom_bank as usize % self.total_rom_banks)
+                    + (address as usize - 0x4000)]
+            }
+            0xA000..=0xBFFF if self.ir_selected => IR_NO_LIGHT,
+            // There is no RAM enable, the RAM is always readable
+            0xA000..=0xBFFF => match ram_address(&self.ram, self.ram_bank, address) {
+                Some(effective_address) => self.ram[effective_address],
+                None => 0xFF,
+            },
+            _ => {
+                log::error!("Read from {:#6X} for {} MBC", address, self.name());
+                0xFF
+            }
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ir_selected = data & 0x0F == 0x0E,
+            0x2000..=0x3FFF => self.rom_bank = (data & 0x3F).max(0x01),
+            0x4000..=0x5FFF => self.ram_bank = data & 0x03,
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF if self.ir_selected => self.ir_led_on = data & 0b1 != 0,
+            0xA000..=0xBFFF => {
+                if let Some(effective_address) = ram_address(&self.ram, self.ram_bank, address) {
+                    self.ram[effective_address] = data;
+                }
+            }
+            _ => log::error!(
+                "Write to {:#6X} with {:#4X} for {} MBC",
+                address,
+                data,
+                self.name()
+            ),
+        }
+    }
+}
+// END-HuC1 ----------------------------------------------------------------------------------------
+
+// HuC3 --------------------------------------------------------------------------------------------
+
+/// Size of the clock trailer appended to the save file. The format is shared with SameBoy: a
+/// 64-bit UNIX timestamp, followed by the minutes and days of the clock and the alarm as 16-bit
+/// values and the alarm enable as a single byte
+const HUC3_TRAILER_SIZE: usize = 17;
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+/// The day counter is 12 bits wide
+const HUC3_DAYS: u64 = 0x1000;
+
+/// Nibbles of the clock memory the time is copied to and from
+const HUC3_TIME_ADDRESS: usize = 0x00;
+const HUC3_ALARM_ADDRESS: usize = 0x10;
+const HUC3_ALARM_ENABLE_ADDRESS: usize = 0x16;
+/// Nibble selecting the tone played by the speaker
+const HUC3_TONE_ADDRESS: usize = 0x27;
+
+/// Value of the 0x0000..=0x1FFF register selecting what 0xA000..=0xBFFF accesses
+mod huc3_mode {
+    pub const RAM_READ: u8 = 0x0;
+    pub const RAM_READ_WRITE: u8 = 0xA;
+    pub const COMMAND: u8 = 0xB;
+    pub const RESPONSE: u8 = 0xC;
+    pub const SEMAPHORE: u8 = 0xD;
+    pub const IR: u8 = 0xE;
+}
+
+/// The clock of the HuC3. Counts minutes of the day and days, with no visible seconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct Huc3Clock {
+    mode: RtcMode,
+    seconds: u8,
+    minutes: u16,
+    days: u16,
+
+    alarm_minutes: u16,
+    alarm_days: u16,
+    alarm_enabled: bool,
+
+    /// Cycles into the current second when running in `RtcMode::Emulated`
+    cycles: u32,
+    /// UNIX time the clock was last brought up to date with when running in
+    /// `RtcMode::WallClock`
+    last_update: u64,
+}
+
+impl Huc3Clock {
+    fn new() -> Self {
+        Self {
+            mode: RtcMode::default(),
+            seconds: 0,
+            minutes: 0,
+            days: 0,
+            alarm_minutes: 0,
+            alarm_days: 0,
+            alarm_enabled: false,
+            cycles: 0,
+            last_update: unix_time(),
+        }
+    }
+
+    /// Restore the clock from the trailer of a save file. Time passed since the save was written
+    /// is caught up on when running in `RtcMode::WallClock`
+    fn from_trailer(trailer: &[u8]) -> Self {
+        let value = |index: usize| u16::from_le_bytes([trailer[index], trailer[index + 1]]);
+        Self {
+            minutes: value(8) % MINUTES_PER_DAY as u16,
+            days: value(10) % HUC3_DAYS as u16,
+            alarm_minutes: value(12),
+            alarm_days: value(14),
+            alarm_enabled: trailer[16] & 0b1 != 0,
+            last_update: u64::from_le_bytes(trailer[0..8].try_into().unwrap()),
+            ..Self::new()
+        }
+    }
+
+    fn trailer(&self) -> Vec<u8> {
+        let mut clock = *self;
+        clock.sync();
+
+        let mut trailer = Vec::with_capacity(HUC3_TRAILER_SIZE);
+        trailer.extend_from_slice(&unix_time().to_le_bytes());
+        for value in [
+            clock.minutes,
+            clock.days,
+            clock.alarm_minutes,
+            clock.alarm_days,
+        ] {
+            trailer.extend_from_slice(&value.to_le_bytes());
+        }
+        trailer.push(clock.alarm_enabled as u8);
+        trailer
+    }
+
+    fn set_mode(&mut self, mode: RtcMode) {
+        if mode != self.mode {
+            self.sync();
+            self.mode = mode;
+            self.last_update = unix_time();
+        }
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        let total = self.seconds as u64 + seconds;
+        let minutes = self.minutes as u64 + total / 60;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (minutes % MINUTES_PER_DAY) as u16;
+        self.days = ((self.days as u64 + minutes / MINUTES_PER_DAY) % HUC3_DAYS) as u16;
+    }
+
+    fn tick(&mut self, double_speed: bool) {
+        if self.mode != RtcMode::Emulated {
+            return;
+        }
+
+        self.cycles += if double_speed { 2 } else { 4 };
+        if self.cycles >= RTC_CYCLES_PER_SECOND {
+            self.cycles -= RTC_CYCLES_PER_SECOND;
+            self.advance(1);
+        }
+    }
+
+    /// Catch up with the wall clock
+    fn sync(&mut self) {
+        if self.mode != RtcMode::WallClock {
+            return;
+        }
+
+        let now = unix_time();
+        self.advance(now.saturating_sub(self.last_update));
+        self.last_update = now;
+    }
+}
+
+/// Hudson HuC3. Besides ROM and RAM banking it has a clock, an infrared port and a speaker. The
+/// clock and speaker are driven by a small controller that is sent commands one nibble at a
+/// time and has its own memory of 256 nibbles
+#[derive(Serialize, Deserialize)]
+pub(super) struct Huc3 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    clock: Huc3Clock,
+
+    rom_bank: u8,
+    total_rom_banks: usize,
+    ram_bank: u8,
+    /// See `huc3_mode`
+    mode: u8,
+
+    #[serde(with = "BigArray")]
+    memory: [u8; 0x100],
+    address: u8,
+    /// Last command written. After a read command the low nibble holds the value read
+    command: u8,
+
+    ir_led_on: bool,
+}
+
+impl Huc3 {
+    pub fn new(rom: Vec<u8>, mut ram: Option<Vec<u8>>, header: &CartridgeHeader) -> Self {
+        let ram_size = header.ram_size();
+        let mut clock = Huc3Clock::new();
+
+        // The clock state is appended to the save RAM
+        if let Some(r) = ram.as_mut() {
+            if r.len() == ram_size + HUC3_TRAILER_SIZE {
+                clock = Huc3Clock::from_trailer(&r[ram_size..]);
+                r.truncate(ram_size);
+            }
+        }
+
+        Huc3 {
+            rom,
+            ram: init_ram(ram, ram_size),
+            clock,
+            rom_bank: 0x01,
+            total_rom_banks: header.rom_banks(),
+            ram_bank: 0x00,
+            mode: huc3_mode::RAM_READ,
+            memory: [0x00; 0x100],
+            address: 0x00,
+            command: 0x00,
+            ir_led_on: false,
+        }
+    }
+
+    fn write_nibbles(&mut self, address: usize, value: u16, count: usize) {
+        for i in 0..count {
+            self.memory[address + i] = (value >> (4 * i)) as u8 & 0x0F;
+        }
+    }
+
+    fn read_nibbles(&self, address: usize, count: usize) -> u16 {
+        (0..count).fold(0, |value, i| {
+            value | (self.memory[address + i] as u16) << (4 * i)
+        })
+    }
+
+    /// Run the last command written. The controller finishes every command instantly
+    fn execute(&mut self) {
+        let argument = self.command & 0x0F;
+        match self.command >> 4 {
+            // Read the nibble at the address and advance it
+            0x1 => {
+                self.command = 0x10 | self.memory[self.address as usize];
+                self.address = self.address.wrapping_add(1);
+            }
+            // Write the nibble to the address and advance it
+            0x3 => {
+                self.memory[self.address as usize] = argument;
+                self.address = self.address.wrapping_add(1);
+            }
+            0x4 => self.address = (self.address & 0xF0) | argument,
+            0x5 => self.address = (self.address & 0x0F) | (argument << 4),
+            0x6 => self.execute_extended(argument),
+            command => log::warn!("Unknown HuC3 command {:#X}", command),
+        }
+    }
+
+    fn execute_extended(&mut self, argument: u8) {
+        match argument {
+            // Copy the clock and alarm to memory
+            0x0 => {
+                self.clock.sync();
+                let clock = self.clock;
+                self.write_nibbles(HUC3_TIME_ADDRESS, clock.minutes, 3);
+                self.write_nibbles(HUC3_TIME_ADDRESS + 3, clock.days, 3);
+                self.write_nibbles(HUC3_ALARM_ADDRESS, clock.alarm_minutes, 3);
+                self.write_nibbles(HUC3_ALARM_ADDRESS + 3, clock.alarm_days, 3);
+                self.memory[HUC3_ALARM_ENABLE_ADDRESS] = clock.alarm_enabled as u8;
+            }
+            // Set the clock and alarm from memory
+            0x1 => {
+                self.clock.sync();
+                self.clock.seconds = 0;
+                self.clock.cycles = 0;
+                self.clock.minutes =
+                    self.read_nibbles(HUC3_TIME_ADDRESS, 3) % MINUTES_PER_DAY as u16;
+                self.clock.days = self.read_nibbles(HUC3_TIME_ADDRESS + 3, 3);
+                self.clock.alarm_minutes = self.read_nibbles(HUC3_ALARM_ADDRESS, 3);
+                self.clock.alarm_days = self.read_nibbles(HUC3_ALARM_ADDRESS + 3, 3);
+                self.clock.alarm_enabled = self.memory[HUC3_ALARM_ENABLE_ADDRESS] & 0b1 != 0;
+            }
+            // Status check. Always reports the controller as working
+            0x2 => self.command = 0x61,
+            // Play the tone selected in memory. The speaker is not emulated
+            0xE => log::info!(
+                "HuC3 speaker tone {:#X} is not supported",
+                self.memory[HUC3_TONE_ADDRESS]
+            ),
+            argument => log::warn!("Unknown HuC3 extended command {:#X}", argument),
+        }
+    }
+}
+
+impl Mbc for Huc3 {
+    fn name(&self) -> String {
+        "HuC3".into()
+    }
+
+    fn rom(&self) -> &Vec<u8> {
+        &self.rom
+    }
+
+    fn ram(&self) -> Option<&Vec<u8>> {
+        Some(&self.ram)
+    }
+
+    fn savable(&self) -> bool {
+        true
+    }
+
+    fn save_ram(&self) -> Option<Vec<u8>> {
+        let mut save = self.ram.clone();
+        save.extend_from_slice(&self.clock.trailer());
+        Some(save)
+    }
+
+    fn tick(&mut self, double_speed: bool) {
+        self.clock.tick(double_speed);
+    }
+
+    fn set_rtc_mode(&mut self, mode: RtcMode) {
+        self.clock.set_mode(mode);
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        savestate::encode(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut restored: Self = savestate::decode(state)?;
+        restored.rom = std::mem::take(&mut self.rom);
+        *self = restored;
+        Ok(())
+    }
+}
+
+impl Memory for Huc3 {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.rom[address as usize],
+            0x4000..=0x7FFF => {
+                self.rom[0x4000 * (self.rom_bank as usize % self.total_rom_banks)
+                    + (address as usize - 0x4000)]
+            }
+            0xA000..=0xBFFF => match self.mode {
+                huc3_mode::RAM_READ | huc3_mode::RAM_READ_WRITE => {
+                    match ram_address(&self.ram, self.ram_bank, address) {
+                        Some(effective_address) => self.ram[effective_address],
+                        None => 0xFF,
+                    }
+                }
+                huc3_mode::RESPONSE => 0x80 | self.command,
+                // Bit 0 set reports that the last command has completed
+                huc3_mode::SEMAPHORE => 0xFF,
+                huc3_mode::IR => IR_NO_LIGHT,
+                _ => 0xFF,
+            },
+            _ => {
+                log::error!("Read from {:#6X} for {} MBC", address, self.name());
+                0xFF
+            }
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.mode = data & 0x0F,
+            0x2000..=0x3FFF => self.rom_bank = (data & 0x7F).max(0x01),
+            0x4000..=0x5FFF => self.ram_bank = data & 0x03,
+            0x6000..=0x7FFF => {}
+            0xA000..=0xBFFF => match self.mode {
+                huc3_mode::RAM_READ_WRITE => {
+                    if let Some(effective_address) = ram_address(&self.ram, self.ram_bank, address)
+                    {
+                        self.ram[effective_address] = data;
+                    }
+                }
+                huc3_mode::COMMAND => self.command = data & 0x7F,
+                // Clearing bit 0 runs the command
+                huc3_mode::SEMAPHORE if data & 0b1 == 0 => self.execute(),
+                huc3_mode::IR => self.ir_led_on = data & 0b1 != 0,
+                _ => {}
+            },
+            _ => log::error!(
+                "Write to {:#6X} with {:#4X} for {} MBC",
+                address,
+                data,
+                self.name()
+            ),
+        }
+    }
+}
+// END-HuC3 ----------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::tests::test_rom;
+    use crate::cartridge::Cartridge;
+
+    fn huc3_command(cart: &mut Cartridge, command: u8) -> u8 {
+        cart.write(0x0000, huc3_mode::COMMAND);
+        cart.write(0xA000, command);
+        cart.write(0x0000, huc3_mode::SEMAPHORE);
+        cart.write(0xA000, 0xFE);
+        assert_eq!(cart.read(0xA000) & 0b1, 0b1);
+        cart.write(0x0000, huc3_mode::RESPONSE);
+        cart.read(0xA000)
+    }
+
+    fn huc3_set_address(cart: &mut Cartridge, address: u8) {
+        huc3_command(cart, 0x40 | (address & 0x0F));
+        huc3_command(cart, 0x50 | (address >> 4));
+    }
+
+    fn huc3_write_nibbles(cart: &mut Cartridge, address: u8, nibbles: &[u8]) {
+        huc3_set_address(cart, address);
+        for nibble in nibbles {
+            huc3_command(cart, 0x30 | nibble);
+        }
+    }
+
+    fn huc3_read_nibbles(cart: &mut Cartridge, address: u8, count: usize) -> Vec<u8> {
+        huc3_set_address(cart, address);
+        (0..count)
+            .map(|_| huc3_command(cart, 0x10) & 0x0F)
+            .collect()
+    }
+
+    #[test]
+    fn test_huc1_banking_and_ir() {
+        let mut cart = Cartridge::new(test_rom(0xFF, 0x05, 0x03), None).unwrap();
+
+        cart.write(0x2000, 0x2B);
+        assert_eq!(cart.read(0x4000), 0x2B);
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.read(0x4000), 0x01);
+
+        // The RAM needs no enabling
+        cart.write(0x4000, 0x02);
+        cart.write(0xA010, 0x42);
+        assert_eq!(cart.read(0xA010), 0x42);
+
+        cart.write(0x0000, 0x0E);
+        assert_eq!(cart.read(0xA010), IR_NO_LIGHT);
+        cart.write(0xA010, 0x01);
+        cart.write(0x0000, 0x00);
+        assert_eq!(cart.read(0xA010), 0x42);
+
+        let save = cart.save_ram().unwrap();
+        assert_eq!(save[2 * 0x2000 + 0x10], 0x42);
+    }
+
+    #[test]
+    fn test_huc3_ram_modes() {
+        let mut cart = Cartridge::new(test_rom(0xFE, 0x05, 0x03), None).unwrap();
+        cart.write(0x4000, 0x01);
+
+        cart.write(0x0000, huc3_mode::RAM_READ);
+        cart.write(0xA000, 0x12);
+        assert_eq!(cart.read(0xA000), 0xFF);
+
+        cart.write(0x0000, huc3_mode::RAM_READ_WRITE);
+        cart.write(0xA000, 0x12);
+        cart.write(0x0000, huc3_mode::RAM_READ);
+        assert_eq!(cart.read(0xA000), 0x12);
+
+        cart.write(0x0000, huc3_mode::IR);
+        assert_eq!(cart.read(0xA000), IR_NO_LIGHT);
+    }
+
+    #[test]
+    fn test_huc3_clock_commands() {
+        let mut cart = Cartridge::new(test_rom(0xFE, 0x05, 0x03), None).unwrap();
+        cart.set_rtc_mode(RtcMode::Emulated);
+
+        // 23:59 on day 0x123
+        let minutes = MINUTES_PER_DAY as u16 - 1;
+        let nibbles = [
+            minutes as u8 & 0x0F,
+            (minutes >> 4) as u8 & 0x0F,
+            (minutes >> 8) as u8,
+            0x3,
+            0x2,
+            0x1,
+        ];
+        huc3_write_nibbles(&mut cart, 0x00, &nibbles);
+        huc3_command(&mut cart, 0x61);
+
+        for _ in 0..60 * RTC_CYCLES_PER_SECOND / 4 {
+            cart.tick(false);
+        }
+
+        assert_eq!(huc3_command(&mut cart, 0x60), 0x80 | 0x60);
+        assert_eq!(
+            huc3_read_nibbles(&mut cart, 0x00, 6),
+            [0, 0, 0, 0x4, 0x2, 0x1]
+        );
+
+        assert_eq!(huc3_command(&mut cart, 0x62) & 0x0F, 0x1);
+    }
+
+    #[test]
+    fn test_huc3_clock_persists_in_save() {
+        let rom = test_rom(0xFE, 0x05, 0x03);
+        let mut cart = Cartridge::new(rom.clone(), None).unwrap();
+        huc3_write_nibbles(&mut cart, 0x00, &[0xA, 0x5, 0x0, 0x7, 0x0, 0x0]);
+        huc3_command(&mut cart, 0x61);
+
+        let save = cart.save_ram().unwrap();
+        assert_eq!(save.len(), 4 * 0x2000 + HUC3_TRAILER_SIZE);
+        let trailer = &save[4 * 0x2000..];
+        assert_eq!(trailer[8..12], [0x5A, 0x00, 0x07, 0x00]);
+
+        // Pretend the save was written two days ago
+        let mut save = save.clone();
+        let two_days_ago = unix_time() - 2 * 24 * 60 * 60;
+        save[4 * 0x2000..4 * 0x2000 + 8].copy_from_slice(&two_days_ago.to_le_bytes());
+
+        let mut cart = Cartridge::new(rom, Some(save)).unwrap();
+        assert_eq!(cart.ram().unwrap().len(), 4 * 0x2000);
+        huc3_command(&mut cart, 0x60);
+        assert_eq!(
+            huc3_read_nibbles(&mut cart, 0x00, 6),
+            [0xA, 0x5, 0x0, 0x9, 0x0, 0x0]
+        );
+    }
+}
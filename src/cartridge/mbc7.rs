@@ -0,0 +1,479 @@
+use serde::{Deserialize, Serialize};
+
+use super::{CartridgeHeader, Mbc};
+use crate::memory::Memory;
+use crate::savestate::{self, SaveStateError};
+
+/// Size of the 93LC56 EEPROM: 128 16-bit words
+const EEPROM_SIZE: usize = 256;
+const EEPROM_WORDS: u8 = 128;
+
+/// Accelerometer reading when the Game Boy is held flat
+const ACCELEROMETER_CENTER: u16 = 0x81D0;
+/// Change in the accelerometer reading for a tilt of 1g
+const ACCELEROMETER_G: f32 = 0x70 as f32;
+/// Reading after the latch was erased and before the next latch
+const ACCELEROMETER_ERASED: u16 = 0x8000;
+
+/// Bits of the EEPROM register at 0xAx8x
+const EEPROM_CS: u8 = 1 << 7;
+const EEPROM_CLK: u8 = 1 << 6;
+const EEPROM_DI: u8 = 1 << 1;
+const EEPROM_DO: u8 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum EepromState {
+    /// Waiting for the start bit
+    Idle,
+    /// Shifting in the 2 bit opcode and the 8 bit address
+    Command,
+    /// Shifting out words starting at the address. Reads continue into the next word
+    Read { address: u8 },
+    /// Shifting in the 16 bit word to write to the address, or to every word
+    Write { address: Option<u8> },
+    /// The instruction completed. Nothing happens until CS is lowered
+    Done,
+}
+
+/// The 93LC56 serial EEPROM in 16-bit word mode. Instructions are clocked in one bit at a time on
+/// DI on the rising edge of CLK while CS is high. Writes complete instantly
+#[derive(Serialize, Deserialize)]
+struct Eeprom {
+    data: Vec<u8>,
+    state: EepromState,
+    write_enabled: bool,
+
+    /// Value last written to the register. Holds CS, CLK and DI
+    pins: u8,
+    data_out: bool,
+
+    /// Bits shifted in or out for the current instruction
+    shift: u16,
+    bits: u8,
+}
+
+impl Eeprom {
+    fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            state: EepromState::Idle,
+            write_enabled: false,
+            pins: 0x00,
+            data_out: true,
+            shift: 0,
+            bits: 0,
+        }
+    }
+
+    fn word(&self, address: u8) -> u16 {
+        let index = (address % EEPROM_WORDS) as usize * 2;
+        u16::from_le_bytes([self.data[index], self.data[index + 1]])
+    }
+
+    fn set_word(&mut self, address: u8, word: u16) {
+        if self.write_enabled {
+            let index = (address % EEPROM_WORDS) as usize * 2;
+            self.data[index..index + 2].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    fn read(&self) -> u8 {
+        // Unused bits read back as written
+        (self.pins & (EEPROM_CS | EEPROM_CLK | EEPROM_DI)) | self.data_out as u8
+    }
+
+    fn write(&mut self, data: u8) {
+        let rising_clock = self.pins & EEPROM_CLK == 0 && data & EEPROM_CLK != 0;
+        self.pins = data;
+
+        if data & EEPROM_CS == 0 {
+            // Lowering CS aborts any instruction in progress
+            self.state = EepromState::Idle;
+            return;
+        }
+
+        if rising_clock {
+            self.clock(data & EEPROM_DI != 0);
+        }
+    }
+
+    fn shift_in(&mut self, bit: bool) {
+        self.shift = (self.shift << 1) | bit as u16;
+        self.bits += 1;
+    }
+
+    fn clock(&mut self, bit: bool) {
+        match self.state {
+            EepromState::Idle if bit => {
+                self.state = EepromState::Command;
+                self.shift = 0;
+                self.bits = 0;
+            }
+            EepromState::Idle | EepromState::Done => {}
+            EepromState::Command => {
+                self.shift_in(bit);
+                if self.bits == 10 {
+                    self.execute((self.shift >> 8) as u8 & 0b11, self.shift as u8);
+                }
+            }
+            EepromState::Read { address } => {
+                self.data_out = self.shift & 0x8000 != 0;
+                self.shift <<= 1;
+                self.bits -= 1;
+                if self.bits == 0 {
+                    let address = address.wrapping_add(1) % EEPROM_WORDS;
+                    self.state = EepromState::Read { address };
+                    self.shift = self.word(address);
+                    self.bits = 16;
+                }
+            }
+            EepromState::Write { address } => {
+                self.shift_in(bit);
+                if self.bits == 16 {
+                    match address {
+                        Some(address) => self.set_word(address, self.shift),
+                        None => (0..EEPROM_WORDS).for_each(|a| self.set_word(a, self.shift)),
+                    }
+                    self.finish();
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, opcode: u8, address: u8) {
+        self.shift = 0;
+        self.bits = 0;
+
+        match opcode {
+            // READ. A dummy 0 is output before the data
+            0b10 => {
+                let address = address % EEPROM_WORDS;
+                self.data_out = false;
+                self.state = EepromState::Read { address };
+                self.shift = self.word(address);
+                self.bits = 16;
+            }
+            // WRITE
+            0b01 => {
+                self.state = EepromState::Write {
+                    address: Some(address),
+                }
+            }
+            // ERASE
+            0b11 => {
+                self.set_word(address, 0xFFFF);
+                self.finish();
+            }
+            // The top 2 bits of the address select one of the extended instructions
+            _ => match address >> 6 {
+                // EWEN
+                0b11 => {
+                    self.write_enabled = true;
+                    self.finish();
+                }
+                // EWDS
+                0b00 => {
+                    self.write_enabled = false;
+                    self.finish();
+                }
+                // ERAL
+                0b10 => {
+                    (0..EEPROM_WORDS).for_each(|a| self.set_word(a, 0xFFFF));
+                    self.finish();
+                }
+                // WRAL
+                _ => self.state = EepromState::Write { address: None },
+            },
+        }
+    }
+
+    fn finish(&mut self) {
+        // DO reports that the EEPROM is ready for the next instruction
+        self.data_out = true;
+        self.state = EepromState::Done;
+    }
+}
+
+/// MBC7. Used by cartridges with a 2-axis accelerometer and a serial EEPROM in place of RAM.
+/// Both are accessed through registers at 0xA000..=0xAFFF once the 2 RAM enable registers are set
+#[derive(Serialize, Deserialize)]
+pub(super) struct Mbc7 {
+    #[serde(skip)]
+    rom: Vec<u8>,
+    eeprom: Eeprom,
+
+    rom_bank: u8,
+    total_rom_banks: usize,
+
+    ram_enabled_1: bool,
+    ram_enabled_2: bool,
+
+    /// Current tilt of the Game Boy in g. Positive X is tilted right, positive Y is tilted
+    /// towards the player
+    tilt: (f32, f32),
+    latched_x: u16,
+    latched_y: u16,
+    latch_erased: bool,
+}
+
+impl Mbc7 {
+    pub fn new(rom: Vec<u8>, eeprom: Option<Vec<u8>>, header: &CartridgeHeader) -> Self {
+        let eeprom = match eeprom {
+            Some(eeprom) if eeprom.len() == EEPROM_SIZE => eeprom,
+            Some(eeprom) => {
+                log::error!(
+                    "Provided EEPROM size {} does not match what was expected {}",
+                    eeprom.len(),
+                    EEPROM_SIZE
+                );
+                vec![0xFF; EEPROM_SIZE]
+            }
+            None => {
+                log::info!(
+                    "No EEPROM provided. Initializing EEPROM of size {} bytes",
+                    EEPROM_SIZE
+                );
+                vec![0xFF; EEPROM_SIZE]
+            }
+        };
+
+        Mbc7 {
+            rom,
+            eeprom: Eeprom::new(eeprom),
+            rom_bank: 0x01,
+            total_rom_banks: header.rom_banks(),
+            ram_enabled_1: false,
+            ram_enabled_2: false,
+            tilt: (0.0, 0.0),
+            latched_x: ACCELEROMETER_ERASED,
+            latched_y: ACCELEROMETER_ERASED,
+            latch_erased: false,
+        }
+    }
+
+    fn registers_enabled(&self) -> bool {
+        self.ram_enabled_1 && self.ram_enabled_2
+    }
+
+    fn accelerometer_value(tilt: f32) -> u16 {
+        (ACCELEROMETER_CENTER as f32 + tilt * ACCELEROMETER_G).clamp(0.0, u16::MAX as f32) as u16
+    }
+
+    fn read_register(&self, register: u16) -> u8 {
+        match register {
+            0x2 => self.latched_x as u8,
+            0x3 => (self.latched_x >> 8) as u8,
+            0x4 => self.latched_y as u8,
+            0x5 => (self.latched_y >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            0x0 if data == 0x55 => {
+                self.latched_x = ACCELEROMETER_ERASED;
+                self.latched_y = ACCELEROMETER_ERASED;
+                self.latch_erased = true;
+            }
+            // Only latches once after each erase
+            0x1 if data == 0xAA && self.latch_erased => {
+                self.latched_x = Self::accelerometer_value(self.tilt.0);
+                self.latched_y = Self::accelerometer_value(self.tilt.1);
+                self.latch_erased = false;
+            }
+            0x8 => self.eeprom.write(data),
+            _ => {}
+        }
+    }
+}
+
+impl Mbc for Mbc7 {
+    fn name(&self) -> String {
+        "MBC7".into()
+    }
+
+    fn rom(&self) -> &Vec<u8> {
+        &self.rom
+    }
+
+    fn savable(&self) -> bool {
+        true
+    }
+
+    fn save_ram(&self) -> Option<Vec<u8>> {
+        Some(self.eeprom.data.clone())
+    }
+
+    fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.tilt = (x, y);
+    }
+
+    fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        savestate::encode(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut restored: Self = savestate::decode(state)?;
+        restored.rom = std::mem::take(&mut self.rom);
+        // The tilt is input, not part of the machine state
+        restored.tilt = self.tilt;
+        *self = restored;
+        Ok(())
+    }
+}
+
+impl Memory for Mbc7 {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => self.rom[address as usize],
+            0x4000..=0x7FFF => {
+                self.rom[0x4000 * (self.rom_bank as usize % self.total_rom_banks)
+                    + (address as usize - 0x4000)]
+            }
+            // Bits 4-7 of the address select the register
+            0xA000..=0xAFFF if self.registers_enabled() => {
+                self.read_register((address >> 4) & 0x0F)
+            }
+            0xA000..=0xBFFF => 0xFF,
+            _ => {
+                log::error!("Read from {:#6X} for {} MBC", address, self.name());
+                0xFF
+            }
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled_1 = data == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = data,
+            0x4000..=0x5FFF => self.ram_enabled_2 = data == 0x40,
+            0x6000..=0x7FFF => {}
+            0xA000..=0xAFFF if self.registers_enabled() => {
+                self.write_register((address >> 4) & 0x0F, data)
+            }
+            0xA000..=0xBFFF => {}
+            _ => log::error!(
+                "Write to {:#6X} with {:#4X} for {} MBC",
+                address,
+                data,
+                self.name()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::tests::test_rom;
+    use crate::cartridge::Cartridge;
+
+    const EEPROM_REGISTER: u16 = 0xA080;
+
+    fn mbc7_cart(eeprom: Option<Vec<u8>>) -> Cartridge {
+        let mut cart = Cartridge::new(test_rom(0x22, 0x06, 0x00), eeprom).unwrap();
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x40);
+        cart
+    }
+
+    /// Clock a bit into the EEPROM and return DO after the rising edge
+    fn clock_bit(cart: &mut Cartridge, bit: bool) -> bool {
+        let di = if bit { EEPROM_DI } else { 0 };
+        cart.write(EEPROM_REGISTER, EEPROM_CS | di);
+        cart.write(EEPROM_REGISTER, EEPROM_CS | EEPROM_CLK | di);
+        cart.read(EEPROM_REGISTER) & EEPROM_DO != 0
+    }
+
+    fn send(cart: &mut Cartridge, value: u16, bits: u8) {
+        for i in (0..bits).rev() {
+            clock_bit(cart, (value >> i) & 0b1 != 0);
+        }
+    }
+
+    /// Start bit ahead of the 2-bit opcode and the 8-bit address of an instruction
+    const EEPROM_START_BIT: u16 = 1 << 10;
+
+    fn instruction(cart: &mut Cartridge, opcode: u16, address: u16) {
+        cart.write(EEPROM_REGISTER, 0x00);
+        send(cart, EEPROM_START_BIT | (opcode << 8) | address, 11);
+    }
+
+    fn read_word(cart: &mut Cartridge) -> u16 {
+        (0..16).fold(0, |word, _| (word << 1) | clock_bit(cart, false) as u16)
+    }
+
+    #[test]
+    fn test_eeprom_write_and_read() {
+        let mut cart = mbc7_cart(None);
+
+        // Writes are ignored until enabled
+        instruction(&mut cart, 0b01, 0x05);
+        send(&mut cart, 0x1234, 16);
+        instruction(&mut cart, 0b10, 0x05);
+        assert_eq!(read_word(&mut cart), 0xFFFF);
+
+        instruction(&mut cart, 0b00, 0b1100_0000); // EWEN
+        instruction(&mut cart, 0b01, 0x05);
+        send(&mut cart, 0x1234, 16);
+        assert!(cart.read(EEPROM_REGISTER) & EEPROM_DO != 0);
+        instruction(&mut cart, 0b01, 0x06);
+        send(&mut cart, 0xBEEF, 16);
+
+        // Reads continue with the next word
+        instruction(&mut cart, 0b10, 0x05);
+        assert_eq!(read_word(&mut cart), 0x1234);
+        assert_eq!(read_word(&mut cart), 0xBEEF);
+
+        instruction(&mut cart, 0b11, 0x05); // ERASE
+        instruction(&mut cart, 0b10, 0x05);
+        assert_eq!(read_word(&mut cart), 0xFFFF);
+
+        let save = cart.save_ram().unwrap();
+        assert_eq!(save.len(), EEPROM_SIZE);
+        assert_eq!(save[12..14], [0xEF, 0xBE]);
+
+        let mut cart = mbc7_cart(Some(save));
+        instruction(&mut cart, 0b10, 0x06);
+        assert_eq!(read_word(&mut cart), 0xBEEF);
+    }
+
+    #[test]
+    fn test_eeprom_write_and_erase_all() {
+        let mut cart = mbc7_cart(None);
+        instruction(&mut cart, 0b00, 0b1100_0000); // EWEN
+        instruction(&mut cart, 0b00, 0b0100_0000); // WRAL
+        send(&mut cart, 0xA5A5, 16);
+        instruction(&mut cart, 0b10, 0x7F);
+        assert_eq!(read_word(&mut cart), 0xA5A5);
+        assert_eq!(read_word(&mut cart), 0xA5A5);
+
+        instruction(&mut cart, 0b00, 0b1000_0000); // ERAL
+        assert!(cart.save_ram().unwrap().iter().all(|&byte| byte == 0xFF));
+    }
+
+    #[test]
+    fn test_accelerometer_latch() {
+        let mut cart = mbc7_cart(None);
+        cart.set_accelerometer(1.0, -0.5);
+
+        // Latching without erasing first does nothing
+        cart.write(0xA010, 0xAA);
+        assert_eq!(cart.read(0xA020), 0x00);
+        assert_eq!(cart.read(0xA030), 0x80);
+
+        cart.write(0xA000, 0x55);
+        cart.write(0xA010, 0xAA);
+        let x = u16::from_le_bytes([cart.read(0xA020), cart.read(0xA030)]);
+        let y = u16::from_le_bytes([cart.read(0xA040), cart.read(0xA050)]);
+        assert_eq!(x, ACCELEROMETER_CENTER + 0x70);
+        assert_eq!(y, ACCELEROMETER_CENTER - 0x38);
+
+        // Registers are only mapped with both enables set
+        cart.write(0x4000, 0x00);
+        assert_eq!(cart.read(0xA020), 0xFF);
+    }
+}
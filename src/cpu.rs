@@ -1,15 +1,26 @@
 use crate::debug::{CpuDebug, ExecutedOpcode};
 use crate::interrupts::{InterruptType, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS};
+use crate::joypad::JOYP_ADDRESS;
 use crate::memory::SystemBus;
-use crate::ExecutionState;
+use crate::timer::DIV_ADDRESS;
+use crate::{ExecutionState, HardwareModel};
 use circular_buffer::CircularBuffer;
 use paste::paste;
+use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
 
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
 pub(crate) struct Cpu<BusType: SystemBus> {
     regs: Registers,
     ime: bool,
+    /// Set by EI, IME is only enabled after the instruction that follows it
+    ime_scheduled: bool,
+    /// Set by a HALT that did not halt with IME off, the next byte is read twice
+    halt_bug: bool,
     previous_execution_state: Option<ExecutionState>,
+    // Only used for debugging
+    #[serde(skip, default = "CircularBuffer::new")]
     opcodes: CircularBuffer<10, ExecutedOpcode>,
     _bus: PhantomData<BusType>,
 }
@@ -24,12 +35,28 @@ impl<BusType: SystemBus> Cpu<BusType> {
         Cpu {
             regs,
             ime,
+            ime_scheduled: false,
+            halt_bug: false,
             previous_execution_state: None,
             opcodes,
             _bus: Default::default(),
         }
     }
 
+    /// Start at 0x0100 with the registers the boot ROM of the model leaves behind
+    pub(crate) fn skip_boot_rom(
+        &mut self,
+        model: HardwareModel,
+        dmg_compat: bool,
+        title_checksum: Option<u8>,
+        header_checksum: u8,
+    ) {
+        self.regs = Registers::post_boot(model, dmg_compat, title_checksum, header_checksum);
+        self.ime = false;
+        self.ime_scheduled = false;
+        self.halt_bug = false;
+    }
+
     pub fn debug(&self) -> CpuDebug {
         CpuDebug {
             registers: self.regs,
@@ -39,12 +66,23 @@ impl<BusType: SystemBus> Cpu<BusType> {
 
     fn fetch(&mut self, mmu: &mut BusType) -> u8 {
         let byte = mmu.read(self.regs.pc);
-        self.regs.pc = self.regs.pc.wrapping_add(1);
+        if self.halt_bug {
+            // PC fails to increment once after the HALT bug
+            self.halt_bug = false;
+        } else {
+            self.regs.pc = self.regs.pc.wrapping_add(1);
+        }
         byte
     }
 
     pub fn execute(&mut self, mmu: &mut BusType) {
-        if self.check_for_pending_interrupts(mmu) {
+        // Only the joypad wakes the CPU up from STOP mode, and nothing from a lock-up
+        let execution_state = mmu.system_state().execution_state;
+        let interruptible = matches!(
+            execution_state,
+            ExecutionState::ExecutingProgram | ExecutionState::Halted
+        );
+        if interruptible && self.check_for_pending_interrupts(mmu) {
             self.handle_interrupts(mmu);
         }
 
@@ -52,11 +90,31 @@ impl<BusType: SystemBus> Cpu<BusType> {
         match execution_state {
               ExecutionState::Halted                   // CPU does not execute when halted
             | ExecutionState::PreparingSpeedSwitch     // switching speed
+            | ExecutionState::Locked                   // or hit an illegal opcode
             => mmu.tick(),
-            ExecutionState::ExecutingProgram => self.execute_opcode(mmu),
+            ExecutionState::Stopped => {
+                if Self::button_held(mmu) {
+                    mmu.system_state().execution_state = ExecutionState::ExecutingProgram;
+                }
+                mmu.tick();
+            }
+            ExecutionState::ExecutingProgram => {
+                let enable_ime = self.ime_scheduled;
+                self.execute_opcode(mmu);
+                // An EI executed before this opcode takes effect now, unless a DI cancelled it
+                if enable_ime && self.ime_scheduled {
+                    self.ime = true;
+                    self.ime_scheduled = false;
+                }
+            }
         }
     }
 
+    /// Whether a button of the rows selected in JOYP is pressed
+    fn button_held(mmu: &mut BusType) -> bool {
+        mmu.unticked_read(JOYP_ADDRESS) & 0x0F != 0x0F
+    }
+
     fn check_for_pending_interrupts(&self, mmu: &mut BusType) -> bool {
         let intf = mmu.unticked_read(INTERRUPT_FLAG_ADDRESS);
         let inte = mmu.unticked_read(INTERRUPT_ENABLE_ADDRESS);
@@ -67,20 +125,12 @@ impl<BusType: SystemBus> Cpu<BusType> {
 
     /// CPU Interrupt Handler. Should take 5 m-cycles
     fn handle_interrupts(&mut self, mmu: &mut BusType) {
-        // Cycle 1
-        let intf = mmu.read(INTERRUPT_FLAG_ADDRESS);
-        // Cycle 2
-        let inte = mmu.read(INTERRUPT_ENABLE_ADDRESS);
-
-        let ii = intf & inte;
-        if ii == 0x00 {
-            return;
-        }
-
         // When there are pending interrupts, the CPU starts executing again and jumps to the interrupt
         // with the highest priority
         if let Some(previous_execution_state) = self.previous_execution_state {
-            mmu.system_state().execution_state = previous_execution_state;
+            if mmu.system_state().execution_state == ExecutionState::Halted {
+                mmu.system_state().execution_state = previous_execution_state;
+            }
         }
 
         // However, if there are pending interrupts, but *all* interrupts are disabled, the CPU still
@@ -88,6 +138,17 @@ impl<BusType: SystemBus> Cpu<BusType> {
         if !self.ime {
             return;
         }
+
+        // Cycle 1
+        let intf = mmu.read(INTERRUPT_FLAG_ADDRESS);
+        // Cycle 2
+        let inte = mmu.read(INTERRUPT_ENABLE_ADDRESS);
+
+        let ii = intf & inte;
+        if ii == 0x00 {
+            return;
+        }
+
         self.ime = false;
         let highest_priority_interrupt = ii.trailing_zeros();
         let interrupt = InterruptType::from_index(highest_priority_interrupt);
@@ -164,24 +225,39 @@ impl<BusType: SystemBus> Cpu<BusType> {
             0xE8 => self.add_sp_i8(opcode_byte, mmu),
             0xF8 => self.ld_hl_sp_i8(opcode_byte, mmu),
             0xF9 => self.ld_sp_hl(opcode_byte, mmu),
-            0xFB => self.ei(opcode_byte, mmu),
+            0xFB => self.ei(opcode_byte),
             0x08 => self.ld_u16_sp(opcode_byte, mmu),
             0xC7 | 0xD7 | 0xE7 | 0xF7 | 0xCF | 0xDF | 0xEF | 0xFF => self.rst(opcode_byte, mmu),
-            _ => panic!(
-                "Unimplemented or illegal opcode {:#04X} at PC: {:#06X}",
-                opcode_byte,
-                self.regs.pc - 1
-            ),
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+                self.lock_up(opcode_byte, mmu)
+            }
         };
     }
 
     // Opcode Implementations
     fn stop(&mut self, mmu: &mut BusType) {
-        if mmu.system_state().key1 & 0b1 == 0b1 {
-            // If a speed switch has been requested
-            mmu.system_state().execution_state = ExecutionState::PreparingSpeedSwitch;
+        let interrupt_pending = self.check_for_pending_interrupts(mmu);
+        // STOP skips the byte after it unless an interrupt is pending
+        if !interrupt_pending {
+            self.fetch(mmu);
+        }
+
+        if Self::button_held(mmu) {
+            // A held button keeps the CPU from stopping. It halts instead, if it would not wake
+            // up right away. DIV is not reset and the speed does not switch
+            if !interrupt_pending {
+                self.halt(0x76, mmu);
+            }
+            return;
         }
-        self.fetch(mmu);
+
+        mmu.unticked_write(DIV_ADDRESS, 0x00);
+        mmu.system_state().execution_state = if mmu.system_state().key1 & 0b1 == 0b1 {
+            // If a speed switch has been requested. The CPU is stalled until DIV overflows
+            ExecutionState::PreparingSpeedSwitch
+        } else {
+            ExecutionState::Stopped
+        };
     }
 
     fn ld_r16_u16(&mut self, opcode: u8, mmu: &mut BusType) {
@@ -438,7 +514,27 @@ impl<BusType: SystemBus> Cpu<BusType> {
         ByteRegister::for_r8(b543, self).set(result, mmu);
     }
 
+    fn lock_up(&mut self, opcode: u8, mmu: &mut BusType) {
+        log::warn!(
+            "CPU locked up on illegal opcode {:#04X} at PC: {:#06X}",
+            opcode,
+            self.regs.pc.wrapping_sub(1)
+        );
+        mmu.system_state().execution_state = ExecutionState::Locked;
+    }
+
     fn halt(&mut self, _: u8, mmu: &mut BusType) {
+        // HALT does not halt if an interrupt is already pending
+        if self.check_for_pending_interrupts(mmu) {
+            if self.ime_scheduled {
+                // With EI right before HALT the interrupt is serviced and returns to the HALT
+                self.regs.pc = self.regs.pc.wrapping_sub(1);
+            } else if !self.ime {
+                self.halt_bug = true;
+            }
+            return;
+        }
+
         self.previous_execution_state = Some(mmu.system_state().execution_state);
         mmu.system_state().execution_state = ExecutionState::Halted;
     }
@@ -800,6 +896,7 @@ impl<BusType: SystemBus> Cpu<BusType> {
 
     fn di(&mut self, _: u8) {
         self.ime = false;
+        self.ime_scheduled = false;
     }
 
     fn add_hl_r16(&mut self, opcode: u8, mmu: &mut BusType) {
@@ -848,16 +945,9 @@ impl<BusType: SystemBus> Cpu<BusType> {
         mmu.tick();
     }
 
-    fn ei(&mut self, _: u8, mmu: &mut BusType) {
-        // The effect of EI is delayed by one m-cycle
-        // TODO: Check behaviour if EI is followed by a HALT
-
-        // Don't execute another opcode if running CPU opcode tests
-        #[cfg(not(test))]
-        {
-            self.execute_opcode(mmu);
-        }
-        self.ime = true;
+    fn ei(&mut self, _: u8) {
+        // The effect of EI is delayed until after the next opcode
+        self.ime_scheduled = true;
     }
 
     fn ld_u16_sp(&mut self, _: u8, mmu: &mut BusType) {
@@ -894,7 +984,7 @@ enum FlagRegisterMask {
     Carry = 1 << 4,
 }
 
-#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub struct FlagRegister {
     pub zero: bool,
     pub negative: bool,
@@ -938,7 +1028,7 @@ impl From<FlagRegister> for u8 {
     }
 }
 
-#[derive(Default, Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Default, Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Registers {
     a: u8,
     pub f: FlagRegister,
@@ -977,6 +1067,75 @@ impl Registers {
     register_pair!(d, e);
     register_pair!(h, l);
     register_pair!(a, f);
+
+    /// Registers after the boot ROM hands over to the cartridge. The DMG boot ROMs leave the flags
+    /// of the header checksum comparison behind, the SGB one always clears them. On a CGB, DMG
+    /// games get the title checksum of Nintendo games in B, which also decides HL. The AGB boot
+    /// ROM increments B before handing over
+    fn post_boot(
+        model: HardwareModel,
+        dmg_compat: bool,
+        title_checksum: Option<u8>,
+        header_checksum: u8,
+    ) -> Self {
+        let mut regs = Registers {
+            sp: 0xFFFE,
+            pc: 0x0100,
+            ..Default::default()
+        };
+
+        if model == HardwareModel::Sgb {
+            regs.a = 0x01;
+            regs.f = 0x00.into();
+            regs.set_bc(0x0014);
+            regs.set_hl(0xC060);
+            return regs;
+        }
+
+        if !model.is_cgb() {
+            regs.a = if model == HardwareModel::Mgb {
+                0xFF
+            } else {
+                0x01
+            };
+            regs.f = FlagRegister {
+                zero: true,
+                negative: false,
+                half_carry: header_checksum != 0x00,
+                carry: header_checksum != 0x00,
+            };
+            regs.set_bc(0x0013);
+            regs.set_de(0x00D8);
+            regs.set_hl(0x014D);
+            return regs;
+        }
+
+        regs.a = 0x11;
+        regs.f = 0x80.into();
+        if dmg_compat {
+            regs.b = title_checksum.unwrap_or(0x00);
+            regs.set_de(0x0008);
+            regs.set_hl(match regs.b {
+                0x43 | 0x58 => 0x991A,
+                _ => 0x007C,
+            });
+        } else {
+            regs.set_de(0xFF56);
+            regs.set_hl(0x000D);
+        }
+
+        if model == HardwareModel::Agb {
+            regs.b = regs.b.wrapping_add(1);
+            regs.f = FlagRegister {
+                zero: regs.b == 0x00,
+                negative: false,
+                half_carry: regs.b & 0x0F == 0x00,
+                carry: false,
+            };
+        }
+
+        regs
+    }
 }
 
 // Register decoding for opcodes
@@ -1355,6 +1514,243 @@ pub mod tests {
         }
     }
 
+    /// Program at 0x0100 with the buttons selected in JOYP and DIV at 0xAB
+    fn program(program: &[u8]) -> Vec<RamState> {
+        let mut ram: Vec<RamState> = (0x0100..)
+            .zip(program)
+            .map(|(address, byte)| RamState(address, *byte))
+            .collect();
+        ram.push(RamState(JOYP_ADDRESS, 0xDF));
+        ram.push(RamState(DIV_ADDRESS, 0xAB));
+        ram
+    }
+
+    /// Program at 0x0100 with a V-Blank interrupt requested and enabled
+    fn pending_interrupt_program(program: &[u8]) -> Vec<RamState> {
+        let mut ram = self::program(program);
+        ram.push(RamState(INTERRUPT_FLAG_ADDRESS, 0x01));
+        ram.push(RamState(INTERRUPT_ENABLE_ADDRESS, 0x01));
+        ram
+    }
+
+    fn cycle(address: u16, data: u8, kind: &str) -> Option<CycleState> {
+        Some(CycleState(address, data, kind.to_string()))
+    }
+
+    fn cpu_at_0100() -> Cpu<FlatMmu<'static>> {
+        let mut cpu = Cpu::new();
+        cpu.ime = false;
+        cpu.regs.pc = 0x0100;
+        cpu.regs.sp = 0xFFFE;
+        cpu
+    }
+
+    #[test]
+    fn test_halt_bug_reads_the_next_byte_twice() {
+        // HALT, LD A,0x14 is executed as HALT, LD A,0x3E, INC D
+        let ram = pending_interrupt_program(&[0x76, 0x3E, 0x14]);
+        let cycles = [
+            cycle(0x0100, 0x76, "read"),
+            cycle(0x0101, 0x3E, "read"),
+            cycle(0x0101, 0x3E, "read"),
+            cycle(0x0102, 0x14, "read"),
+        ];
+        let mut mmu = FlatMmu::new(&ram, &cycles);
+        let mut cpu = cpu_at_0100();
+
+        cpu.execute(&mut mmu);
+        assert_eq!(
+            mmu.system_state.execution_state,
+            ExecutionState::ExecutingProgram
+        );
+        assert_eq!(cpu.regs.pc, 0x0101);
+
+        cpu.execute(&mut mmu);
+        assert_eq!(cpu.regs.a, 0x3E);
+        assert_eq!(cpu.regs.pc, 0x0102);
+
+        cpu.execute(&mut mmu);
+        assert_eq!(cpu.regs.d, 0x01);
+        assert_eq!(cpu.regs.pc, 0x0103);
+        assert_eq!(mmu.ticked_cycle_count, 4);
+    }
+
+    #[test]
+    fn test_ei_takes_effect_after_the_next_opcode() {
+        // EI, NOP with the handler at 0x0040 being a NOP
+        let ram = pending_interrupt_program(&[0xFB, 0x00]);
+        let cycles = [
+            cycle(0x0100, 0xFB, "read"),
+            cycle(0x0101, 0x00, "read"),
+            cycle(INTERRUPT_FLAG_ADDRESS, 0x01, "read"),
+            cycle(INTERRUPT_ENABLE_ADDRESS, 0x01, "read"),
+            cycle(0xFFFD, 0x01, "write"),
+            cycle(0xFFFC, 0x02, "write"),
+            None,
+            cycle(0x0040, 0x00, "read"),
+        ];
+        let mut mmu = FlatMmu::new(&ram, &cycles);
+        let mut cpu = cpu_at_0100();
+
+        cpu.execute(&mut mmu);
+        assert!(!cpu.ime);
+
+        // The interrupt is not serviced before the NOP
+        cpu.execute(&mut mmu);
+        assert!(cpu.ime);
+        assert_eq!(cpu.regs.pc, 0x0102);
+
+        cpu.execute(&mut mmu);
+        assert!(!cpu.ime);
+        assert_eq!(cpu.regs.pc, 0x0041);
+        assert_eq!(cpu.regs.sp, 0xFFFC);
+    }
+
+    #[test]
+    fn test_di_cancels_a_pending_ei() {
+        // EI, DI, NOP
+        let ram = pending_interrupt_program(&[0xFB, 0xF3, 0x00]);
+        let cycles = [
+            cycle(0x0100, 0xFB, "read"),
+            cycle(0x0101, 0xF3, "read"),
+            cycle(0x0102, 0x00, "read"),
+        ];
+        let mut mmu = FlatMmu::new(&ram, &cycles);
+        let mut cpu = cpu_at_0100();
+
+        for _ in 0..3 {
+            cpu.execute(&mut mmu);
+        }
+        assert!(!cpu.ime);
+        assert_eq!(cpu.regs.pc, 0x0103);
+    }
+
+    #[test]
+    fn test_ei_before_halt_returns_to_the_halt() {
+        // EI, HALT with the handler at 0x0040 being a NOP
+        let ram = pending_interrupt_program(&[0xFB, 0x76]);
+        let cycles = [
+            cycle(0x0100, 0xFB, "read"),
+            cycle(0x0101, 0x76, "read"),
+            cycle(INTERRUPT_FLAG_ADDRESS, 0x01, "read"),
+            cycle(INTERRUPT_ENABLE_ADDRESS, 0x01, "read"),
+            cycle(0xFFFD, 0x01, "write"),
+            cycle(0xFFFC, 0x01, "write"),
+            None,
+            cycle(0x0040, 0x00, "read"),
+        ];
+        let mut mmu = FlatMmu::new(&ram, &cycles);
+        let mut cpu = cpu_at_0100();
+
+        for _ in 0..3 {
+            cpu.execute(&mut mmu);
+        }
+        assert_eq!(mmu.memory[0xFFFC], 0x01);
+        assert_eq!(mmu.memory[0xFFFD], 0x01);
+        assert_eq!(cpu.regs.pc, 0x0041);
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks_up_the_cpu() {
+        // Pending interrupts don't wake up a locked CPU
+        let ram = pending_interrupt_program(&[0xD3, 0x00]);
+        let cycles = [cycle(0x0100, 0xD3, "read"), None, None];
+        let mut mmu = FlatMmu::new(&ram, &cycles);
+        let mut cpu = cpu_at_0100();
+
+        cpu.execute(&mut mmu);
+        assert_eq!(mmu.system_state.execution_state, ExecutionState::Locked);
+
+        cpu.execute(&mut mmu);
+        cpu.execute(&mut mmu);
+        assert_eq!(mmu.system_state.execution_state, ExecutionState::Locked);
+        assert_eq!(cpu.regs.pc, 0x0101);
+        assert_eq!(cpu.regs.sp, 0xFFFE);
+        assert_eq!(mmu.ticked_cycle_count, 3);
+    }
+
+    #[test]
+    fn test_stop_mode_lasts_until_a_button_is_pressed() {
+        // STOP, INC B
+        let ram = program(&[0x10, 0x00, 0x04]);
+        let cycles = [
+            cycle(0x0100, 0x10, "read"),
+            cycle(0x0101, 0x00, "read"),
+            None,
+            None,
+            cycle(0x0102, 0x04, "read"),
+        ];
+        let mut mmu = FlatMmu::new(&ram, &cycles);
+        let mut cpu = cpu_at_0100();
+
+        cpu.execute(&mut mmu);
+        assert_eq!(mmu.system_state.execution_state, ExecutionState::Stopped);
+        assert_eq!(mmu.memory[DIV_ADDRESS as usize], 0x00);
+        assert_eq!(cpu.regs.pc, 0x0102);
+
+        cpu.execute(&mut mmu);
+        assert_eq!(mmu.system_state.execution_state, ExecutionState::Stopped);
+
+        // Press A
+        mmu.memory[JOYP_ADDRESS as usize] = 0xDE;
+        cpu.execute(&mut mmu);
+        assert_eq!(
+            mmu.system_state.execution_state,
+            ExecutionState::ExecutingProgram
+        );
+        cpu.execute(&mut mmu);
+        assert_eq!(cpu.regs.b, 0x01);
+    }
+
+    #[test]
+    fn test_stop_with_a_button_held() {
+        // The CPU halts instead and skips a byte
+        let mut ram = program(&[0x10, 0x00]);
+        ram.push(RamState(JOYP_ADDRESS, 0xDE));
+        let cycles = [cycle(0x0100, 0x10, "read"), cycle(0x0101, 0x00, "read")];
+        let mut mmu = FlatMmu::new(&ram, &cycles);
+        let mut cpu = cpu_at_0100();
+
+        cpu.execute(&mut mmu);
+        assert_eq!(mmu.system_state.execution_state, ExecutionState::Halted);
+        assert_eq!(mmu.memory[DIV_ADDRESS as usize], 0xAB);
+        assert_eq!(cpu.regs.pc, 0x0102);
+
+        // Unless an interrupt is pending, then STOP does nothing and is a single byte
+        let mut ram = pending_interrupt_program(&[0x10, 0x04]);
+        ram.push(RamState(JOYP_ADDRESS, 0xDE));
+        let cycles = [cycle(0x0100, 0x10, "read"), cycle(0x0101, 0x04, "read")];
+        let mut mmu = FlatMmu::new(&ram, &cycles);
+        let mut cpu = cpu_at_0100();
+
+        cpu.execute(&mut mmu);
+        assert_eq!(
+            mmu.system_state.execution_state,
+            ExecutionState::ExecutingProgram
+        );
+        assert_eq!(mmu.memory[DIV_ADDRESS as usize], 0xAB);
+        cpu.execute(&mut mmu);
+        assert_eq!(cpu.regs.b, 0x01);
+    }
+
+    #[test]
+    fn test_stop_switches_speed() {
+        // With an interrupt pending, STOP is a single byte
+        let ram = pending_interrupt_program(&[0x10]);
+        let cycles = [cycle(0x0100, 0x10, "read")];
+        let mut mmu = FlatMmu::new(&ram, &cycles);
+        mmu.system_state.key1 = 0x01;
+        let mut cpu = cpu_at_0100();
+
+        cpu.execute(&mut mmu);
+        assert_eq!(
+            mmu.system_state.execution_state,
+            ExecutionState::PreparingSpeedSwitch
+        );
+        assert_eq!(mmu.memory[DIV_ADDRESS as usize], 0x00);
+        assert_eq!(cpu.regs.pc, 0x0101);
+    }
+
     macro_rules! test_opcode {
         ($opcode: literal) => {
             paste! {
@@ -1,16 +1,42 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use std::{fs, io};
 
-use crate::cartridge::{Cartridge, CartridgeHeader};
+use crate::apu::StereoSample;
+use crate::cartridge::{Cartridge, CartridgeHeader, RtcMode, SensorImage};
 use crate::debug::CpuDebug;
 use crate::framebuffer::access;
 use crate::joypad::JoypadKeys;
 use crate::memory::SystemBus;
-use crate::HardwareSupport;
-use crate::{cpu::Cpu, mmu::Mmu, GameFrame};
+use crate::ppu::Renderer;
+use crate::savestate::{self, SaveStateError, SaveStateHeader};
+use crate::serial::LinkPeer;
+use crate::textures::Texture;
+use crate::{cpu::Cpu, mmu::Mmu};
+use crate::{ExecutionState, HardwareModel, HardwareSupport};
+
+pub mod rewind;
 
 const CYCLES_PER_FRAME: u64 = 17556;
+/// Number of machine cycles per second in single speed mode
+const CYCLES_PER_SECOND: u64 = 1_048_576;
+
+/// Real time taken by one frame. The Game Boy runs at ~59.73 frames per second
+pub const FRAME_DURATION: Duration =
+    Duration::from_nanos(CYCLES_PER_FRAME * 1_000_000_000 / CYCLES_PER_SECOND);
+
+/// Settings a `Gameboy` is created with
+#[derive(Debug, Clone, Default)]
+pub struct GameboyOptions {
+    /// Model of Game Boy the cartridge runs on
+    pub model: HardwareModel,
+    /// Boot ROM run before the cartridge. Without one, the cartridge is started at 0x0100 in the
+    /// state the boot ROM would have left the machine in
+    pub boot_rom: Option<Vec<u8>>,
+    /// How the PPU draws the LCD image
+    pub renderer: Renderer,
+}
 
 pub struct Gameboy {
     mmu: Mmu,
@@ -18,8 +44,12 @@ pub struct Gameboy {
 }
 
 impl Gameboy {
-    pub fn new(rom: Vec<u8>, ram: Option<Vec<u8>>) -> (Self, CartridgeHeader) {
-        let cart = Cartridge::new(rom, ram).unwrap();
+    pub fn new(
+        rom: Vec<u8>,
+        ram: Option<Vec<u8>>,
+        options: GameboyOptions,
+    ) -> (Self, CartridgeHeader) {
+        let mut cart = Cartridge::new(rom, ram).unwrap();
         let header = cart.header.clone();
 
         log::info!("Loaded a cartridge with title: {}", header.title);
@@ -35,8 +65,38 @@ impl Gameboy {
             HardwareSupport::DmgCompat => log::info!("Game is running in DMG compatibility mode"),
         }
 
-        let mmu = Mmu::new(cart);
-        let cpu = Cpu::new();
+        let model = options.model;
+        log::info!("Running on {}", model);
+
+        let boot_rom = options.boot_rom.filter(|boot_rom| {
+            let valid = boot_rom.len() == model.boot_rom_size();
+            if !valid {
+                log::error!(
+                    "Boot ROM size {} does not match what was expected {}. Skipping the boot ROM",
+                    boot_rom.len(),
+                    model.boot_rom_size()
+                );
+            }
+            valid
+        });
+
+        let title_checksum = cart.nintendo_title_checksum();
+        let header_checksum = cart.header_checksum();
+        let skip_boot_rom = boot_rom.is_none();
+        let mut mmu = Mmu::new(cart, model, boot_rom);
+        mmu.ppu.set_renderer(options.renderer);
+        let mut cpu = Cpu::new();
+        if skip_boot_rom {
+            log::info!("No boot ROM provided. Starting the cartridge at 0x0100");
+            mmu.skip_boot_rom();
+            cpu.skip_boot_rom(
+                model,
+                header.hardware_supported == HardwareSupport::DmgCompat,
+                title_checksum,
+                header_checksum,
+            );
+        }
+
         (Gameboy { mmu, cpu }, header)
     }
 
@@ -60,8 +120,29 @@ impl Gameboy {
         self.mmu.system_state().carry_over_cycles = carry_over_cycles;
     }
 
-    pub fn write_frame(&self, frame_writer: &mut access::AccessW<GameFrame>) {
-        self.mmu.ppu.write_frame(frame_writer);
+    /// Address and value of the illegal opcode the CPU locked up on, if it did
+    pub fn lockup(&mut self) -> Option<(u16, u8)> {
+        if self.mmu.system_state().execution_state != ExecutionState::Locked {
+            return None;
+        }
+        let address = self.cpu.debug().registers.pc.wrapping_sub(1);
+        Some((address, self.mmu.unticked_read(address)))
+    }
+
+    /// Write the picture to show. This is the LCD image, or on the SGB, the colored LCD image
+    /// inside the border
+    pub fn write_frame(&self, frame_writer: &mut access::AccessW<Texture>) {
+        let frame = self.mmu.ppu.frame();
+        match self.mmu.sgb.as_ref() {
+            Some(sgb) => sgb.render(frame, frame_writer.get().write()),
+            None => *frame_writer.get().write() = frame.clone(),
+        }
+    }
+
+    /// Take all the audio samples generated since the last call. Samples are generated at
+    /// `apu::APU_SAMPLE_RATE`
+    pub fn take_audio_samples(&mut self) -> Vec<StereoSample> {
+        self.mmu.apu.take_samples()
     }
 
     pub fn keydown(&mut self, key: JoypadKeys) {
@@ -72,6 +153,56 @@ impl Gameboy {
         self.mmu.keyup(key);
     }
 
+    /// Snapshot the whole machine in a versioned binary format. The cartridge ROM is not included,
+    /// so the state can only be loaded back into a `Gameboy` running the same cartridge
+    pub fn save_state(&self) -> Result<Vec<u8>, SaveStateError> {
+        let header = SaveStateHeader::new(&self.mmu.cart.header);
+        let cart_state = self.mmu.cart.save_state()?;
+        savestate::encode(&(header, &self.cpu, &self.mmu, cart_state))
+    }
+
+    /// Restore a snapshot created by `save_state`. The machine is left untouched if this fails
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = state;
+        // The header is checked before anything else is decoded, since the layout of the rest of
+        // the state can change between versions
+        let header: SaveStateHeader = bincode::deserialize_from(&mut reader)?;
+        header.validate(&self.mmu.cart.header)?;
+
+        let (cpu, mut mmu, cart_state): (Cpu<Mmu>, Mmu, Vec<u8>) = savestate::decode(reader)?;
+        self.mmu.cart.load_state(&cart_state)?;
+        mmu.cart = std::mem::take(&mut self.mmu.cart);
+        mmu.boot_rom = std::mem::take(&mut self.mmu.boot_rom);
+        mmu.ppu.set_renderer(self.mmu.ppu.renderer());
+        mmu.serial.set_peer(self.mmu.serial.take_peer());
+
+        self.cpu = cpu;
+        self.mmu = mmu;
+        Ok(())
+    }
+
+    /// Choose how the real-time clock of the cartridge advances. Ignored for cartridges without
+    /// one
+    pub fn set_rtc_mode(&mut self, mode: RtcMode) {
+        self.mmu.cart.set_rtc_mode(mode);
+    }
+
+    /// Connect something to the link cable. Nothing is connected by default
+    pub fn set_link_peer(&mut self, peer: Box<dyn LinkPeer>) {
+        self.mmu.serial.set_peer(peer);
+    }
+
+    /// Image seen by the sensor of Game Boy Camera cartridges. Ignored for other cartridges
+    pub fn set_camera_image(&mut self, image: SensorImage) {
+        self.mmu.cart.set_camera_image(image);
+    }
+
+    /// Tilt of the Game Boy seen by the accelerometer of MBC7 cartridges, in g. Positive X is
+    /// tilted right and positive Y is tilted towards the player. Ignored for other cartridges
+    pub fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.mmu.cart.set_accelerometer(x, y);
+    }
+
     pub fn save(&self, path: &PathBuf) -> io::Result<String> {
         if let Some(ram) = self.mmu.save_ram() {
             fs::write(path, ram).map(|_| "Save RAM to file".into())
@@ -80,3 +211,348 @@ impl Gameboy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::CGB_BOOT_ROM_SIZE;
+    use crate::framebuffer;
+    use crate::palettes::{DMG_COMPAT_BG_PALETTE, DMG_COMPAT_OBJ_PALETTE};
+    use crate::ppu::{BCPD, BCPS, OCPD, OCPS};
+    use crate::ppu::{LCD_HEIGHT, LCD_WIDTH};
+    use crate::savestate::{SAVE_STATE_MAGIC, SAVE_STATE_VERSION};
+    use crate::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+
+    const NINTENDO_LOGO: [u8; 48] = [
+        0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00,
+        0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD,
+        0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB,
+        0xB9, 0x33, 0x3E,
+    ];
+
+    /// MBC1 + RAM + battery cartridge running a program that keeps mixing TIMA into cartridge RAM
+    pub(crate) fn test_rom(title: &str) -> Vec<u8> {
+        let mut rom = vec![0x00; 0x8000];
+        // nop; jp 0x0150
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x134..0x134 + title.len()].copy_from_slice(title.as_bytes());
+        rom[0x143] = 0x80; // DMG and CGB
+        rom[0x147] = 0x03; // MBC1 + RAM + Battery
+        rom[0x148] = 0x00; // 32KB
+        rom[0x149] = 0x02; // 8KB
+
+        let mut checksum: u8 = 0;
+        for byte in &rom[0x134..=0x14C] {
+            checksum = checksum.wrapping_sub(*byte).wrapping_sub(1);
+        }
+        rom[0x14D] = checksum;
+
+        #[rustfmt::skip]
+        let program = [
+            0x3E, 0x0A,       // ld a, 0x0A
+            0xEA, 0x00, 0x00, // ld (0x0000), a  ; Enable cartridge RAM
+            0x3E, 0x05,       // ld a, 0x05
+            0xE0, 0x07,       // ldh (TAC), a    ; Enable timer
+            0x21, 0x00, 0xA0, // ld hl, 0xA000
+            0xF0, 0x05,       // ldh a, (TIMA)   ; loop:
+            0x86,             // add a, (hl)
+            0x22,             // ld (hl+), a
+            0x7C,             // ld a, h
+            0xE6, 0x1F,       // and 0x1F
+            0xF6, 0xA0,       // or 0xA0         ; Keep HL within cartridge RAM
+            0x67,             // ld h, a
+            0x18, 0xF4,       // jr loop
+        ];
+        rom[0x150..0x150 + program.len()].copy_from_slice(&program);
+
+        rom
+    }
+
+    pub(crate) fn run_frames(gameboy: &mut Gameboy, frames: usize) -> Vec<StereoSample> {
+        let mut samples = Vec::new();
+        for _ in 0..frames {
+            gameboy.run_one_frame();
+            samples.extend(gameboy.take_audio_samples());
+        }
+        samples
+    }
+
+    #[test]
+    fn test_skipping_boot_rom_starts_at_cartridge() {
+        let (gameboy, _) = Gameboy::new(test_rom("NOBOOT"), None, GameboyOptions::default());
+        let registers = gameboy.load_cpu_debug().registers;
+        assert_eq!(registers.pc, 0x0100);
+        assert_eq!(registers.sp, 0xFFFE);
+        assert_eq!(registers.get_af(), 0x1180);
+        assert_eq!(registers.get_de(), 0xFF56);
+        assert!(!gameboy.mmu.system_state.bootrom_mapped);
+
+        // DMG games get the colors the boot ROM would have picked for them
+        let mut rom = test_rom("NOBOOT");
+        rom[0x143] = 0x00;
+        let (mut gameboy, _) = Gameboy::new(rom, None, GameboyOptions::default());
+        assert_eq!(gameboy.load_cpu_debug().registers.get_hl(), 0x007C);
+        gameboy.mmu.unticked_write(BCPS, 0x00);
+        assert_eq!(gameboy.mmu.unticked_read(BCPD), DMG_COMPAT_BG_PALETTE[0]);
+        gameboy.mmu.unticked_write(OCPS, 0x0A);
+        assert_eq!(gameboy.mmu.unticked_read(OCPD), DMG_COMPAT_OBJ_PALETTE[2]);
+    }
+
+    #[test]
+    fn test_skipping_boot_rom_picks_colors_of_nintendo_games() {
+        // BG palette and both OBJ palettes of a DMG game published by Nintendo
+        let palettes = |title| {
+            let mut rom = test_rom(title);
+            rom[0x143] = 0x00;
+            rom[0x14B] = 0x01; // Nintendo
+            let (mut gameboy, _) = Gameboy::new(rom, None, GameboyOptions::default());
+            let mut read = |index, data, count| {
+                (0..count)
+                    .map(|i| {
+                        gameboy.mmu.unticked_write(index, i);
+                        gameboy.mmu.unticked_read(data)
+                    })
+                    .collect::<Vec<_>>()
+            };
+            (read(BCPS, BCPD, 8), read(OCPS, OCPD, 16))
+        };
+
+        // Title checksum 0xDB picks combination 0x03, the same palette everywhere
+        let palette = [0xFF, 0x7F, 0xFF, 0x03, 0x1F, 0x00, 0x00, 0x00];
+        let (bg, obj) = palettes("TETRIS");
+        assert_eq!(bg, palette);
+        assert_eq!(obj, [palette, palette].concat());
+
+        // Title checksum 0xD1 picks combination 0x22, whose OBJ0 palette starts in the middle of
+        // one palette of the boot ROM
+        let (bg, obj) = palettes("TETRII");
+        assert_eq!(bg, [0xED, 0x03, 0xFF, 0x7F, 0x5F, 0x25, 0x00, 0x00]);
+        assert_eq!(
+            obj,
+            [
+                0xFF, 0x7F, 0xFF, 0x7F, 0x8C, 0x7E, 0x00, 0x7C, // OBJ0
+                0xFF, 0x7F, 0xBF, 0x32, 0xD0, 0x00, 0x00, 0x00, // OBJ1
+            ]
+        );
+    }
+
+    #[test]
+    fn test_boot_rom_is_mapped_until_disabled() {
+        let mut boot_rom = vec![0x00; CGB_BOOT_ROM_SIZE];
+        #[rustfmt::skip]
+        let program = [
+            0x3E, 0x11, // ld a, 0x11
+            0xE0, 0x50, // ldh (0xFF50), a ; Disable the boot ROM
+        ];
+        boot_rom[..program.len()].copy_from_slice(&program);
+        boot_rom[0x200] = 0xAB;
+
+        let options = GameboyOptions {
+            boot_rom: Some(boot_rom),
+            ..Default::default()
+        };
+        let (mut gameboy, _) = Gameboy::new(test_rom("BOOT"), None, options);
+        assert_eq!(gameboy.load_cpu_debug().registers.pc, 0x0000);
+        assert_eq!(gameboy.mmu.unticked_read(0x0000), 0x3E);
+        assert_eq!(gameboy.mmu.unticked_read(0x0200), 0xAB);
+        // The cartridge header shows through the boot ROM
+        assert_eq!(gameboy.mmu.unticked_read(0x0101), 0xC3);
+
+        // Save states don't include the boot ROM, but keep it mapped
+        let state = gameboy.save_state().unwrap();
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.mmu.unticked_read(0x0200), 0xAB);
+
+        gameboy.run_one_frame();
+        assert!(!gameboy.mmu.system_state.bootrom_mapped);
+        assert_eq!(gameboy.mmu.unticked_read(0x0000), 0x00);
+        assert_eq!(gameboy.mmu.unticked_read(0x0200), 0x00);
+
+        // A boot ROM of the wrong size is skipped
+        let options = GameboyOptions {
+            boot_rom: Some(vec![0x00; 0x100]),
+            ..Default::default()
+        };
+        let (gameboy, _) = Gameboy::new(test_rom("BOOT"), None, options);
+        assert_eq!(gameboy.load_cpu_debug().registers.pc, 0x0100);
+    }
+
+    #[test]
+    fn test_hardware_models_start_with_their_boot_rom_registers() {
+        let model_options = |model| GameboyOptions {
+            model,
+            ..Default::default()
+        };
+
+        let (gameboy, _) = Gameboy::new(test_rom("MODEL"), None, model_options(HardwareModel::Dmg));
+        let registers = gameboy.load_cpu_debug().registers;
+        assert_eq!(registers.get_af(), 0x01B0);
+        assert_eq!(registers.get_bc(), 0x0013);
+        assert_eq!(registers.get_de(), 0x00D8);
+        assert_eq!(registers.get_hl(), 0x014D);
+
+        let (gameboy, _) = Gameboy::new(test_rom("MODEL"), None, model_options(HardwareModel::Mgb));
+        assert_eq!(gameboy.load_cpu_debug().registers.get_af(), 0xFFB0);
+
+        let (gameboy, _) = Gameboy::new(test_rom("MODEL"), None, model_options(HardwareModel::Sgb));
+        let registers = gameboy.load_cpu_debug().registers;
+        assert_eq!(registers.get_af(), 0x0100);
+        assert_eq!(registers.get_hl(), 0xC060);
+
+        let (gameboy, _) = Gameboy::new(test_rom("MODEL"), None, model_options(HardwareModel::Agb));
+        let registers = gameboy.load_cpu_debug().registers;
+        assert_eq!(registers.get_af(), 0x1100);
+        assert_eq!(registers.get_bc(), 0x0100);
+
+        // DMG boot ROMs are 256 bytes
+        let options = GameboyOptions {
+            model: HardwareModel::Dmg,
+            boot_rom: Some(vec![0x00; 0x100]),
+            ..Default::default()
+        };
+        let (gameboy, _) = Gameboy::new(test_rom("MODEL"), None, options);
+        assert_eq!(gameboy.load_cpu_debug().registers.pc, 0x0000);
+    }
+
+    #[test]
+    fn test_sgb_frame_includes_the_border() {
+        let (frame_reader, mut frame_writer) = framebuffer::buffers::triple::new::<Texture>();
+        let (gameboy, _) = Gameboy::new(test_rom("FRAME"), None, GameboyOptions::default());
+        gameboy.write_frame(&mut frame_writer);
+        let frame = frame_reader.get().read();
+        assert_eq!((frame.width(), frame.height()), (LCD_WIDTH, LCD_HEIGHT));
+
+        let options = GameboyOptions {
+            model: HardwareModel::Sgb,
+            ..Default::default()
+        };
+        let (gameboy, _) = Gameboy::new(test_rom("FRAME"), None, options);
+        gameboy.write_frame(&mut frame_writer);
+        let frame = frame_reader.get().read();
+        assert_eq!(
+            (frame.width(), frame.height()),
+            (SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT)
+        );
+    }
+
+    #[test]
+    fn test_dmg_has_no_cgb_registers() {
+        let options = GameboyOptions {
+            model: HardwareModel::Dmg,
+            ..Default::default()
+        };
+        let (mut gameboy, _) = Gameboy::new(test_rom("DMG"), None, options);
+
+        for address in [
+            0xFF4D, 0xFF4F, 0xFF51, 0xFF55, BCPS, BCPD, OCPS, OCPD, 0xFF70,
+        ] {
+            gameboy.mmu.unticked_write(address, 0x01);
+            assert_eq!(gameboy.mmu.unticked_read(address), 0xFF, "{address:#06X}");
+        }
+
+        // Banking registers are ignored
+        gameboy.mmu.unticked_write(0x8000, 0xAB);
+        gameboy.mmu.unticked_write(0xD000, 0xCD);
+        gameboy.mmu.unticked_write(0xFF4F, 0x00);
+        gameboy.mmu.unticked_write(0xFF70, 0x02);
+        assert_eq!(gameboy.mmu.unticked_read(0x8000), 0xAB);
+        assert_eq!(gameboy.mmu.unticked_read(0xD000), 0xCD);
+    }
+
+    #[test]
+    fn test_stop_mode_is_left_with_the_joypad() {
+        let mut rom = test_rom("STOP");
+        #[rustfmt::skip]
+        let program = [
+            0x3E, 0x20, // ld a, 0x20
+            0xE0, 0x00, // ldh (P1), a ; Select the D-pad
+            0x10, 0x00, // stop
+            0x04,       // inc b       ; loop:
+            0x18, 0xFD, // jr loop
+        ];
+        rom[0x150..0x150 + program.len()].copy_from_slice(&program);
+        let (mut gameboy, _) = Gameboy::new(rom, None, GameboyOptions::default());
+
+        // Nothing but the joypad runs in STOP mode
+        run_frames(&mut gameboy, 1);
+        assert_eq!(
+            gameboy.mmu.system_state.execution_state,
+            ExecutionState::Stopped
+        );
+        let ly = gameboy.mmu.unticked_read(0xFF44);
+        run_frames(&mut gameboy, 1);
+        assert_eq!(gameboy.mmu.unticked_read(0xFF44), ly);
+        assert_eq!(gameboy.mmu.unticked_read(0xFF04), 0x00);
+        let bc = gameboy.load_cpu_debug().registers.get_bc();
+
+        gameboy.keydown(JoypadKeys::Right);
+        run_frames(&mut gameboy, 2);
+        assert_eq!(
+            gameboy.mmu.system_state.execution_state,
+            ExecutionState::ExecutingProgram
+        );
+        assert_ne!(gameboy.load_cpu_debug().registers.get_bc(), bc);
+    }
+
+    #[test]
+    fn test_save_state_round_trip_is_bit_identical() {
+        let (mut gameboy, _) = Gameboy::new(test_rom("SAVESTATE"), None, GameboyOptions::default());
+        run_frames(&mut gameboy, 30);
+
+        let state = gameboy.save_state().unwrap();
+        let expected_samples = run_frames(&mut gameboy, 120);
+        let expected_registers = gameboy.load_cpu_debug().registers;
+        let expected_state = gameboy.save_state().unwrap();
+
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.save_state().unwrap(), state);
+
+        let samples = run_frames(&mut gameboy, 120);
+        assert_eq!(gameboy.load_cpu_debug().registers, expected_registers);
+        assert_eq!(gameboy.save_state().unwrap(), expected_state);
+        assert_eq!(samples, expected_samples);
+        assert_eq!(gameboy.mmu.save_ram(), {
+            let (mut other, _) =
+                Gameboy::new(test_rom("SAVESTATE"), None, GameboyOptions::default());
+            other.load_state(&expected_state).unwrap();
+            other.mmu.save_ram()
+        });
+    }
+
+    #[test]
+    fn test_save_state_header() {
+        let (mut gameboy, _) = Gameboy::new(test_rom("SAVESTATE"), None, GameboyOptions::default());
+        let state = gameboy.save_state().unwrap();
+        assert_eq!(state[0..4], SAVE_STATE_MAGIC);
+        assert_eq!(state[4..8], SAVE_STATE_VERSION.to_le_bytes());
+
+        let mut wrong_magic = state.clone();
+        wrong_magic[0] = b'X';
+        assert!(matches!(
+            gameboy.load_state(&wrong_magic),
+            Err(SaveStateError::InvalidMagic)
+        ));
+
+        let mut wrong_version = state.clone();
+        wrong_version[4..8].copy_from_slice(&(SAVE_STATE_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            gameboy.load_state(&wrong_version),
+            Err(SaveStateError::UnsupportedVersion(_))
+        ));
+
+        assert!(gameboy.load_state(&state[..state.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_save_state_rejects_other_cartridges() {
+        let (gameboy, _) = Gameboy::new(test_rom("SAVESTATE"), None, GameboyOptions::default());
+        let (mut other, _) = Gameboy::new(test_rom("OTHER"), None, GameboyOptions::default());
+
+        let state = gameboy.save_state().unwrap();
+        assert!(matches!(
+            other.load_state(&state),
+            Err(SaveStateError::CartridgeMismatch(_))
+        ));
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::VecDeque;
+
+use crate::gameboy::Gameboy;
+use crate::savestate::SaveStateError;
+
+/// Number of frames between two snapshots. Stepping back one frame re-runs at most this many
+/// frames minus one
+pub const DEFAULT_SNAPSHOT_INTERVAL: usize = 10;
+/// Memory used by the rewind history before the oldest snapshots are dropped
+pub const DEFAULT_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+/// A snapshot stored as the difference to the snapshot taken after it
+struct Delta {
+    /// Length of the uncompressed save state
+    len: usize,
+    /// XOR against the next snapshot, with runs of zeros run-length encoded
+    data: Vec<u8>,
+}
+
+impl Delta {
+    fn encode(next: &[u8], state: &[u8]) -> Self {
+        let xor: Vec<u8> = state
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ next.get(i).copied().unwrap_or(0))
+            .collect();
+
+        // Alternating runs of zeros and literal bytes, each prefixed with its length
+        let mut data = Vec::new();
+        let mut i = 0;
+        while i < xor.len() {
+            let zeros = xor[i..]
+                .iter()
+                .take(u16::MAX as usize)
+                .take_while(|&&byte| byte == 0)
+                .count();
+            i += zeros;
+
+            let literals = xor[i..]
+                .iter()
+                .take(u16::MAX as usize)
+                .take_while(|&&byte| byte != 0)
+                .count();
+
+            data.extend_from_slice(&(zeros as u16).to_le_bytes());
+            data.extend_from_slice(&(literals as u16).to_le_bytes());
+            data.extend_from_slice(&xor[i..i + literals]);
+            i += literals;
+        }
+
+        Self {
+            len: state.len(),
+            data,
+        }
+    }
+
+    fn decode(&self, next: &[u8]) -> Vec<u8> {
+        let mut xor = Vec::with_capacity(self.len);
+        let mut data = self.data.as_slice();
+        while let [z0, z1, l0, l1, rest @ ..] = data {
+            let zeros = u16::from_le_bytes([*z0, *z1]) as usize;
+            let literals = u16::from_le_bytes([*l0, *l1]) as usize;
+            xor.resize(xor.len() + zeros, 0);
+            xor.extend_from_slice(&rest[..literals]);
+            data = &rest[literals..];
+        }
+
+        xor.iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ next.get(i).copied().unwrap_or(0))
+            .collect()
+    }
+}
+
+/// A point in the rewind history and the inputs of the frames run after it
+struct Snapshot {
+    delta: Option<Delta>,
+    /// Joypad keys at the start of each frame run after the snapshot was taken
+    inputs: Vec<u8>,
+}
+
+impl Snapshot {
+    fn size(&self) -> usize {
+        self.delta.as_ref().map_or(0, |delta| delta.data.len()) + self.inputs.len()
+    }
+}
+
+/// History of the machine used to step back frame by frame.
+///
+/// A snapshot of the machine is taken every `snapshot_interval` frames and the joypad keys are
+/// recorded for every frame. Only the newest snapshot is kept in full, older snapshots are stored
+/// as the compressed difference to the snapshot after them. Stepping back restores the newest
+/// snapshot and re-runs the recorded frames up to the one before the current frame
+pub struct Rewind {
+    snapshot_interval: usize,
+    memory_budget: usize,
+
+    /// Oldest snapshot first. The newest snapshot has no delta, its state is `newest_state`
+    snapshots: VecDeque<Snapshot>,
+    newest_state: Vec<u8>,
+    /// Memory used by the deltas and inputs of `snapshots`
+    history_size: usize,
+}
+
+impl Rewind {
+    pub fn new(snapshot_interval: usize, memory_budget: usize) -> Self {
+        Self {
+            snapshot_interval: snapshot_interval.max(1),
+            memory_budget,
+            snapshots: VecDeque::new(),
+            newest_state: Vec::new(),
+            history_size: 0,
+        }
+    }
+
+    pub fn set_memory_budget(&mut self, memory_budget: usize) {
+        self.memory_budget = memory_budget;
+        self.enforce_memory_budget();
+    }
+
+    /// Memory currently used by the history, in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.history_size + self.newest_state.len()
+    }
+
+    /// Number of frames that can be stepped back
+    pub fn frames(&self) -> usize {
+        self.snapshots.iter().map(|snapshot| snapshot.inputs.len()).sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.newest_state.clear();
+        self.history_size = 0;
+    }
+
+    /// Record the state of the machine. Must be called right before every frame that is run
+    pub fn record_frame(&mut self, gameboy: &Gameboy) -> Result<(), SaveStateError> {
+        let snapshot_due = match self.snapshots.back() {
+            Some(newest) => newest.inputs.len() >= self.snapshot_interval,
+            None => true,
+        };
+
+        if snapshot_due {
+            let state = gameboy.save_state()?;
+            if let Some(previous) = self.snapshots.back_mut() {
+                let delta = Delta::encode(&state, &self.newest_state);
+                self.history_size += delta.data.len();
+                previous.delta = Some(delta);
+            }
+
+            self.newest_state = state;
+            self.snapshots.push_back(Snapshot {
+                delta: None,
+                inputs: Vec::with_capacity(self.snapshot_interval),
+            });
+        }
+
+        // Just pushed a snapshot if there was none
+        let newest = self.snapshots.back_mut().unwrap();
+        newest.inputs.push(gameboy.mmu.joypad_keys());
+        self.history_size += 1;
+
+        self.enforce_memory_budget();
+        Ok(())
+    }
+
+    /// Take the machine back to the state it was in one frame ago. Returns `false` without
+    /// touching the machine once the start of the history is reached
+    pub fn step_back(&mut self, gameboy: &mut Gameboy) -> Result<bool, SaveStateError> {
+        // Frames run since the newest snapshot
+        let frames = match self.snapshots.back() {
+            Some(newest) => newest.inputs.len(),
+            None => return Ok(false),
+        };
+
+        if frames == 0 {
+            // The machine is at the newest snapshot, continue from the one before it
+            if self.snapshots.len() < 2 {
+                return Ok(false);
+            }
+
+            self.snapshots.pop_back();
+            let previous = self.snapshots.back_mut().unwrap();
+            let delta = previous.delta.take().unwrap();
+            self.history_size -= delta.data.len();
+            self.newest_state = delta.decode(&self.newest_state);
+        }
+
+        gameboy.load_state(&self.newest_state)?;
+
+        let newest = self.snapshots.back_mut().unwrap();
+        // Keys held when the frame we are stepping back to was recorded
+        let keys = newest.inputs.pop().unwrap();
+        self.history_size -= 1;
+        for &keys in &newest.inputs {
+            gameboy.mmu.set_joypad_keys(keys);
+            gameboy.run_one_frame();
+        }
+        gameboy.mmu.set_joypad_keys(keys);
+        // Audio is not played while rewinding
+        gameboy.take_audio_samples();
+
+        Ok(true)
+    }
+
+    fn enforce_memory_budget(&mut self) {
+        // The newest snapshot is always kept
+        while self.memory_usage() > self.memory_budget && self.snapshots.len() > 1 {
+            let oldest = self.snapshots.pop_front().unwrap();
+            self.history_size -= oldest.size();
+        }
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self::new(DEFAULT_SNAPSHOT_INTERVAL, DEFAULT_MEMORY_BUDGET)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::tests::test_rom;
+    use crate::gameboy::GameboyOptions;
+    use crate::joypad::JoypadKeys;
+
+    fn press_keys_for_frame(gameboy: &mut Gameboy, frame: usize) {
+        match frame {
+            3 | 14 => gameboy.keydown(JoypadKeys::A),
+            6 => {
+                gameboy.keyup(JoypadKeys::A);
+                gameboy.keydown(JoypadKeys::Start);
+            }
+            9 | 21 => gameboy.keyup(JoypadKeys::Start),
+            17 => gameboy.keydown(JoypadKeys::Start),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let next = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let states = [
+            vec![1, 2, 3, 4, 5, 6, 7, 8],
+            vec![1, 2, 0, 4, 5, 9, 9, 8],
+            vec![1, 2, 3],
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+            vec![0; 0x20000],
+        ];
+
+        for state in states {
+            let delta = Delta::encode(&next, &state);
+            assert_eq!(delta.decode(&next), state);
+        }
+    }
+
+    #[test]
+    fn test_step_back_restores_every_frame() {
+        let (mut gameboy, _) = Gameboy::new(test_rom("REWIND"), None, GameboyOptions::default());
+        let mut rewind = Rewind::new(4, usize::MAX);
+
+        let mut states = Vec::new();
+        for frame in 0..25 {
+            press_keys_for_frame(&mut gameboy, frame);
+            states.push(gameboy.save_state().unwrap());
+            rewind.record_frame(&gameboy).unwrap();
+            gameboy.run_one_frame();
+        }
+        assert_eq!(rewind.frames(), 25);
+
+        while let Some(expected) = states.pop() {
+            assert!(rewind.step_back(&mut gameboy).unwrap());
+            assert_eq!(gameboy.save_state().unwrap(), expected);
+        }
+        assert!(!rewind.step_back(&mut gameboy).unwrap());
+        assert_eq!(rewind.frames(), 0);
+    }
+
+    #[test]
+    fn test_recording_after_step_back() {
+        let (mut gameboy, _) = Gameboy::new(test_rom("REWIND"), None, GameboyOptions::default());
+        let mut rewind = Rewind::new(3, usize::MAX);
+
+        for frame in 0..10 {
+            press_keys_for_frame(&mut gameboy, frame);
+            rewind.record_frame(&gameboy).unwrap();
+            gameboy.run_one_frame();
+        }
+        for _ in 0..5 {
+            rewind.step_back(&mut gameboy).unwrap();
+        }
+
+        let expected = gameboy.save_state().unwrap();
+        for _ in 0..4 {
+            rewind.record_frame(&gameboy).unwrap();
+            gameboy.run_one_frame();
+        }
+        for _ in 0..4 {
+            rewind.step_back(&mut gameboy).unwrap();
+        }
+        assert_eq!(gameboy.save_state().unwrap(), expected);
+        assert_eq!(rewind.frames(), 5);
+    }
+
+    #[test]
+    fn test_memory_budget_drops_oldest_snapshots() {
+        let (mut gameboy, _) = Gameboy::new(test_rom("REWIND"), None, GameboyOptions::default());
+        let state_size = gameboy.save_state().unwrap().len();
+        let budget = state_size + 256;
+        let mut rewind = Rewind::new(2, budget);
+
+        for _ in 0..100 {
+            rewind.record_frame(&gameboy).unwrap();
+            gameboy.run_one_frame();
+            assert!(rewind.memory_usage() <= budget);
+        }
+        assert!(rewind.frames() > 0 && rewind.frames() < 100);
+
+        rewind.set_memory_budget(0);
+        assert_eq!(rewind.snapshots.len(), 1);
+        assert_eq!(rewind.memory_usage(), state_size + rewind.frames());
+    }
+}
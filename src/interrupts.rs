@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 use crate::memory::Memory;
 
 pub(crate) const INTERRUPT_FLAG_ADDRESS: u16 = 0xFF0F;
@@ -43,7 +45,7 @@ pub(crate) enum InterruptVector {
     Joypad = 0x60,
 }
 
-#[derive(Debug, Copy, Clone, Default)]
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
 pub(crate) struct InterruptHandler {
     interrupt_enable: u8,
     interrupt_flag: u8,
@@ -65,6 +67,11 @@ impl InterruptHandler {
     pub fn reset_interrupt_request(&mut self, interrupt: InterruptType) {
         self.interrupt_flag &= !(interrupt as u8);
     }
+
+    #[cfg(test)]
+    pub fn is_requested(&self, interrupt: InterruptType) -> bool {
+        self.interrupt_flag & interrupt as u8 != 0
+    }
 }
 
 impl Memory for InterruptHandler {
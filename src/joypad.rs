@@ -1,8 +1,15 @@
+use serde::{Deserialize, Serialize};
+
 use crate::interrupts::{InterruptHandler, InterruptType};
 use crate::memory::Memory;
 
 pub(crate) const JOYP_ADDRESS: u16 = 0xFF00;
-pub(crate) const JOYPAD_POLL_CYCLES: u64 = 65536; // 64Hz
+
+/// Size of a SGB command packet. Commands are made of 1 to 7 packets
+pub(crate) const SGB_PACKET_SIZE: usize = 16;
+const SGB_PACKET_BITS: usize = SGB_PACKET_SIZE * 8;
+/// Command requesting multiple controllers
+const SGB_MLT_REQ: u8 = 0x11;
 
 #[derive(Debug, Copy, Clone)]
 pub enum JoypadKeys {
@@ -16,20 +23,89 @@ pub enum JoypadKeys {
     Start = 1 << 7,
 }
 
+/// Receives the command packets SGB games send through P14 and P15. A packet starts with both
+/// lines pulled low, followed by 128 bits, least significant bit first, where P14 low is a 0 and
+/// P15 low is a 1. Both lines go high after every pulse. A 0 stop bit ends the packet
+#[derive(Default, Serialize, Deserialize)]
+struct SgbPacketReceiver {
+    /// Set by the start pulse until the stop bit
+    receiving: bool,
+    bits: usize,
+    packet: [u8; SGB_PACKET_SIZE],
+    /// Packets of the command received so far
+    command: Vec<u8>,
+}
+
+impl SgbPacketReceiver {
+    /// Follow a change of the P14 and P15 lines. Returns a command once all its packets arrived
+    fn on_lines_changed(&mut self, lines: u8) -> Option<Vec<u8>> {
+        match lines {
+            0x00 => {
+                self.receiving = true;
+                self.bits = 0;
+                self.packet = [0x00; SGB_PACKET_SIZE];
+            }
+            0x10 | 0x20 if self.receiving => {
+                let bit = lines == 0x10;
+                if self.bits < SGB_PACKET_BITS {
+                    self.packet[self.bits / 8] |= u8::from(bit) << (self.bits % 8);
+                    self.bits += 1;
+                } else {
+                    self.receiving = false;
+                    if bit {
+                        log::warn!("SGB packet without a stop bit. Dropping the command");
+                        self.command.clear();
+                    } else {
+                        return self.on_packet_received();
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    fn on_packet_received(&mut self) -> Option<Vec<u8>> {
+        self.command.extend_from_slice(&self.packet);
+        let packets = usize::from(self.command[0] & 0x07).max(1);
+        (self.command.len() >= packets * SGB_PACKET_SIZE).then(|| std::mem::take(&mut self.command))
+    }
+}
+
+/// The joypad side of the Super Game Boy: command packets and up to 4 controllers
+#[derive(Default, Serialize, Deserialize)]
+struct SgbJoypad {
+    receiver: SgbPacketReceiver,
+    /// Commands received and not yet handled by the `Sgb`
+    commands: Vec<Vec<u8>>,
+    /// Number of controllers requested with MLT_REQ
+    players: u8,
+    /// Controller being read. Only the first one has keys pressed
+    player: u8,
+}
+
+#[derive(Serialize, Deserialize)]
 pub(crate) struct Joypad {
     keys: u8,
-    joyp: u8,
-    cycles: u64,
-    queue_interrupt: bool,
+    /// P14 and P15 as written to JOYP. A row of keys is selected while its bit is cleared
+    select: u8,
+    /// P10 to P13 on the last tick. The interrupt is requested when one of them goes low
+    lines: u8,
+    sgb: Option<SgbJoypad>,
 }
 
 impl Joypad {
-    pub fn new() -> Self {
+    /// With `sgb` set, command packets are received and multiple controllers can be requested
+    pub fn new(sgb: bool) -> Self {
         Joypad {
             keys: 0xFF,
-            joyp: 0xFF,
-            cycles: 0,
-            queue_interrupt: false,
+            select: 0x30,
+            lines: 0x0F,
+            sgb: sgb.then(|| SgbJoypad {
+                players: 1,
+                ..Default::default()
+            }),
         }
     }
 
@@ -41,43 +117,85 @@ impl Joypad {
         self.keys |= key as u8;
     }
 
-    pub(crate) fn tick(&mut self, interrupts: &mut InterruptHandler) {
-        self.cycles += 4;
+    /// State of all the keys, one bit per `JoypadKeys`. A cleared bit means the key is down
+    pub(crate) fn keys(&self) -> u8 {
+        self.keys
+    }
 
-        if self.queue_interrupt {
-            interrupts.request_interrupt(InterruptType::Joypad);
-            self.queue_interrupt = false;
-        }
+    pub(crate) fn set_keys(&mut self, keys: u8) {
+        self.keys = keys;
+    }
 
-        // The Joypad polls for input every 64Hz
-        if self.cycles >= JOYPAD_POLL_CYCLES {
-            self.cycles %= JOYPAD_POLL_CYCLES;
-            self.update();
-        }
+    /// Take the SGB commands received since the last call
+    pub(crate) fn take_sgb_commands(&mut self) -> Vec<Vec<u8>> {
+        self.sgb
+            .as_mut()
+            .map(|sgb| std::mem::take(&mut sgb.commands))
+            .unwrap_or_default()
     }
 
-    fn update(&mut self) {
-        let mut current = self.joyp & 0xF0;
+    fn on_sgb_lines_written(&mut self, lines: u8) {
+        let old_lines = self.select;
+        let Some(sgb) = self.sgb.as_mut() else {
+            return;
+        };
 
-        match current & 0x30 {
-            0x10 => current |= (self.keys >> 4) & 0x0F,
-            0x20 => current |= self.keys & 0x0F,
-            0x30 => current |= 0x0F,
-            _ => {}
+        // The next controller is selected when P15 goes back high
+        if lines & 0x20 != 0 && old_lines & 0x20 == 0 {
+            sgb.player = (sgb.player + 1) % sgb.players;
+        }
+
+        if lines == old_lines {
+            return;
+        }
+
+        if let Some(command) = sgb.receiver.on_lines_changed(lines) {
+            if command[0] >> 3 == SGB_MLT_REQ {
+                sgb.players = match command[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                sgb.player = 0;
+                log::info!("SGB requested {} controllers", sgb.players);
+            }
+            sgb.commands.push(command);
         }
+    }
 
-        if (self.joyp & !current & 0x0F) != 0 {
-            self.queue_interrupt = true;
+    pub(crate) fn tick(&mut self, interrupts: &mut InterruptHandler) {
+        // Pressing a key or selecting a row with a key down pulls a line from high to low
+        let lines = self.lines();
+        if self.lines & !lines != 0 {
+            interrupts.request_interrupt(InterruptType::Joypad);
         }
+        self.lines = lines;
+    }
+
+    /// P10 to P13. A line is low while a key on it is down in one of the selected rows
+    fn lines(&self) -> u8 {
+        // Only the first SGB controller has keys pressed. With both lines high, the SGB shows the
+        // controller being read, 0x0F being the first one
+        let (keys, player) = match self.sgb.as_ref() {
+            Some(sgb) if sgb.player != 0 => (0xFF, sgb.player),
+            Some(sgb) => (self.keys, sgb.player),
+            None => (self.keys, 0),
+        };
 
-        self.joyp = current;
+        match self.select {
+            0x00 => (keys >> 4) & keys & 0x0F,
+            0x10 => (keys >> 4) & 0x0F,
+            0x20 => keys & 0x0F,
+            _ => 0x0F - player,
+        }
     }
 }
 
 impl Memory for Joypad {
     fn read(&mut self, address: u16) -> u8 {
         if address == JOYP_ADDRESS {
-            return self.joyp;
+            // Bits 6 and 7 are unused
+            return 0xC0 | self.select | self.lines();
         }
 
         panic!("Invalid address {:#06X} for Joypad::Read", address);
@@ -85,11 +203,65 @@ impl Memory for Joypad {
 
     fn write(&mut self, address: u16, data: u8) {
         if address == JOYP_ADDRESS {
-            self.joyp = (self.joyp & 0xCF) | (data & 0x30);
-            self.update();
+            self.on_sgb_lines_written(data & 0x30);
+            self.select = data & 0x30;
             return;
         }
 
         panic!("Invalid address {:#06X} for Joypad::Write", address);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_joyp_reads_the_selected_rows() {
+        let mut joypad = Joypad::new(false);
+        joypad.keydown(JoypadKeys::A);
+        joypad.keydown(JoypadKeys::Down);
+        assert_eq!(joypad.read(JOYP_ADDRESS), 0xFF);
+
+        joypad.write(JOYP_ADDRESS, 0x10);
+        assert_eq!(joypad.read(JOYP_ADDRESS), 0xDE);
+        joypad.write(JOYP_ADDRESS, 0x20);
+        assert_eq!(joypad.read(JOYP_ADDRESS), 0xE7);
+
+        // Both rows selected at once
+        joypad.write(JOYP_ADDRESS, 0x00);
+        assert_eq!(joypad.read(JOYP_ADDRESS), 0xC6);
+
+        joypad.keyup(JoypadKeys::A);
+        assert_eq!(joypad.read(JOYP_ADDRESS), 0xC7);
+    }
+
+    #[test]
+    fn test_interrupt_on_high_to_low_transition() {
+        let mut interrupts = InterruptHandler::default();
+        let mut joypad = Joypad::new(false);
+        joypad.write(JOYP_ADDRESS, 0x20);
+        joypad.tick(&mut interrupts);
+
+        joypad.keydown(JoypadKeys::Left);
+        joypad.tick(&mut interrupts);
+        assert!(interrupts.is_requested(InterruptType::Joypad));
+
+        // Keeping the key down or releasing it does not request another one
+        let mut interrupts = InterruptHandler::default();
+        joypad.tick(&mut interrupts);
+        joypad.keyup(JoypadKeys::Left);
+        joypad.tick(&mut interrupts);
+        assert!(!interrupts.is_requested(InterruptType::Joypad));
+
+        // Neither does a key in a row that is not selected
+        joypad.keydown(JoypadKeys::Start);
+        joypad.tick(&mut interrupts);
+        assert!(!interrupts.is_requested(InterruptType::Joypad));
+
+        // Until the row is selected
+        joypad.write(JOYP_ADDRESS, 0x10);
+        joypad.tick(&mut interrupts);
+        assert!(interrupts.is_requested(InterruptType::Joypad));
+    }
+}
@@ -1,12 +1,13 @@
 #![allow(dead_code)] // Only for development
 
-use cartridge::CartridgeHeader;
-use ppu::{LCD_HEIGHT, LCD_WIDTH};
+use cartridge::{CartridgeHeader, CGB_BOOT_ROM_SIZE, DMG_BOOT_ROM_SIZE};
+use serde::{Deserialize, Serialize};
 
 use crate::debug::CpuDebug;
 use crate::textures::Texture;
 
-mod apu;
+pub mod apu;
+pub mod audio;
 pub mod cartridge;
 pub mod cpu;
 pub mod debug;
@@ -18,22 +19,32 @@ mod memory;
 mod mmu;
 mod palettes;
 pub mod ppu;
-mod serial;
+pub mod savestate;
+pub mod serial;
+pub mod sgb;
 pub mod textures;
 mod timer;
 
-pub type GameFrame = Texture<LCD_WIDTH, LCD_HEIGHT>;
+/// Image of the LCD, `LCD_WIDTH` x `LCD_HEIGHT`
+pub type GameFrame = Texture;
 
 pub enum EmulatorEvent {
     /// Raised on Vblank
     CompletedFrame,
+    /// Raised when the CPU executes an illegal opcode and stops
+    CpuLockedUp {
+        address: u16,
+        opcode: u8,
+    },
+    /// Raised when rewinding goes back to before the lock-up
+    CpuResumed,
 
     // UI debug data
     CpuRegisters(CpuDebug),
     CartridgeInfo(CartridgeHeader),
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Default)]
+#[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub enum HardwareSupport {
     #[default]
     CgbOnly,
@@ -41,19 +52,78 @@ pub enum HardwareSupport {
     DmgCompat,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// Game Boy model being emulated
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HardwareModel {
+    /// Original Game Boy
+    Dmg,
+    /// Game Boy Pocket
+    Mgb,
+    /// Super Game Boy, which runs Game Boy games on a SNES with colors and a border
+    Sgb,
+    /// Game Boy Color
+    #[default]
+    Cgb,
+    /// Game Boy Advance, which runs Game Boy games on CGB hardware
+    Agb,
+}
+
+impl HardwareModel {
+    pub const ALL: [HardwareModel; 5] = [
+        HardwareModel::Dmg,
+        HardwareModel::Mgb,
+        HardwareModel::Sgb,
+        HardwareModel::Cgb,
+        HardwareModel::Agb,
+    ];
+
+    /// Whether the model has the CGB hardware: color palettes, VRAM and WRAM banking, double
+    /// speed and VRAM DMA
+    pub fn is_cgb(self) -> bool {
+        matches!(self, HardwareModel::Cgb | HardwareModel::Agb)
+    }
+
+    /// Size of the boot ROM of the model
+    pub fn boot_rom_size(self) -> usize {
+        if self.is_cgb() {
+            CGB_BOOT_ROM_SIZE
+        } else {
+            DMG_BOOT_ROM_SIZE
+        }
+    }
+}
+
+impl std::fmt::Display for HardwareModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HardwareModel::Dmg => "Game Boy (DMG)",
+            HardwareModel::Mgb => "Game Boy Pocket (MGB)",
+            HardwareModel::Sgb => "Super Game Boy (SGB)",
+            HardwareModel::Cgb => "Game Boy Color (CGB)",
+            HardwareModel::Agb => "Game Boy Advance (AGB)",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
 enum ExecutionState {
     #[default]
     ExecutingProgram,
     PreparingSpeedSwitch,
     Halted,
+    /// STOP mode. The system clock is stopped until a selected button is pressed
+    Stopped,
+    /// Hit an illegal opcode. Only a reset gets the CPU going again
+    Locked,
 }
 
-#[derive(Default, Debug, Copy, Clone)]
+#[derive(Default, Debug, Copy, Clone, Serialize, Deserialize)]
 struct HdmaState {
     source_addr: u16, // Built from HDMA1, HDMA2
     dest_addr: u16,   // Built from HDMA3, HDMA4
     hdma_stat: u8,    // HDMA5 (Length, Mode, Start)
+    /// Blocks of 0x10 bytes the `Mmu` copies before the CPU runs again
+    pending_blocks: u8,
 }
 
 impl HdmaState {
@@ -61,6 +131,21 @@ impl HdmaState {
         (self.hdma_stat & 0x80) == 0x00
     }
 
+    /// Called by the PPU when it enters H-Blank. An active HDMA copies one block
+    pub(crate) fn on_hblank(&mut self) {
+        if self.is_hdma_active() {
+            self.pending_blocks = 1;
+        }
+    }
+
+    /// Count down the length of an active HDMA after a block was copied. HDMA5 reads 0xFF once
+    /// all the blocks are copied
+    pub(crate) fn on_block_copied(&mut self) {
+        if self.is_hdma_active() {
+            self.hdma_stat = self.hdma_stat.checked_sub(1).unwrap_or(0xFF);
+        }
+    }
+
     fn write_high(attrib: &mut u16, high: u8) {
         *attrib = (*attrib & 0x00FF) | ((high as u16) << 8);
     }
@@ -86,9 +171,10 @@ impl HdmaState {
     }
 }
 
-#[derive(Debug, Copy, Clone, Default)]
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
 struct SystemState {
     execution_state: ExecutionState,
+    model: HardwareModel,
     /// Hardware supported by current cartridge
     hardware_support: HardwareSupport,
 
@@ -111,8 +197,11 @@ impl SystemState {
         (((self.key1 & 0x80) >> 7) + 1).into()
     }
 
-    pub(crate) fn dmg_compat_mode(&self) -> bool {
-        self.hardware_support == HardwareSupport::DmgCompat && !self.bootrom_mapped
+    /// Whether the PPU runs with DMG features only. Always the case on DMG hardware. A CGB runs
+    /// DMG games in compatibility mode once its boot ROM is done
+    pub(crate) fn dmg_mode(&self) -> bool {
+        !self.model.is_cgb()
+            || (self.hardware_support == HardwareSupport::DmgCompat && !self.bootrom_mapped)
     }
 }
 
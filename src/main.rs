@@ -1,6 +1,5 @@
 use eframe::{self, egui};
 
-use gibi::GameFrame;
 use ui::GameboyApp;
 
 mod ui;
@@ -9,7 +8,7 @@ fn main() -> Result<(), eframe::Error> {
     env_logger::init();
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default().with_inner_size([1240.0, 760.0]),
-        // TODO: Separate frame rate from monitor
+        // Emulation is paced by the emulation thread, the UI only shows the latest frame
         vsync: true,
         ..Default::default()
     };
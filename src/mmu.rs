@@ -1,19 +1,24 @@
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+
 use crate::{
     apu::{Apu, SOUND_END, SOUND_START, WAVE_END, WAVE_START},
     cartridge::{
         Cartridge, BOOT_ROM_END, BOOT_ROM_START, CART_RAM_END, CART_RAM_START, CART_ROM_END,
-        CART_ROM_START, CGB_BOOT_ROM,
+        CART_ROM_START,
     },
     interrupts::{InterruptHandler, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS},
     joypad::{Joypad, JoypadKeys, JOYP_ADDRESS},
     memory::{Memory, SystemBus},
+    palettes::{dmg_compat_palettes, DMG_COMPAT_BG_PALETTE, DMG_COMPAT_OBJ_PALETTE},
     ppu::{
-        Ppu, OAM_DMA_CYCLES, OAM_END, OAM_START, PALETTE_END, PALETTE_START, VRAM_BANK_ADDRESS,
-        VRAM_END, VRAM_START,
+        Ppu, BCPD, BCPS, OAM_DMA_CYCLES, OAM_END, OAM_START, OCPD, OCPS, PALETTE_END,
+        PALETTE_START, VRAM_BANK_ADDRESS, VRAM_END, VRAM_START,
     },
     serial::{Serial, SERIAL_END, SERIAL_START},
+    sgb::Sgb,
     timer::{Timer, TIMER_END, TIMER_START},
-    ExecutionState, HardwareSupport, HdmaState, SystemState,
+    ExecutionState, HardwareModel, HardwareSupport, HdmaState, SystemState,
 };
 
 const WRAM_BANK_SIZE: usize = 1024 * 4; // 4KB
@@ -30,37 +35,118 @@ const HDMA4: u16 = 0xFF54;
 /// VRAM DMA Length/Mode/Start
 const HDMA5: u16 = 0xFF55;
 const VRAM_DMA_END: u16 = 0xFF55;
+/// Size of the blocks VRAM DMA copies
+const VRAM_DMA_BLOCK_SIZE: u16 = 0x10;
+/// M-cycles the CPU is stalled for each block copied in single speed. Double speed takes twice as
+/// many
+const VRAM_DMA_BLOCK_CYCLES: u64 = 8;
 
 const WRAM_BANK_SELECT: u16 = 0xFF70;
+/// Speed switch
+const KEY1: u16 = 0xFF4D;
 
 const HRAM_START: u16 = 0xFF80;
 const HRAM_END: u16 = 0xFFFE;
 
+/// IO registers as the boot ROM leaves them. Sound is powered on first so that the sound
+/// registers can be written. The boot ROM leaves channel 1 running silently after the startup
+/// sound, which is not restarted here
+const POST_BOOT_IO: [(u16, u8); 29] = [
+    (0xFF26, 0x80), // NR52
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    (0xFF12, 0xF3), // NR12
+    (0xFF13, 0xFF), // NR13
+    (0xFF14, 0x3F), // NR14
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF18, 0xFF), // NR23
+    (0xFF19, 0x3F), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1D, 0xFF), // NR33
+    (0xFF1E, 0x3F), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0x3F), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF00, 0xCF), // P1
+    (0xFF02, 0x7F), // SC
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF47, 0xFC), // BGP
+    (0xFF4F, 0x00), // VBK
+    (0xFF40, 0x91), // LCDC
+    (0xFFFF, 0x00), // IE
+];
+
+/// Write the registers of the CGB palette RAM starting at the first color of the first palette
+const BCPS_AUTO_INCREMENT: u8 = 0x80;
+
+#[derive(Serialize, Deserialize)]
 struct OamDma {
     pending_cycles: u64,
     next_address: u16,
+    /// Last byte copied, which the CPU reads when it uses the same bus as the DMA
+    value: u8,
+}
+
+/// Buses the CPU shares with the OAM DMA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bus {
+    /// Cartridge ROM and RAM, and WRAM on DMG hardware
+    External,
+    /// WRAM has its own bus on CGB hardware
+    Wram,
+    Vram,
+}
+
+impl Bus {
+    /// Bus the address is accessed through. OAM, IO registers and HRAM are on none of them
+    fn of(address: u16, model: HardwareModel) -> Option<Bus> {
+        match address {
+            VRAM_START..=VRAM_END => Some(Bus::Vram),
+            0xC000..=0xFDFF if model.is_cgb() => Some(Bus::Wram),
+            0x0000..=0xFDFF => Some(Bus::External),
+            _ => None,
+        }
+    }
 }
 
 /// The MMU (Memory-Management Unit) is responsible for connecting the CPU to
 /// the rest of the components. The `Mmu` implements the memory-map of the
 /// Game Boy, redirecting reads and writes made by the CPU and PPU to the
 /// correct component to which the address is mapped.
+#[derive(Serialize, Deserialize)]
 pub(crate) struct Mmu {
+    // The ROM is not part of the machine state. The banking state is saved separately by the
+    // `Gameboy`, see `Cartridge::save_state`
+    #[serde(skip)]
     pub(crate) cart: Cartridge,
     pub(crate) ppu: Ppu,
-    apu: Apu,
+    pub(crate) apu: Apu,
     joypad: Joypad,
     timer: Timer,
-    serial: Serial,
+    pub(crate) serial: Serial,
 
     pub(crate) interrupts: InterruptHandler,
     pub(crate) system_state: SystemState,
+    pub(crate) sgb: Option<Sgb>,
+
+    // The boot ROM is not part of the machine state either. Empty when starting without one
+    #[serde(skip)]
+    pub(crate) boot_rom: Box<[u8]>,
 
     // Only two banks are used in DMG mode
     // CGB mode uses all 8, with 0 being fixed, and 1-7 being switchable
-    wram: [u8; WRAM_BANK_SIZE * 8],
+    // Kept on the heap so restoring a save state does not copy it around the stack
+    wram: Box<[u8]>,
     wram_bank: usize,
 
+    #[serde(with = "BigArray")]
     hram: [u8; HRAM_SIZE],
 
     // DMAs
@@ -68,37 +154,42 @@ pub(crate) struct Mmu {
 }
 
 impl Mmu {
-    pub fn new(cart: Cartridge) -> Self {
-        let wram = [0x00; WRAM_BANK_SIZE * 8]; // 32KB
+    pub fn new(cart: Cartridge, model: HardwareModel, boot_rom: Option<Vec<u8>>) -> Self {
+        let wram = vec![0x00; WRAM_BANK_SIZE * 8].into_boxed_slice(); // 32KB
         let wram_bank = 0x1;
 
         let hram = [0x00; HRAM_SIZE];
-        let serial = Serial::new();
+        let serial = Serial::new(model.is_cgb());
 
         let interrupts = InterruptHandler::default();
 
         let timer = Timer::new();
         let ppu = Ppu::new();
         let apu = Apu::new();
-        let joypad = Joypad::new();
+        let sgb = (model == HardwareModel::Sgb).then(Sgb::new);
+        let joypad = Joypad::new(sgb.is_some() && cart.header.sgb_supported);
 
         let system_state = SystemState {
             execution_state: ExecutionState::ExecutingProgram,
+            model,
             hardware_support: cart.header.hardware_supported,
             carry_over_cycles: 0,
             total_cycles: 0,
             key1: 0x00,
-            bootrom_mapped: true,
+            bootrom_mapped: boot_rom.is_some(),
             hdma_state: HdmaState {
                 source_addr: 0xFFFF,
                 dest_addr: 0xFFFF,
-                hdma_stat: 0x00,
+                hdma_stat: 0xFF,
+                pending_blocks: 0,
             },
         };
 
         Self {
             cart,
+            boot_rom: boot_rom.unwrap_or_default().into_boxed_slice(),
             system_state,
+            sgb,
             wram,
             wram_bank,
             hram,
@@ -124,9 +215,11 @@ impl Mmu {
             let dest_address = 0xFE00 | (next_address & 0x00FF);
 
             let data = self.unticked_read(next_address);
-            self.ppu.write(dest_address, data);
+            self.ppu.oam_dma_write(dest_address, data);
 
-            self.oam_dma.as_mut().unwrap().next_address += 1;
+            let oam_dma = self.oam_dma.as_mut().unwrap();
+            oam_dma.next_address += 1;
+            oam_dma.value = data;
             match pending_cycles.checked_sub(1) {
                 None => oam_dma_completed = true,
                 Some(x) => self.oam_dma.as_mut().unwrap().pending_cycles = x,
@@ -138,8 +231,20 @@ impl Mmu {
         }
     }
 
-    fn oam_dma_in_progress(&self) -> bool {
-        self.oam_dma.is_some()
+    /// The CPU can't use OAM while the OAM DMA runs, nor the bus the DMA reads from. Reads from
+    /// that bus return the byte the DMA copies instead
+    fn oam_dma_conflict(&self, address: u16) -> Option<u8> {
+        let oam_dma = self.oam_dma.as_ref()?;
+        let model = self.system_state.model;
+        if (OAM_START..=OAM_END).contains(&address) {
+            Some(0xFF)
+        } else if Bus::of(address, model).is_some()
+            && Bus::of(address, model) == Bus::of(oam_dma.next_address, model)
+        {
+            Some(oam_dma.value)
+        } else {
+            None
+        }
     }
 
     fn wram_banked_read(&self, address: u16) -> u8 {
@@ -169,28 +274,52 @@ impl Mmu {
     }
 
     fn on_hdma5_write(&mut self, data: u8) {
+        let lcd_enabled = self.ppu.lcd_enabled();
+        let hdma_state = &mut self.system_state.hdma_state;
         if (data & 0x80) == 0 {
             // GDMA
-            if self.system_state.hdma_state.is_hdma_active() {
+            if hdma_state.is_hdma_active() {
                 // If HDMA is active, cancel it keeping the remaining length
-                self.system_state.hdma_state.hdma_stat |= 0x80;
+                hdma_state.hdma_stat |= 0x80;
             } else {
-                let len = ((data as usize & 0x7F) + 1) * 0x10;
-                let mut src_addr = self.system_state.hdma_state.source_addr & 0xFFF0;
-                let mut dest_addr = (self.system_state.hdma_state.dest_addr & 0x1FF0) | 0x8000;
-
-                for _ in 0..len {
-                    let value = self.unticked_read(src_addr);
-                    self.unticked_write(dest_addr, value);
-                    src_addr += 1;
-                    dest_addr += 1;
-                }
-
-                self.system_state.hdma_state.hdma_stat = 0xFF;
+                // All the blocks are copied before the CPU runs again
+                hdma_state.pending_blocks = (data & 0x7F) + 1;
+                hdma_state.hdma_stat = 0xFF;
             }
         } else {
-            // HDMA
-            log::info!("TODO: Setup HDMA");
+            // HDMA. Blocks are copied at the start of each H-Blank. Writing while active restarts
+            // the transfer with the new length
+            hdma_state.hdma_stat = data & 0x7F;
+            if !lcd_enabled {
+                // There is no H-Blank with the LCD off, only one block is copied right away
+                hdma_state.pending_blocks = 1;
+            }
+        }
+    }
+
+    /// Copy the blocks of a GDMA, or the block of an HDMA for the current H-Blank. The CPU is
+    /// stalled until they are copied. This is only called before the CPU accesses memory, so
+    /// HDMA pauses while the CPU is halted
+    fn run_vram_dma(&mut self) {
+        while self.system_state.hdma_state.pending_blocks > 0 {
+            let hdma_state = &mut self.system_state.hdma_state;
+            hdma_state.pending_blocks -= 1;
+            let src_addr = hdma_state.source_addr & 0xFFF0;
+            let dest_addr = (hdma_state.dest_addr & 0x1FF0) | 0x8000;
+
+            for offset in 0..VRAM_DMA_BLOCK_SIZE {
+                let value = self.unticked_read(src_addr.wrapping_add(offset));
+                self.unticked_write(dest_addr + offset, value);
+            }
+
+            let hdma_state = &mut self.system_state.hdma_state;
+            hdma_state.source_addr = src_addr.wrapping_add(VRAM_DMA_BLOCK_SIZE);
+            hdma_state.dest_addr = (dest_addr + VRAM_DMA_BLOCK_SIZE) & 0x1FF0;
+            hdma_state.on_block_copied();
+
+            for _ in 0..VRAM_DMA_BLOCK_CYCLES * self.system_state.speed_divider() {
+                self.tick();
+            }
         }
     }
 
@@ -202,6 +331,71 @@ impl Mmu {
         self.joypad.keyup(key);
     }
 
+    pub fn joypad_keys(&self) -> u8 {
+        self.joypad.keys()
+    }
+
+    pub fn set_joypad_keys(&mut self, keys: u8) {
+        self.joypad.set_keys(keys);
+    }
+
+    /// Put the IO registers, VRAM and palettes in the state the boot ROM leaves them in, to start
+    /// the cartridge at 0x0100 without running the boot ROM
+    pub(crate) fn skip_boot_rom(&mut self) {
+        self.system_state.bootrom_mapped = false;
+
+        // The boot ROM clears VRAM, both banks on a CGB. The logo it leaves behind is not drawn.
+        // Writes to the bank register are ignored on DMG hardware
+        for bank in [1, 0] {
+            self.unticked_write(VRAM_BANK_ADDRESS, bank);
+            for address in VRAM_START..=VRAM_END {
+                self.unticked_write(address, 0x00);
+            }
+        }
+
+        // The BG palettes of CGB games are left white, which they already are. DMG games get
+        // colors the boot ROM looks up by title, when published by Nintendo
+        if self.system_state.model.is_cgb() && self.system_state.dmg_mode() {
+            let [bg, obj0, obj1] = self
+                .cart
+                .nintendo_title_checksum()
+                .and_then(|checksum| dmg_compat_palettes(checksum, self.cart.title_fourth_letter()))
+                .unwrap_or([
+                    DMG_COMPAT_BG_PALETTE,
+                    DMG_COMPAT_OBJ_PALETTE,
+                    DMG_COMPAT_OBJ_PALETTE,
+                ]);
+            self.unticked_write(BCPS, BCPS_AUTO_INCREMENT);
+            for data in bg {
+                self.unticked_write(BCPD, data);
+            }
+            self.unticked_write(OCPS, BCPS_AUTO_INCREMENT);
+            for data in obj0.into_iter().chain(obj1) {
+                self.unticked_write(OCPD, data);
+            }
+        }
+
+        for (address, data) in POST_BOOT_IO {
+            self.unticked_write(address, data);
+        }
+    }
+
+    /// Registers that only exist on CGB hardware. They read 0xFF and ignore writes on DMG hardware
+    fn is_cgb_register(address: u16) -> bool {
+        matches!(
+            address,
+            VRAM_BANK_ADDRESS
+                | KEY1
+                | HDMA1..=HDMA5
+                | PALETTE_START..=PALETTE_END
+                | WRAM_BANK_SELECT
+        )
+    }
+
+    fn boot_rom_mapped(&self, address: u16) -> bool {
+        self.system_state.bootrom_mapped && (address as usize) < self.boot_rom.len()
+    }
+
     fn disable_bootrom(&mut self, data: u8) {
         self.system_state.bootrom_mapped = data == 0x00;
         if !self.system_state.bootrom_mapped {
@@ -209,7 +403,7 @@ impl Mmu {
         }
     }
 
-    pub fn save_ram(&self) -> Option<&Vec<u8>> {
+    pub fn save_ram(&self) -> Option<Vec<u8>> {
         self.cart.save_ram()
     }
 }
@@ -220,29 +414,20 @@ impl Memory for Mmu {
     /// using `tick` inside it. We want the other components to keep up with the
     /// CPU during each memory access
     fn read(&mut self, address: u16) -> u8 {
+        self.run_vram_dma();
         self.tick();
-        if self.oam_dma_in_progress() {
-            // Only HRAM is accessible during OAM DMA
-            if (HRAM_START..=HRAM_END).contains(&address) {
-                self.hram[address as usize - 0xFF80]
-            } else {
-                0xFF
-            }
-        } else {
-            self.unticked_read(address)
+        match self.oam_dma_conflict(address) {
+            Some(value) => value,
+            None => self.unticked_read(address),
         }
     }
 
     fn write(&mut self, address: u16, data: u8) {
+        self.run_vram_dma();
         self.tick();
-        if self.oam_dma_in_progress() {
-            // Only HRAM is accessible during OAM DMA
-            if (HRAM_START..=HRAM_END).contains(&address) {
-                self.hram[address as usize - 0xFF80] = data;
-            }
-        } else {
+        if self.oam_dma_conflict(address).is_none() {
             self.unticked_write(address, data);
-        };
+        }
     }
 }
 
@@ -250,10 +435,14 @@ impl SystemBus for Mmu {
     /// Raw Read: Read the contents of a memory location without ticking all the
     /// components
     fn unticked_read(&mut self, address: u16) -> u8 {
+        if !self.system_state.model.is_cgb() && Mmu::is_cgb_register(address) {
+            return 0xFF;
+        }
+
         match address {
             0x100..=0x1FF => return self.cart.read(address),
-            BOOT_ROM_START..=BOOT_ROM_END if self.system_state.bootrom_mapped => {
-                return CGB_BOOT_ROM[address as usize]
+            BOOT_ROM_START..=BOOT_ROM_END if self.boot_rom_mapped(address) => {
+                return self.boot_rom[address as usize]
             }
             CART_ROM_START..=CART_ROM_END => return self.cart.read(address),
             VRAM_START..=VRAM_END => return self.ppu.read(address),
@@ -273,7 +462,7 @@ impl SystemBus for Mmu {
             0xFF46 => return 0xFF, // TODO: Check if this is correct
             0xFF40..=0xFF4B => return self.ppu.read(address),
             VRAM_BANK_ADDRESS => return self.ppu.read(address),
-            0xFF4D => return self.system_state.key1,
+            KEY1 => return self.system_state.key1,
             0xFF50 => return u8::from(self.system_state.bootrom_mapped),
             HDMA1..=HDMA4 => return 0xFF,
             HDMA5 => return self.system_state.hdma_state.hdma_stat,
@@ -287,9 +476,13 @@ impl SystemBus for Mmu {
     }
 
     fn unticked_write(&mut self, address: u16, data: u8) {
+        if !self.system_state.model.is_cgb() && Mmu::is_cgb_register(address) {
+            return;
+        }
+
         match address {
             0x100..=0x1FF => self.cart.write(address, data),
-            BOOT_ROM_START..=BOOT_ROM_END if self.system_state.bootrom_mapped => {
+            BOOT_ROM_START..=BOOT_ROM_END if self.boot_rom_mapped(address) => {
                 log::error!("Write to boot ROM {:#06X} with {:#04X}", address, data)
             }
             CART_ROM_START..=CART_ROM_END => self.cart.write(address, data),
@@ -300,7 +493,14 @@ impl SystemBus for Mmu {
             0xE000..=0xFDFF => self.unticked_write(address - 0xE000, data),
             OAM_START..=OAM_END => self.ppu.write(address, data),
             0xFEA0..=0xFEFF => {}
-            JOYP_ADDRESS => self.joypad.write(address, data),
+            JOYP_ADDRESS => {
+                self.joypad.write(address, data);
+                for command in self.joypad.take_sgb_commands() {
+                    if let Some(sgb) = self.sgb.as_mut() {
+                        sgb.command(&command, &self.ppu);
+                    }
+                }
+            }
             SERIAL_START..=SERIAL_END => self.serial.write(address, data),
             TIMER_START..=TIMER_END => self.timer.write(address, data),
             INTERRUPT_FLAG_ADDRESS => self.interrupts.write(address, data),
@@ -310,13 +510,14 @@ impl SystemBus for Mmu {
                 let oam_dma = OamDma {
                     pending_cycles: OAM_DMA_CYCLES,
                     next_address: (data as u16) << 8,
+                    value: 0xFF,
                 };
 
                 self.oam_dma = Some(oam_dma);
             }
             0xFF40..=0xFF4B => self.ppu.write(address, data),
             VRAM_BANK_ADDRESS => self.ppu.write(address, data),
-            0xFF4D => {
+            KEY1 => {
                 let key1 = (self.system_state.key1 & 0x80) | (data & 0x7F);
                 self.system_state.key1 = key1;
             }
@@ -339,13 +540,25 @@ impl SystemBus for Mmu {
 
     fn tick(&mut self) {
         self.system_state.total_cycles += 1;
+
+        // The system clock is stopped in STOP mode. The joypad can still wake the CPU up, the LCD
+        // is blanked and the cartridge keeps its own time
+        if self.system_state.execution_state == ExecutionState::Stopped {
+            self.joypad.tick(&mut self.interrupts);
+            self.ppu.tick(&mut self.system_state, &mut self.interrupts);
+            self.cart.tick(self.system_state.speed_divider() == 2);
+            return;
+        }
+
         self.tick_oam_dma();
 
         self.timer
             .tick(&mut self.system_state, &mut self.interrupts);
+        self.serial.tick(&mut self.interrupts);
         self.joypad.tick(&mut self.interrupts);
         self.ppu.tick(&mut self.system_state, &mut self.interrupts);
         self.apu.tick(&mut self.system_state, &mut self.interrupts);
+        self.cart.tick(self.system_state.speed_divider() == 2);
     }
 
     fn system_state(&mut self) -> &mut SystemState {
@@ -356,3 +569,210 @@ impl SystemBus for Mmu {
         &mut self.interrupts
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::tests::test_rom;
+
+    /// Machine cycles in a scanline
+    const SCANLINE_CYCLES: u64 = 114;
+
+    /// CGB with the LCD on and 0x40 bytes of data in WRAM set up as the source of a VRAM DMA
+    fn vram_dma_mmu() -> Mmu {
+        let cart = Cartridge::new(test_rom(0x00, 0x00, 0x00), None).unwrap();
+        let mut mmu = Mmu::new(cart, HardwareModel::Cgb, None);
+        mmu.skip_boot_rom();
+
+        for offset in 0..0x40 {
+            mmu.unticked_write(0xC000 + offset, offset as u8 + 1);
+        }
+        mmu.unticked_write(HDMA1, 0xC0);
+        mmu.unticked_write(HDMA2, 0x00);
+        mmu.unticked_write(HDMA3, 0x00);
+        mmu.unticked_write(HDMA4, 0x00);
+        mmu
+    }
+
+    /// Read HDMA5 like the CPU would, returning its value and the machine cycles it took
+    fn timed_hdma5_read(mmu: &mut Mmu) -> (u8, u64) {
+        let start = mmu.system_state.total_cycles;
+        let value = mmu.read(HDMA5);
+        (value, mmu.system_state.total_cycles - start)
+    }
+
+    /// Keep reading HDMA5 until a block is copied. Returns the cycles the CPU was stalled for
+    fn wait_for_hdma_block(mmu: &mut Mmu) -> u64 {
+        // A scanline takes twice as many cycles in double speed
+        for _ in 0..2 * SCANLINE_CYCLES {
+            let (_, cycles) = timed_hdma5_read(mmu);
+            if cycles > 1 {
+                return cycles - 1;
+            }
+        }
+        panic!("No HDMA block copied in a scanline");
+    }
+
+    #[test]
+    fn test_gdma_stalls_the_cpu_for_every_block() {
+        // The LCD is turned off so that the copy does not hit mode 3, where VRAM is locked
+        let mut mmu = vram_dma_mmu();
+        mmu.unticked_write(0xFF40, 0x00);
+        mmu.write(HDMA5, 0x02);
+        let (hdma5, cycles) = timed_hdma5_read(&mut mmu);
+        assert_eq!(hdma5, 0xFF);
+        assert_eq!(cycles, 3 * VRAM_DMA_BLOCK_CYCLES + 1);
+        assert_eq!(mmu.unticked_read(0x8000), 0x01);
+        assert_eq!(mmu.unticked_read(0x802F), 0x30);
+        assert_eq!(mmu.unticked_read(0x8030), 0x00);
+
+        // Double speed takes as long, which is twice as many cycles
+        let mut mmu = vram_dma_mmu();
+        mmu.unticked_write(0xFF40, 0x00);
+        mmu.system_state.key1 = 0x80;
+        mmu.write(HDMA5, 0x02);
+        let (_, cycles) = timed_hdma5_read(&mut mmu);
+        assert_eq!(cycles, 3 * 2 * VRAM_DMA_BLOCK_CYCLES + 1);
+    }
+
+    #[test]
+    fn test_hdma_copies_one_block_per_hblank() {
+        let mut mmu = vram_dma_mmu();
+        mmu.write(HDMA5, 0x82);
+        assert_eq!(mmu.unticked_read(HDMA5), 0x02);
+        assert_eq!(mmu.unticked_read(0x8000), 0x00);
+
+        for (block, remaining) in [(0, 0x01), (1, 0x00), (2, 0xFF)] {
+            assert_eq!(wait_for_hdma_block(&mut mmu), VRAM_DMA_BLOCK_CYCLES);
+            assert_eq!(mmu.unticked_read(HDMA5), remaining);
+            assert_eq!(
+                mmu.unticked_read(0x8000 + block * 0x10),
+                block as u8 * 0x10 + 1
+            );
+            assert_eq!(mmu.unticked_read(0x8010 + block * 0x10), 0x00);
+        }
+
+        // Nothing is left to copy
+        for _ in 0..2 * SCANLINE_CYCLES {
+            assert_eq!(timed_hdma5_read(&mut mmu).1, 1);
+        }
+    }
+
+    #[test]
+    fn test_hdma_double_speed() {
+        let mut mmu = vram_dma_mmu();
+        mmu.system_state.key1 = 0x80;
+        mmu.write(HDMA5, 0x80);
+        assert_eq!(wait_for_hdma_block(&mut mmu), 2 * VRAM_DMA_BLOCK_CYCLES);
+        assert_eq!(mmu.unticked_read(HDMA5), 0xFF);
+    }
+
+    #[test]
+    fn test_hdma_pauses_while_halted() {
+        let mut mmu = vram_dma_mmu();
+        mmu.write(HDMA5, 0x81);
+        mmu.system_state.execution_state = ExecutionState::Halted;
+        for _ in 0..2 * SCANLINE_CYCLES {
+            mmu.tick();
+        }
+        assert_eq!(mmu.unticked_read(HDMA5), 0x01);
+        assert_eq!(mmu.unticked_read(0x8000), 0x00);
+
+        // The block of the last H-Blank is copied once the CPU runs again
+        mmu.system_state.execution_state = ExecutionState::ExecutingProgram;
+        let (_, cycles) = timed_hdma5_read(&mut mmu);
+        assert_eq!(cycles, VRAM_DMA_BLOCK_CYCLES + 1);
+        assert_eq!(mmu.unticked_read(HDMA5), 0x00);
+        assert_eq!(mmu.unticked_read(0x8000), 0x01);
+    }
+
+    #[test]
+    fn test_hdma_cancel_keeps_remaining_length() {
+        let mut mmu = vram_dma_mmu();
+        mmu.write(HDMA5, 0x83);
+        wait_for_hdma_block(&mut mmu);
+        assert_eq!(mmu.unticked_read(HDMA5), 0x02);
+
+        mmu.write(HDMA5, 0x00);
+        assert_eq!(mmu.unticked_read(HDMA5), 0x82);
+        for _ in 0..2 * SCANLINE_CYCLES {
+            assert_eq!(timed_hdma5_read(&mut mmu).1, 1);
+        }
+        assert_eq!(mmu.unticked_read(0x8010), 0x00);
+    }
+
+    #[test]
+    fn test_hdma_with_lcd_off_copies_one_block() {
+        let mut mmu = vram_dma_mmu();
+        mmu.unticked_write(0xFF40, 0x00);
+        mmu.write(HDMA5, 0x81);
+        let (hdma5, cycles) = timed_hdma5_read(&mut mmu);
+        assert_eq!(cycles, VRAM_DMA_BLOCK_CYCLES + 1);
+        assert_eq!(hdma5, 0x00);
+
+        for _ in 0..2 * SCANLINE_CYCLES {
+            assert_eq!(timed_hdma5_read(&mut mmu).1, 1);
+        }
+    }
+
+    /// Tick until STAT reports the PPU mode
+    fn tick_until_mode(mmu: &mut Mmu, mode: u8) {
+        while mmu.unticked_read(0xFF41) & 0b11 != mode {
+            mmu.tick();
+        }
+    }
+
+    #[test]
+    fn test_vram_and_oam_are_locked_by_the_ppu() {
+        let mut mmu = vram_dma_mmu();
+        tick_until_mode(&mut mmu, 0);
+        mmu.unticked_write(0x8000, 0x12);
+        mmu.unticked_write(0xFE00, 0x34);
+
+        // OAM search
+        tick_until_mode(&mut mmu, 2);
+        assert_eq!(mmu.unticked_read(0x8000), 0x12);
+        assert_eq!(mmu.unticked_read(0xFE00), 0xFF);
+
+        // Mode 3
+        tick_until_mode(&mut mmu, 3);
+        assert_eq!(mmu.unticked_read(0x8000), 0xFF);
+        assert_eq!(mmu.unticked_read(0xFE00), 0xFF);
+        assert_eq!(mmu.unticked_read(BCPD), 0xFF);
+        mmu.unticked_write(0x8000, 0x56);
+        mmu.unticked_write(0xFE00, 0x78);
+
+        tick_until_mode(&mut mmu, 0);
+        assert_eq!(mmu.unticked_read(0x8000), 0x12);
+        assert_eq!(mmu.unticked_read(0xFE00), 0x34);
+    }
+
+    #[test]
+    fn test_oam_dma_locks_its_bus() {
+        // WRAM has its own bus on a CGB. The cartridge can still be read during a DMA from WRAM
+        let mut mmu = vram_dma_mmu();
+        let rom = mmu.unticked_read(0x0000);
+        mmu.write(0xFF46, 0xC0);
+        assert_eq!(mmu.read(0xC030), 0x01);
+        assert_eq!(mmu.read(0xC030), 0x02);
+        assert_eq!(mmu.read(0x0000), rom);
+        assert_eq!(mmu.read(0xFE00), 0xFF);
+        mmu.write(0xFF80, 0x9A);
+        assert_eq!(mmu.read(0xFF80), 0x9A);
+
+        // DMG hardware reads both from the same bus
+        let cart = Cartridge::new(test_rom(0x00, 0x00, 0x00), None).unwrap();
+        let mut mmu = Mmu::new(cart, HardwareModel::Dmg, None);
+        mmu.skip_boot_rom();
+        mmu.unticked_write(0xC000, 0x01);
+        mmu.write(0xFF46, 0xC0);
+        assert_eq!(mmu.read(0x0000), 0x01);
+
+        // Everything is accessible again once the DMA is over
+        for _ in 0..OAM_DMA_CYCLES {
+            mmu.tick();
+        }
+        tick_until_mode(&mut mmu, 0);
+        assert_eq!(mmu.read(0xFE00), 0x01);
+    }
+}
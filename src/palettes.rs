@@ -24,6 +24,131 @@ pub(crate) const RGBA_LIGHT_GRAY: RGBA = RGBA(0x88, 0xC0, 0x70, 0xFF);
 pub(crate) const RGBA_DARK_GRAY: RGBA = RGBA(0x34, 0x68, 0x56, 0xFF);
 pub(crate) const RGBA_BLACK: RGBA = RGBA(0x08, 0x18, 0x20, 0xFF);
 
+/// Colors the CGB boot ROM gives DMG games it has no palette for, in the format of the CGB
+/// palette RAM. The same as the first entry of `COMPAT_COMBINATIONS`
+pub(crate) const DMG_COMPAT_BG_PALETTE: [u8; 8] = [0xFF, 0x7F, 0xEF, 0x1B, 0x80, 0x61, 0x00, 0x00];
+pub(crate) const DMG_COMPAT_OBJ_PALETTE: [u8; 8] = [0xFF, 0x7F, 0x1F, 0x42, 0xF2, 0x1C, 0x00, 0x00];
+
+/// Title checksums of the Nintendo games the CGB boot ROM has colors for. Checksums from
+/// `COMPAT_FIRST_DUPLICATE` on are shared by several games, which are told apart by the fourth
+/// letter of their title
+const COMPAT_TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+const COMPAT_FIRST_DUPLICATE: usize = 65;
+const COMPAT_FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// Palette combination of each title checksum. Bit 7 is not part of the index
+const COMPAT_COMBINATION_PER_CHECKSUM: [u8; 94] = [
+    0x00, 0x04, 0x05, 0x23, 0x22, 0x03, 0x1F, 0x0F, 0x0A, 0x05, 0x13, 0x24, 0x87, 0x25, 0x1E, 0x2C,
+    0x15, 0x20, 0x1F, 0x14, 0x05, 0x21, 0x0D, 0x0E, 0x05, 0x1D, 0x05, 0x12, 0x09, 0x03, 0x02, 0x1A,
+    0x19, 0x19, 0x29, 0x2A, 0x1A, 0x2D, 0x2A, 0x2D, 0x24, 0x26, 0x1A, 0x2A, 0x1E, 0x29, 0x22, 0x22,
+    0x05, 0x2A, 0x06, 0x05, 0x21, 0x19, 0x2A, 0x2A, 0x28, 0x02, 0x10, 0x19, 0x2A, 0x2A, 0x05, 0x00,
+    0x27, 0x24, 0x16, 0x19, 0x06, 0x20, 0x0C, 0x24, 0x0B, 0x27, 0x12, 0x27, 0x18, 0x1F, 0x32, 0x11,
+    0x2E, 0x06, 0x1B, 0x00, 0x2F, 0x29, 0x29, 0x00, 0x00, 0x13, 0x22, 0x17, 0x12, 0x1D,
+];
+
+/// OBJ0, OBJ1 and BG palettes of each combination, as byte offsets into `COMPAT_PALETTES`. A few
+/// do not start at a palette boundary
+const COMPAT_COMBINATIONS: [[u8; 3]; 51] = [
+    [32, 32, 232],
+    [144, 144, 144],
+    [160, 160, 160],
+    [192, 192, 192],
+    [72, 72, 72],
+    [0, 0, 0],
+    [216, 216, 216],
+    [40, 40, 40],
+    [96, 96, 96],
+    [208, 208, 208],
+    [128, 64, 64],
+    [32, 224, 224],
+    [32, 16, 16],
+    [24, 32, 32],
+    [32, 232, 232],
+    [224, 32, 224],
+    [16, 136, 16],
+    [128, 128, 64],
+    [32, 32, 56],
+    [32, 32, 144],
+    [32, 32, 160],
+    [152, 152, 72],
+    [30, 30, 88],
+    [136, 136, 16],
+    [32, 32, 16],
+    [32, 32, 24],
+    [224, 224, 0],
+    [24, 24, 0],
+    [0, 0, 8],
+    [144, 176, 144],
+    [160, 176, 160],
+    [192, 176, 192],
+    [128, 176, 64],
+    [136, 32, 104],
+    [222, 0, 112],
+    [222, 32, 120],
+    [152, 176, 72],
+    [128, 224, 80],
+    [32, 184, 224],
+    [136, 176, 16],
+    [32, 0, 16],
+    [32, 224, 24],
+    [224, 24, 0],
+    [24, 224, 32],
+    [168, 224, 32],
+    [24, 224, 0],
+    [200, 24, 224],
+    [0, 224, 64],
+    [32, 24, 224],
+    [224, 24, 48],
+    [32, 224, 232],
+];
+
+/// Palettes of the CGB boot ROM, 4 RGB555 colors each
+const COMPAT_PALETTES: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, 0x639F, 0x4279, 0x15B0, 0x04CB, 0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, 0x7FFF, 0x421F, 0x1CF2, 0x0000, 0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000, 0x7FFF, 0x03EF, 0x01D6, 0x0000, 0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000, 0x67FF, 0x77AC, 0x1A13, 0x2D6B, 0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, 0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, 0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF, 0x7FFF, 0x01DF, 0x0112, 0x0000, 0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000, 0x299F, 0x001A, 0x000C, 0x0000, 0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120, 0x7FFF, 0x7EEB, 0x001F, 0x7C00, 0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000, 0x03FF, 0x001F, 0x000C, 0x0000, 0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF, 0x7FFF, 0x7E8C, 0x7C00, 0x0000, 0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+/// BG, OBJ0 and OBJ1 palettes the CGB boot ROM picks for a DMG game published by Nintendo, in the
+/// format of the CGB palette RAM. `None` for games it has no colors for
+pub(crate) fn dmg_compat_palettes(title_checksum: u8, fourth_letter: u8) -> Option<[[u8; 8]; 3]> {
+    let index = COMPAT_TITLE_CHECKSUMS
+        .iter()
+        .enumerate()
+        .position(|(index, checksum)| {
+            *checksum == title_checksum
+                && (index < COMPAT_FIRST_DUPLICATE
+                    || COMPAT_FOURTH_LETTERS[index - COMPAT_FIRST_DUPLICATE] == fourth_letter)
+        })?;
+
+    let combination =
+        COMPAT_COMBINATIONS[usize::from(COMPAT_COMBINATION_PER_CHECKSUM[index] & 0x7F)];
+    let palette = |offset: u8| {
+        let mut palette = [0x00; 8];
+        for (i, byte) in palette.iter_mut().enumerate() {
+            let offset = usize::from(offset) + i;
+            *byte = COMPAT_PALETTES[offset / 2].to_le_bytes()[offset % 2];
+        }
+        palette
+    };
+    let [obj0, obj1, bg] = combination;
+    Some([palette(bg), palette(obj0), palette(obj1)])
+}
+
 fn map_to_actual_color(shade: GameboyColorShade) -> RGBA {
     match shade {
         GameboyColorShade::White => RGBA_WHITE,
@@ -33,13 +158,24 @@ fn map_to_actual_color(shade: GameboyColorShade) -> RGBA {
     }
 }
 
+/// Shade of a pixel drawn with a DMG palette
+pub(crate) fn dmg_shade(color: RGBA) -> usize {
+    [RGBA_WHITE, RGBA_LIGHT_GRAY, RGBA_DARK_GRAY, RGBA_BLACK]
+        .iter()
+        .position(|shade| shade.full() == color.full())
+        .unwrap_or(0)
+}
+
 fn extract_actual_color_from_spec(spec: &[u8; 8], index: usize) -> RGBA {
-    let color_byte_1 = spec[index * 2];
-    let color_byte_2 = spec[index * 2 + 1];
+    rgb555_to_rgba(u16::from_le_bytes([spec[index * 2], spec[index * 2 + 1]]))
+}
 
+/// Convert a color in the little endian RGB555 format of the CGB and SGB
+pub(crate) fn rgb555_to_rgba(color: u16) -> RGBA {
     // GGGRRRRR             |  XBBBBBGG
     // Color 1              |  Color 2
     // Red and Lower Green  |  Upper Green and Blue
+    let [color_byte_1, color_byte_2] = color.to_le_bytes();
 
     let r = color_byte_1 & 0b11111;
     let g = ((color_byte_2 & 0b11) << 3) | ((color_byte_1 & 0b11100000) >> 5);
@@ -1,9 +1,16 @@
-use crate::framebuffer::access;
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+
 use crate::interrupts::{InterruptHandler, InterruptType};
 use crate::memory::Memory;
-use crate::palettes::Palette;
+use crate::palettes::{rgb555_to_rgba, Palette, RGBA_WHITE};
+use crate::sgb::SGB_TRANSFER_SIZE;
 use crate::textures::RGBA;
-use crate::{GameFrame, SystemState};
+use crate::{ExecutionState, GameFrame, SystemState};
+
+use fifo::PixelFifo;
+
+mod fifo;
 
 pub(crate) const VRAM_START: u16 = 0x8000;
 pub(crate) const VRAM_END: u16 = 0x9FFF;
@@ -11,6 +18,11 @@ pub(crate) const OAM_START: u16 = 0xFE00;
 pub(crate) const OAM_END: u16 = 0xFE9F;
 pub(crate) const PALETTE_START: u16 = 0xFF68;
 pub(crate) const PALETTE_END: u16 = 0xFF6B;
+/// CGB BG and OBJ palette index and data registers
+pub(crate) const BCPS: u16 = 0xFF68;
+pub(crate) const BCPD: u16 = 0xFF69;
+pub(crate) const OCPS: u16 = 0xFF6A;
+pub(crate) const OCPD: u16 = 0xFF6B;
 
 pub(crate) const VRAM_BANK_ADDRESS: u16 = 0xFF4F;
 
@@ -42,6 +54,9 @@ const OAM_SEARCH_DOTS: u64 = 80;
 const RENDERING_DOTS: u64 = 168;
 const HBLANK_DOTS: u64 = 208;
 const SCANLINE_DOTS: u64 = OAM_SEARCH_DOTS + RENDERING_DOTS + HBLANK_DOTS;
+/// The first line after the LCD is turned on is shorter, and has no OAM search. It reports mode 0
+/// until mode 3
+const LCD_ON_SKIPPED_DOTS: u64 = 4;
 
 pub const LCD_WIDTH: usize = 160;
 pub const LCD_HEIGHT: usize = 144;
@@ -52,20 +67,37 @@ const VBLANK_DOTS: u64 = VBLANK_SCANLINES as u64 * SCANLINE_DOTS;
 
 const COLOR_PALETTE_SIZE: usize = 64;
 
-#[derive(Debug, Clone, Copy, Default)]
+/// How the PPU draws the LCD image
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Renderer {
+    /// One pixel per dot during mode 3. Register writes in the middle of a line show up on the
+    /// rest of the line, and mode 3 is longer with sprites, SCX fine scroll and the window
+    #[default]
+    PixelFifo,
+    /// Whole lines at the end of a mode 3 of fixed length. Faster, but mid-line effects are lost
+    Scanline,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 struct RenderedBackgroundPixel {
     bg_color_index: u8,
     bg_priority: bool, // Derived from Bit 7 in BG Attribute Map for the pixel
 }
 
+#[derive(Serialize, Deserialize)]
 pub(crate) struct Ppu {
-    vram: [u8; (VRAM_END - VRAM_START + 1) as usize * 2],
+    vram: Box<[u8]>,
     vram_bank: usize,
 
+    #[serde(with = "BigArray")]
     oam: [u8; (OAM_END - OAM_START + 1) as usize],
 
     lcdc: Lcdc,
     stat: LcdStat,
+    /// State of the STAT interrupt line, which requests an interrupt when it goes high
+    stat_line: bool,
+    /// STAT was written since the last dot
+    stat_written: bool,
     scy: u8,
     scx: u8,
     ly: u8,
@@ -76,17 +108,27 @@ pub(crate) struct Ppu {
     // CGB palette registers and data
     bcps: u8,
     ocps: u8,
+    #[serde(with = "BigArray")]
     color_bg_palettes: [u8; COLOR_PALETTE_SIZE],
+    #[serde(with = "BigArray")]
     color_obj_palettes: [u8; COLOR_PALETTE_SIZE],
 
     dots_in_line: u64,
     window_internal_counter: Option<u8>,
+    /// Mode 3 of the current line when drawn by the pixel FIFO
+    fifo: Option<PixelFifo>,
+    // The renderer is a setting of the emulator, not part of the machine state
+    #[serde(skip)]
+    renderer: Renderer,
 
     bgp: u8,
     obp0: u8,
     obp1: u8,
 
-    frame: GameFrame,
+    frame: Box<GameFrame>,
+    /// The LCD shows a blank frame while it is off, and until the end of the first frame after it
+    /// is turned back on
+    frame_blank: bool,
     // Index in palette of each color that was used for background
     bg_color_indices: Vec<RenderedBackgroundPixel>,
 }
@@ -97,11 +139,13 @@ impl Ppu {
         stat.set_mode(LcdStatus::OamSearch);
 
         Ppu {
-            vram: [0xFF; (VRAM_END - VRAM_START + 1) as usize * 2],
+            vram: vec![0xFF; (VRAM_END - VRAM_START + 1) as usize * 2].into_boxed_slice(),
             vram_bank: 0xFE, // Bank 0. All other bits are 1
             oam: [0xFF; (OAM_END - OAM_START + 1) as usize],
             lcdc: Default::default(),
             stat,
+            stat_line: false,
+            stat_written: false,
             dots_in_line: Default::default(),
             scy: 0x00,
             scx: 0x00,
@@ -110,6 +154,8 @@ impl Ppu {
             wy: 0x00,
             wx: 0x00,
             window_internal_counter: None,
+            fifo: None,
+            renderer: Renderer::default(),
             bgp: 0x00,
             obp0: 0x00,
             obp1: 0x00,
@@ -117,48 +163,62 @@ impl Ppu {
             ocps: 0x00,
             color_bg_palettes: [0xFF; COLOR_PALETTE_SIZE],
             color_obj_palettes: [0xFF; COLOR_PALETTE_SIZE],
-            frame: Default::default(),
+            frame: Box::new(GameFrame::new(LCD_WIDTH, LCD_HEIGHT)),
+            frame_blank: false,
             bg_color_indices: vec![Default::default(); LCD_WIDTH * LCD_HEIGHT],
         }
     }
 
     pub fn tick(&mut self, system_state: &mut SystemState, interrupts: &mut InterruptHandler) {
+        // Nothing runs while the LCD is off or in STOP mode, and the STAT line is not updated
+        if !self.lcdc.lcd_enabled() || system_state.execution_state == ExecutionState::Stopped {
+            self.stat_written = false;
+            if !self.frame_blank {
+                let color = self.lcd_off_color(system_state);
+                for y in 0..LCD_HEIGHT {
+                    self.frame.row_mut(y).fill(color);
+                }
+                self.frame_blank = true;
+            }
+            return;
+        }
+
         // Tick 4 times if single speed mode and 2 times if double speed mode
         // The LCD controller speed does not change with the speed mode
         let cycles_to_tick = 4 / system_state.speed_divider();
         for _ in 0..cycles_to_tick {
             self.dots_in_line += 1;
 
+            if let Some(mut fifo) = self.fifo.take() {
+                self.step_fifo(&mut fifo, system_state);
+                self.fifo = Some(fifo);
+            }
+
             match self.stat.mode() {
-                LcdStatus::OamSearch if self.dots_in_line == OAM_SEARCH_DOTS => {
-                    let old_stat = self.stat;
+                // The first line after the LCD is turned on goes from mode 0 to mode 3
+                LcdStatus::OamSearch | LcdStatus::Hblank
+                    if self.dots_in_line == OAM_SEARCH_DOTS =>
+                {
                     self.stat.set_mode(LcdStatus::Rendering);
-                    self.assert_lcd_stat(old_stat, interrupts);
+                    if self.renderer == Renderer::PixelFifo {
+                        self.fifo = Some(self.new_fifo());
+                    }
                 }
-                LcdStatus::Rendering if self.dots_in_line == RENDERING_DOTS => {
-                    self.render_line(system_state);
-                    let old_stat = self.stat;
+                LcdStatus::Rendering if self.rendering_done() => {
+                    // The pixel FIFO has drawn the line already
+                    if self.fifo.take().is_none() {
+                        self.render_line(system_state);
+                    }
                     self.stat.set_mode(LcdStatus::Hblank);
-                    self.assert_lcd_stat(old_stat, interrupts);
+                    system_state.hdma_state.on_hblank();
                 }
                 LcdStatus::Hblank if self.dots_in_line == SCANLINE_DOTS => {
-                    self.ly += 1;
-                    self.dots_in_line = 0;
-                    let old_stat = self.stat;
+                    self.next_line();
 
                     let next_mode = if self.ly == LCD_HEIGHT as u8 {
                         // Going into VBlank
                         self.window_internal_counter = None;
-
-                        // TODO: Check the second Model1Vblank condition
-                        if !old_stat.is_stat_irq_asserted()
-                            && self
-                                .stat
-                                .is_stat_interrupt_source_enabled(LcdStatSource::Mode1Vblank)
-                        {
-                            interrupts.request_interrupt(InterruptType::LcdStat);
-                        }
-
+                        self.frame_blank = false;
                         interrupts.request_interrupt(InterruptType::Vblank);
 
                         LcdStatus::Vblank
@@ -166,78 +226,112 @@ impl Ppu {
                         // Going into another LCD line
                         LcdStatus::OamSearch
                     };
-
                     self.stat.set_mode(next_mode);
-                    self.assert_lcd_stat(old_stat, interrupts);
-
-                    // The LY-LYC compare interrupt is actually delayed by 1 CPU cycle
-                    let old_stat = self.stat;
-                    self.stat.set_ly_lyc_state(self.ly == self.lyc);
-                    self.assert_lcd_stat(old_stat, interrupts);
                 }
+                // LY reads 0 for most of line 153
                 LcdStatus::Vblank if self.ly == 153 && self.dots_in_line == 8 => {
                     self.ly = 0;
-                    let old_stat = self.stat;
-                    self.stat.set_ly_lyc_state(self.ly == self.lyc);
-                    self.assert_lcd_stat(old_stat, interrupts);
+                    self.stat.set_ly_lyc_state(false);
                 }
                 LcdStatus::Vblank if self.ly == 0 && self.dots_in_line == SCANLINE_DOTS => {
+                    // LY is already 0 and was compared with LYC on line 153
                     self.dots_in_line = 0;
-                    let old_stat = self.stat;
                     self.stat.set_mode(LcdStatus::OamSearch);
-                    self.stat.set_ly_lyc_state(self.ly == self.lyc);
-                    self.assert_lcd_stat(old_stat, interrupts);
-                }
-                LcdStatus::Vblank if self.dots_in_line == SCANLINE_DOTS => {
-                    self.ly += 1;
-                    self.dots_in_line = 0;
-                    let old_stat = self.stat;
-                    self.stat.set_ly_lyc_state(self.ly == self.lyc);
-                    self.assert_lcd_stat(old_stat, interrupts);
                 }
+                LcdStatus::Vblank if self.dots_in_line == SCANLINE_DOTS => self.next_line(),
                 _ => {}
             }
+
+            // LY is compared with LYC 4 dots after it changes. The early LY = 0 of line 153 is
+            // compared 4 dots after it too
+            let line_153_ly_0 = self.ly == 0 && self.stat.mode() == LcdStatus::Vblank;
+            if self.dots_in_line == 4 || (line_153_ly_0 && self.dots_in_line == 12) {
+                self.stat.set_ly_lyc_state(self.ly == self.lyc);
+            }
+
+            self.update_stat_line(system_state, interrupts);
         }
     }
 
-    pub(crate) fn write_frame(&self, frame_writer: &mut access::AccessW<GameFrame>) {
-        *frame_writer.get().write() = self.frame.clone();
+    /// Start the next line. LY no longer matches LYC until it is compared again
+    fn next_line(&mut self) {
+        self.ly += 1;
+        self.dots_in_line = 0;
+        self.stat.set_ly_lyc_state(false);
     }
 
-    fn assert_lcd_stat(&mut self, old_stat: LcdStat, interrupts: &mut InterruptHandler) {
-        if !old_stat.is_stat_irq_asserted() && self.stat.is_stat_irq_asserted() {
+    /// The STAT interrupt is requested on the rising edge of the OR of all its enabled sources. No
+    /// interrupt is requested for a source while another one keeps the line high
+    fn update_stat_line(&mut self, system_state: &SystemState, interrupts: &mut InterruptHandler) {
+        let mut stat = self.stat;
+        // DMG hardware behaves as if 0xFF was written to STAT for a cycle before the written value
+        if std::mem::take(&mut self.stat_written) && !system_state.model.is_cgb() {
+            stat.0 |= 0x78;
+        }
+
+        // The mode 2 source also triggers at the start of line 144, even though it is in mode 1
+        let line_144_oam = self.ly == LCD_HEIGHT as u8
+            && self.dots_in_line < 4
+            && stat.is_stat_interrupt_source_enabled(LcdStatSource::Mode2Oam);
+
+        let stat_line = stat.is_stat_irq_asserted() || line_144_oam;
+        if stat_line && !self.stat_line {
             interrupts.request_interrupt(InterruptType::LcdStat);
         }
+        self.stat_line = stat_line;
+    }
+
+    pub(crate) fn lcd_enabled(&self) -> bool {
+        self.lcdc.lcd_enabled()
+    }
+
+    /// Takes effect from the next line
+    pub(crate) fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub(crate) fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    /// Mode 3 ends once the pixel FIFO has drawn the line, or after a fixed number of dots when
+    /// the line is drawn at once
+    fn rendering_done(&self) -> bool {
+        match self.fifo.as_ref() {
+            Some(fifo) => fifo.line_done(),
+            None => self.dots_in_line == RENDERING_DOTS,
+        }
+    }
+
+    /// The last frame drawn on the LCD
+    pub(crate) fn frame(&self) -> &GameFrame {
+        &self.frame
     }
 
     fn render_line(&mut self, system_state: &mut SystemState) {
-        if !self.lcdc.lcd_enabled() {
+        if self.frame_blank {
             return;
         }
 
-        if system_state.dmg_compat_mode() {
+        if system_state.dmg_mode() {
             if self.lcdc.bg_and_window_enabled() {
                 self.render_background_line(system_state);
             } else {
-                // If BG/Window is disabled both BG and Window become white
-                // Reference: https://gbdev.io/pandocs/LCDC.html#non-cgb-mode-dmg-sgb-and-cgb-in-compatibility-mode-bg-and-window-display
-                let palette_spec = &self.color_bg_palettes[0..8];
-                let palette = Palette::new_color(palette_spec);
-
-                for pixel in self.frame.data[self.ly as usize].iter_mut() {
-                    *pixel = palette.color0();
+                let white = self.blank_color(system_state);
+                for pixel in self.frame.row_mut(self.ly as usize).iter_mut() {
+                    *pixel = white;
                 }
             }
 
             // Both BG/Window and Window should be enabled to draw window pixels
             if self.lcdc.bg_and_window_enabled() && self.lcdc.window_enabled() {
-                self.render_window_line();
+                self.render_window_line(system_state);
             }
         } else {
             self.render_background_line(system_state);
 
             if self.lcdc.window_enabled() {
-                self.render_window_line();
+                self.render_window_line(system_state);
             }
         }
 
@@ -269,8 +363,8 @@ impl Ppu {
             let tile_attr = self.vram[vram_index(tile_index_address as u16, 1)];
 
             // If the bootrom is mapped, run in CGB mode regardless of cart
-            let (pixel_color, color_id) = if system_state.dmg_compat_mode() {
-                self.render_dmg_compat_bg(bg_map_x, bg_map_y, tile_id, tileset_address)
+            let (pixel_color, color_id) = if system_state.dmg_mode() {
+                self.render_dmg_bg(system_state, bg_map_x, bg_map_y, tile_id, tileset_address)
             } else {
                 self.render_cgb_bg(bg_map_x, bg_map_y, tile_id, tile_attr, tileset_address)
             };
@@ -279,12 +373,13 @@ impl Ppu {
                 bg_priority: (tile_attr & 0x80) == 0x80,
             };
 
-            self.frame.data[screen_y][screen_x] = pixel_color;
+            self.frame.row_mut(screen_y)[screen_x] = pixel_color;
         }
     }
 
-    fn render_dmg_compat_bg(
+    fn render_dmg_bg(
         &self,
+        system_state: &SystemState,
         bg_map_x: usize,
         bg_map_y: usize,
         tile_id: u8,
@@ -293,9 +388,7 @@ impl Ppu {
         let tile_pixel_x = bg_map_x % TILE_WIDTH_PX;
         let tile_pixel_y = bg_map_y % TILE_HEIGHT_PX;
 
-        // DMG compat games always use the first palette
-        let palette_spec = &self.color_bg_palettes[0..8];
-        let palette = Palette::new_color(palette_spec);
+        let palette = self.bg_palette(system_state, 0x00);
 
         let tiledata_mem_offset = match self.lcdc.bg_and_window_tiledata_area() {
             TiledataAddressingMode::Signed => (tile_id as i8 as i16 + 128) as usize * SIZEOF_TILE,
@@ -338,10 +431,7 @@ impl Ppu {
             bg_map_x % TILE_WIDTH_PX
         };
 
-        let bg_palette_number = tile_attr as usize & 0b111;
-        let palette_spec =
-            &self.color_bg_palettes[(bg_palette_number * 8)..((bg_palette_number + 1) * 8)];
-        let palette = Palette::new_color(palette_spec);
+        let palette = Palette::new_color(self.color_bg_palette(tile_attr & 0b111));
 
         let tiledata_mem_offset = match self.lcdc.bg_and_window_tiledata_area() {
             TiledataAddressingMode::Signed => (tile_id as i8 as i16 + 128) as usize * SIZEOF_TILE,
@@ -362,7 +452,7 @@ impl Ppu {
         (palette.actual_color_from_index(color_id), color_id)
     }
 
-    fn render_window_line(&mut self) {
+    fn render_window_line(&mut self, system_state: &SystemState) {
         let tileset_address = self.lcdc.bg_and_window_tiledata_area() as usize;
         let tilemap_address = self.lcdc.window_tilemap_area() as usize;
 
@@ -388,11 +478,8 @@ impl Ppu {
 
         let screen_y = self.ly as usize;
 
-        for (window_index_x, pixel) in self.frame.data[screen_y][screen_x_start..]
-            .iter_mut()
-            .enumerate()
-        {
-            let window_x = window_x_start + window_index_x;
+        for screen_x in screen_x_start..LCD_WIDTH {
+            let window_x = window_x_start + screen_x - screen_x_start;
 
             let tile_x = window_x / TILE_WIDTH_PX;
             let tile_y = window_y / TILE_HEIGHT_PX;
@@ -405,10 +492,7 @@ impl Ppu {
             let tile_id = self.vram[vram_index(tile_index_address as u16, 0)];
             let tile_attr = self.vram[vram_index(tile_index_address as u16, 1)];
 
-            let window_palette_number = tile_attr as usize & 0b111;
-            let palette_spec = &self.color_bg_palettes
-                [(window_palette_number * 8)..((window_palette_number + 1) * 8)];
-            let palette = Palette::new_color(palette_spec);
+            let palette = self.bg_palette(system_state, tile_attr);
 
             let tiledata_mem_offset = match self.lcdc.bg_and_window_tiledata_area() {
                 TiledataAddressingMode::Signed => {
@@ -426,7 +510,7 @@ impl Ppu {
             let index = 7 - tile_pixel_x as u8;
             let color_id = ((u8::from(pixel_2 & (1 << index) != 0)) << 1)
                 | u8::from(pixel_1 & (1 << index) != 0);
-            *pixel = palette.actual_color_from_index(color_id);
+            self.frame.row_mut(screen_y)[screen_x] = palette.actual_color_from_index(color_id);
         }
     }
 
@@ -470,14 +554,16 @@ impl Ppu {
             let tile_line_data_start_address =
                 sprite_tile_address + (sprite_line_offset as u16 * 2);
 
-            let obj_palette_number = sprite.palette(system_state.dmg_compat_mode()) as usize;
-            let palette_spec =
-                &self.color_obj_palettes[(obj_palette_number * 8)..((obj_palette_number + 1) * 8)];
-            let palette = Palette::new_color(palette_spec);
+            let palette = self.obj_palette(system_state, sprite.palette(system_state.dmg_mode()));
 
-            let pixel_1 = self.vram[vram_index(tile_line_data_start_address, sprite.vram_bank())];
-            let pixel_2 =
-                self.vram[vram_index(tile_line_data_start_address + 1, sprite.vram_bank())];
+            // Sprites can only take their tiles from the second VRAM bank in CGB mode
+            let vram_bank = if system_state.dmg_mode() {
+                0
+            } else {
+                sprite.vram_bank()
+            };
+            let pixel_1 = self.vram[vram_index(tile_line_data_start_address, vram_bank)];
+            let pixel_2 = self.vram[vram_index(tile_line_data_start_address + 1, vram_bank)];
 
             // The sprite is partially hidden on the left
             let (visible_column_start, columns_visible, screen_x_start) = if sprite.x < 8 {
@@ -501,7 +587,7 @@ impl Ppu {
             let sprite_first_index = screen_x_start as usize;
             let sprite_last_index = (screen_x_start + columns_visible) as usize - 1;
 
-            for (i, pixel) in self.frame.data[screen_y][sprite_first_index..=sprite_last_index]
+            for (i, pixel) in self.frame.row_mut(screen_y)[sprite_first_index..=sprite_last_index]
                 .iter_mut()
                 .enumerate()
             {
@@ -525,7 +611,7 @@ impl Ppu {
                     bg_priority,
                 } = self.bg_color_indices[screen_y * LCD_WIDTH + screen_x];
 
-                if system_state.dmg_compat_mode() {
+                if system_state.dmg_mode() {
                     if sprite.bg_window_over_sprite() {
                         if bg_color_index == 0 {
                             *pixel = palette.actual_color_from_index(color_id);
@@ -557,7 +643,7 @@ impl Ppu {
 
         // Sorting by the X coordinate will take care of the first condition for DMG mode where the
         // sprite with the lower X coordinate has higher priority and is drawn over
-        if system_state.dmg_compat_mode() {
+        if system_state.dmg_mode() {
             sprites.sort_by(|sprite1, sprite2| sprite1.x.cmp(&sprite2.x));
         }
 
@@ -586,12 +672,112 @@ impl Ppu {
         sprites
     }
 
+    /// The 4KB the SGB reads for its `_TRN` commands. Games show the data as the first 256 BG
+    /// tiles of the screen, from left to right and top to bottom
+    pub(crate) fn sgb_transfer_data(&self) -> Vec<u8> {
+        let tileset_address = self.lcdc.bg_and_window_tiledata_area() as usize;
+        let tilemap_address = self.lcdc.bg_tilemap_area() as usize;
+        let tiles_per_row = LCD_WIDTH / TILE_WIDTH_PX;
+
+        let mut data = Vec::with_capacity(SGB_TRANSFER_SIZE);
+        for tile in 0..SGB_TRANSFER_SIZE / SIZEOF_TILE {
+            let tile_index = (tile / tiles_per_row) * TILES_PER_LINE + tile % tiles_per_row;
+            let tile_id = self.vram[vram_index((tilemap_address + tile_index) as u16, 0)];
+            let tiledata_mem_offset = match self.lcdc.bg_and_window_tiledata_area() {
+                TiledataAddressingMode::Signed => {
+                    (tile_id as i8 as i16 + 128) as usize * SIZEOF_TILE
+                }
+                TiledataAddressingMode::Unsigned => tile_id as usize * SIZEOF_TILE,
+            };
+
+            let start = tileset_address + tiledata_mem_offset;
+            data.extend(
+                (start..start + SIZEOF_TILE)
+                    .map(|address| self.vram[vram_index(address as u16, 0)]),
+            );
+        }
+
+        data
+    }
+
+    /// Color of the LCD when it is off, which is lighter than any shade on DMG hardware
+    fn lcd_off_color(&self, system_state: &SystemState) -> RGBA {
+        if system_state.model.is_cgb() {
+            rgb555_to_rgba(0x7FFF)
+        } else {
+            RGBA_WHITE
+        }
+    }
+
+    /// Color of the BG and the window when they are disabled in DMG mode
+    fn blank_color(&self, system_state: &SystemState) -> RGBA {
+        // If BG/Window is disabled both BG and Window become white
+        // Reference: https://gbdev.io/pandocs/LCDC.html#non-cgb-mode-dmg-sgb-and-cgb-in-compatibility-mode-bg-and-window-display
+        if system_state.model.is_cgb() {
+            self.bg_palette(system_state, 0x00).color0()
+        } else {
+            RGBA_WHITE
+        }
+    }
+
+    /// The 8 bytes of a CGB BG palette
+    fn color_bg_palette(&self, number: u8) -> &[u8] {
+        let start = number as usize * 8;
+        &self.color_bg_palettes[start..start + 8]
+    }
+
+    /// Palette of a BG or window tile. DMG hardware shows the BGP shades, DMG games on a CGB always
+    /// use the first CGB palette and CGB games pick one from the tile attributes
+    fn bg_palette(&self, system_state: &SystemState, tile_attr: u8) -> Palette {
+        if !system_state.model.is_cgb() {
+            Palette::new_greyscale(self.bgp)
+        } else if system_state.dmg_mode() {
+            Palette::new_color(self.color_bg_palette(0))
+        } else {
+            Palette::new_color(self.color_bg_palette(tile_attr & 0b111))
+        }
+    }
+
+    /// Palette of a sprite, numbered as in `Sprite::palette`. DMG hardware shows the OBP0 or OBP1
+    /// shades
+    fn obj_palette(&self, system_state: &SystemState, number: u8) -> Palette {
+        let number = number as usize;
+        if !system_state.model.is_cgb() {
+            Palette::new_greyscale(if number == 0 { self.obp0 } else { self.obp1 })
+        } else {
+            Palette::new_color(&self.color_obj_palettes[number * 8..(number + 1) * 8])
+        }
+    }
+
+    /// The PPU reads VRAM and the CGB palettes in mode 3. The CPU reads 0xFF from them and its
+    /// writes are ignored
+    fn vram_accessible(&self) -> bool {
+        !self.lcdc.lcd_enabled() || self.stat.mode() != LcdStatus::Rendering
+    }
+
+    /// The PPU reads OAM during the OAM search and mode 3. The CPU reads 0xFF from it and its
+    /// writes are ignored
+    fn oam_accessible(&self) -> bool {
+        !self.lcdc.lcd_enabled()
+            || matches!(self.stat.mode(), LcdStatus::Hblank | LcdStatus::Vblank)
+    }
+
+    /// Write made by the OAM DMA, which has priority over the PPU
+    pub(crate) fn oam_dma_write(&mut self, address: u16, data: u8) {
+        if (OAM_START..=OAM_END).contains(&address) {
+            self.oam[(address - OAM_START) as usize] = data;
+        }
+    }
+
     fn bcp_read(&self) -> u8 {
         self.color_bg_palettes[(self.bcps & 0x3F) as usize]
     }
 
     fn bcp_write(&mut self, data: u8) {
-        self.color_bg_palettes[(self.bcps & 0x3F) as usize] = data;
+        // The write is lost in mode 3, but the index is still incremented
+        if self.vram_accessible() {
+            self.color_bg_palettes[(self.bcps & 0x3F) as usize] = data;
+        }
         if self.bcps & 0x80 != 0 {
             self.bcps += 1;
             self.bcps &= 0xBF;
@@ -603,7 +789,10 @@ impl Ppu {
     }
 
     fn ocp_write(&mut self, data: u8) {
-        self.color_obj_palettes[(self.ocps & 0x3F) as usize] = data;
+        // The write is lost in mode 3, but the index is still incremented
+        if self.vram_accessible() {
+            self.color_obj_palettes[(self.ocps & 0x3F) as usize] = data;
+        }
         if self.ocps & 0x80 != 0 {
             self.ocps += 1;
             self.ocps &= 0xBF;
@@ -614,8 +803,9 @@ impl Ppu {
 impl Memory for Ppu {
     fn read(&mut self, address: u16) -> u8 {
         match address {
-            // TODO: VRAM/OAM disable access to CPU after timings are perfect
+            VRAM_START..=VRAM_END if !self.vram_accessible() => 0xFF,
             VRAM_START..=VRAM_END => self.vram[vram_index(address, self.vram_bank & 0b1)],
+            OAM_START..=OAM_END if !self.oam_accessible() => 0xFF,
             OAM_START..=OAM_END => self.oam[(address - OAM_START) as usize],
             0xFF40 => self.lcdc.0,
             0xFF41 => self.stat.0,
@@ -629,27 +819,41 @@ impl Memory for Ppu {
             0xFF4A => self.wy,
             0xFF4B => self.wx,
             0xFF4F => self.vram_bank as u8,
-            0xFF68 => self.bcps,
-            0xFF69 => self.bcp_read(),
-            0xFF6A => self.ocps,
-            0xFF6B => self.ocp_read(),
+            BCPS => self.bcps,
+            BCPD | OCPD if !self.vram_accessible() => 0xFF,
+            BCPD => self.bcp_read(),
+            OCPS => self.ocps,
+            OCPD => self.ocp_read(),
             _ => 0xFF,
         }
     }
 
     fn write(&mut self, address: u16, data: u8) {
         match address {
+            VRAM_START..=VRAM_END if !self.vram_accessible() => {}
             VRAM_START..=VRAM_END => self.vram[vram_index(address, self.vram_bank & 0b1)] = data,
+            OAM_START..=OAM_END if !self.oam_accessible() => {}
             OAM_START..=OAM_END => self.oam[(address - OAM_START) as usize] = data,
             0xFF40 => {
-                if ((self.lcdc.0 & 0x80) == 0x80) && ((data & 0x80) == 0x00) {
+                let was_enabled = self.lcdc.lcd_enabled();
+                self.lcdc.0 = data;
+                if was_enabled && !self.lcdc.lcd_enabled() {
+                    // LY and the mode are reset without raising interrupts
                     self.stat.set_mode(LcdStatus::Hblank);
                     self.ly = 0x00;
+                    self.dots_in_line = 0;
+                    self.window_internal_counter = None;
+                    self.fifo = None;
+                } else if !was_enabled && self.lcdc.lcd_enabled() {
+                    self.dots_in_line = LCD_ON_SKIPPED_DOTS;
+                    self.stat.set_ly_lyc_state(self.ly == self.lyc);
                 }
-                self.lcdc.0 = data;
             }
             // Ignore bit 7 as it is not used and don't set status or lyc=ly on write
-            0xFF41 => self.stat.0 = ((data & 0x78) | (self.stat.0 & 0x7)) & 0x7F,
+            0xFF41 => {
+                self.stat.0 = ((data & 0x78) | (self.stat.0 & 0x7)) & 0x7F;
+                self.stat_written = true;
+            }
             0xFF42 => self.scy = data,
             0xFF43 => self.scx = data,
             0xFF44 => {}
@@ -663,10 +867,10 @@ impl Memory for Ppu {
             0xFF4A => self.wy = data,
             0xFF4B => self.wx = data,
             0xFF4F => self.vram_bank = 0xFE | (data as usize & 0b1),
-            0xFF68 => self.bcps = data & !(0x1 << 6), // Ignore bit 6
-            0xFF69 => self.bcp_write(data),
-            0xFF6A => self.ocps = data & !(0x1 << 6), // Ignore bit 6
-            0xFF6B => self.ocp_write(data),
+            BCPS => self.bcps = data & !(0x1 << 6), // Ignore bit 6
+            BCPD => self.bcp_write(data),
+            OCPS => self.ocps = data & !(0x1 << 6), // Ignore bit 6
+            OCPD => self.ocp_write(data),
             _ => {}
         }
     }
@@ -732,7 +936,7 @@ enum LcdcFlags {
     BgAndWindowEnabled = 1,
 }
 
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 struct Lcdc(u8);
 
 impl Lcdc {
@@ -804,7 +1008,7 @@ enum LcdStatus {
 const LYC_LY_EQUAL: u8 = 1 << 2;
 const LCD_STAT_MASK: u8 = 0x03; // Bits 1,0
 
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 struct LcdStat(u8);
 
 impl LcdStat {
@@ -851,3 +1055,174 @@ impl LcdStat {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupts::INTERRUPT_FLAG_ADDRESS;
+    use crate::HardwareModel;
+
+    /// Run the PPU for a number of machine cycles in single speed
+    fn run(ppu: &mut Ppu, system_state: &mut SystemState, cycles: u64) -> InterruptHandler {
+        let mut interrupts = InterruptHandler::default();
+        for _ in 0..cycles {
+            ppu.tick(system_state, &mut interrupts);
+        }
+        interrupts
+    }
+
+    /// Machine cycles from turning the LCD on to the start of a line
+    fn cycles_to_line(ly: u64) -> u64 {
+        (ly * SCANLINE_DOTS - LCD_ON_SKIPPED_DOTS) / 4
+    }
+
+    #[test]
+    fn test_stat_interrupt_is_blocked_while_the_line_is_high() {
+        // H-Blank and LY = LYC sources
+        for (lyc, hblank_interrupt) in [(1, false), (2, true)] {
+            let mut system_state = SystemState::default();
+            let mut ppu = Ppu::new();
+            ppu.write(0xFF40, 0x91);
+            ppu.write(0xFF41, 0x48);
+            ppu.write(0xFF45, lyc);
+
+            run(&mut ppu, &mut system_state, cycles_to_line(1));
+            // LY is compared with LYC 4 dots into the line
+            let interrupts = run(&mut ppu, &mut system_state, 1);
+            assert_eq!(interrupts.is_requested(InterruptType::LcdStat), lyc == 1);
+
+            // Mode 3 is over well before the end of the line
+            let interrupts = run(&mut ppu, &mut system_state, 100);
+            assert_eq!(ppu.read(0xFF41) & LCD_STAT_MASK, LcdStatus::Hblank as u8);
+            assert_eq!(
+                interrupts.is_requested(InterruptType::LcdStat),
+                hblank_interrupt
+            );
+        }
+    }
+
+    #[test]
+    fn test_mode_2_interrupt_on_line_144() {
+        let mut system_state = SystemState::default();
+        let mut ppu = Ppu::new();
+        ppu.write(0xFF40, 0x91);
+        ppu.write(0xFF41, 0x20);
+
+        run(
+            &mut ppu,
+            &mut system_state,
+            cycles_to_line(LCD_HEIGHT as u64) - 1,
+        );
+        let interrupts = run(&mut ppu, &mut system_state, 1);
+        assert_eq!(ppu.read(0xFF44), LCD_HEIGHT as u8);
+        assert!(interrupts.is_requested(InterruptType::LcdStat));
+    }
+
+    #[test]
+    fn test_ly_reads_0_for_most_of_line_153() {
+        let mut system_state = SystemState::default();
+        let mut ppu = Ppu::new();
+        ppu.write(0xFF40, 0x91);
+        ppu.write(0xFF45, 0x00);
+
+        run(&mut ppu, &mut system_state, cycles_to_line(153) + 1);
+        assert_eq!(ppu.read(0xFF44), 153);
+        assert_eq!(ppu.read(0xFF41) & LYC_LY_EQUAL, 0);
+
+        // LY reads 0 after 8 dots, and matches LYC = 0 4 dots later
+        run(&mut ppu, &mut system_state, 1);
+        assert_eq!(ppu.read(0xFF44), 0);
+        assert_eq!(ppu.read(0xFF41) & LYC_LY_EQUAL, 0);
+        run(&mut ppu, &mut system_state, 1);
+        assert_eq!(ppu.read(0xFF41) & LYC_LY_EQUAL, LYC_LY_EQUAL);
+        assert_eq!(ppu.read(0xFF41) & LCD_STAT_MASK, LcdStatus::Vblank as u8);
+    }
+
+    #[test]
+    fn test_stat_write_requests_an_interrupt_on_dmg() {
+        for (model, interrupt) in [(HardwareModel::Dmg, true), (HardwareModel::Cgb, false)] {
+            let mut system_state = SystemState {
+                model,
+                ..Default::default()
+            };
+            let mut ppu = Ppu::new();
+            ppu.write(0xFF40, 0x91);
+            run(&mut ppu, &mut system_state, 70);
+            assert_eq!(ppu.read(0xFF41) & LCD_STAT_MASK, LcdStatus::Hblank as u8);
+
+            ppu.write(0xFF41, 0x00);
+            let interrupts = run(&mut ppu, &mut system_state, 1);
+            assert_eq!(interrupts.is_requested(InterruptType::LcdStat), interrupt);
+        }
+    }
+
+    #[test]
+    fn test_lcd_off_resets_ly_and_mode_without_interrupts() {
+        let mut system_state = SystemState::default();
+        let mut ppu = Ppu::new();
+        ppu.write(0xFF40, 0x91);
+        // STAT interrupts on every mode and on LY = LYC = 0
+        ppu.write(0xFF41, 0x78);
+        run(&mut ppu, &mut system_state, 5 * SCANLINE_DOTS / 4 + 10);
+        assert_eq!(ppu.read(0xFF44), 5);
+
+        ppu.write(0xFF40, 0x11);
+        assert_eq!(ppu.read(0xFF44), 0);
+        assert_eq!(ppu.read(0xFF41) & LCD_STAT_MASK, LcdStatus::Hblank as u8);
+
+        let mut interrupts = run(&mut ppu, &mut system_state, 2 * SCANLINE_DOTS);
+        assert_eq!(interrupts.read(INTERRUPT_FLAG_ADDRESS), 0x00);
+        assert_eq!(ppu.read(0xFF44), 0);
+    }
+
+    #[test]
+    fn test_first_line_after_lcd_on_has_no_oam_search() {
+        let mut system_state = SystemState::default();
+        let mut ppu = Ppu::new();
+        ppu.write(0xFF40, 0x91);
+        ppu.write(0xFF40, 0x11);
+        ppu.write(0xFF40, 0x91);
+
+        let cycles_to_mode_3 = (OAM_SEARCH_DOTS - LCD_ON_SKIPPED_DOTS) / 4;
+        run(&mut ppu, &mut system_state, cycles_to_mode_3 - 1);
+        assert_eq!(ppu.read(0xFF41) & LCD_STAT_MASK, LcdStatus::Hblank as u8);
+        run(&mut ppu, &mut system_state, 1);
+        assert_eq!(ppu.read(0xFF41) & LCD_STAT_MASK, LcdStatus::Rendering as u8);
+
+        // The line is 4 dots shorter
+        let cycles_to_ly_1 = (SCANLINE_DOTS - LCD_ON_SKIPPED_DOTS) / 4;
+        run(
+            &mut ppu,
+            &mut system_state,
+            cycles_to_ly_1 - cycles_to_mode_3 - 1,
+        );
+        assert_eq!(ppu.read(0xFF44), 0);
+        run(&mut ppu, &mut system_state, 1);
+        assert_eq!(ppu.read(0xFF44), 1);
+        assert_eq!(ppu.read(0xFF41) & LCD_STAT_MASK, LcdStatus::OamSearch as u8);
+    }
+
+    #[test]
+    fn test_first_frame_after_lcd_on_is_blank() {
+        let mut system_state = SystemState {
+            model: HardwareModel::Dmg,
+            ..Default::default()
+        };
+        let frame_cycles = SCANLINE_DOTS * TOTAL_SCANLINES as u64 / 4;
+        let mut ppu = Ppu::new();
+        // VRAM is filled with 0xFF, so the BG is black
+        ppu.write(0xFF47, 0xFF);
+        run(&mut ppu, &mut system_state, 1);
+
+        ppu.write(0xFF40, 0x91);
+        run(&mut ppu, &mut system_state, frame_cycles);
+        assert_eq!(ppu.frame().row(0)[0].full(), RGBA_WHITE.full());
+        run(&mut ppu, &mut system_state, frame_cycles);
+        assert_ne!(ppu.frame().row(0)[0].full(), RGBA_WHITE.full());
+
+        // The frame is blank again as soon as the LCD is turned off
+        ppu.write(0xFF40, 0x11);
+        run(&mut ppu, &mut system_state, 1);
+        assert_eq!(ppu.frame().row(100)[80].full(), RGBA_WHITE.full());
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    vram_index, Ppu, Sprite, TiledataAddressingMode, LCD_WIDTH, SIZEOF_TILE, TILES_PER_LINE,
+    TILE_HEIGHT_PX, TILE_WIDTH_PX,
+};
+use crate::textures::RGBA;
+use crate::SystemState;
+
+/// Dots at the start of mode 3 spent on a first tile fetch that is thrown away
+const STARTUP_DOTS: u8 = 6;
+/// Dots the fetcher takes to read a tile number and the two bytes of a tile row
+const TILE_FETCH_DOTS: u8 = 6;
+/// Dots the fetcher takes to read the tile row of a sprite
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+/// BG or window pixel waiting to be shifted out
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct BgPixel {
+    color_index: u8,
+    // CGB BG map attributes of the tile
+    attrs: u8,
+}
+
+/// Sprite pixel waiting to be mixed with the BG pixel at the same position
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ObjPixel {
+    color_index: u8,
+    palette: u8,
+    bg_window_over_sprite: bool,
+    oam_index: u8,
+}
+
+/// State of mode 3 while the PPU draws the line one pixel per dot. A tile row of the BG or the
+/// window is fetched every 8 pixels. Sprites stop the pixel output while their tile row is
+/// fetched, so each sprite on the line makes mode 3 longer, and so do the SCX fine scroll and
+/// the window
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(super) struct PixelFifo {
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<ObjPixel>,
+
+    // Dots into the current tile fetch. The tile row is pushed once the BG FIFO is empty
+    fetcher_dots: u8,
+    // Tile column of the next fetch, from the left of the screen or of the window
+    fetcher_x: u8,
+    tile_id: u8,
+    tile_attrs: u8,
+    tile_low: u8,
+    tile_high: u8,
+
+    startup_dots: u8,
+    /// Next column of the LCD to draw
+    lx: u8,
+    /// Pixels thrown away before the next one is drawn. Set by the SCX fine scroll at the start of
+    /// the line, and by a window partially left of the screen
+    discard: u8,
+    /// Line of the window being drawn, once the window started on this line
+    window_y: Option<u8>,
+    /// OAM index of the sprites on the line that are yet to be fetched, by increasing X
+    sprites: Vec<u8>,
+    /// Remaining dots and OAM index of the sprite being fetched
+    sprite_fetch: Option<(u8, u8)>,
+}
+
+impl PixelFifo {
+    /// All the pixels of the line were drawn, mode 3 is over
+    pub(super) fn line_done(&self) -> bool {
+        self.lx as usize == LCD_WIDTH
+    }
+}
+
+impl Ppu {
+    /// Start of mode 3 with the sprites the OAM search found on the line
+    pub(super) fn new_fifo(&self) -> PixelFifo {
+        // Sprites with X = 0 are entirely left of the screen and never fetched
+        let mut sprites: Vec<u8> = self
+            .sprites_on_ly()
+            .into_iter()
+            .map(|index| index as u8)
+            .filter(|&index| self.oam_x(index) != 0)
+            .collect();
+        // The sort is stable, so sprites at the same X stay in OAM order
+        sprites.sort_by_key(|&index| self.oam_x(index));
+
+        PixelFifo {
+            startup_dots: STARTUP_DOTS,
+            discard: self.scx % TILE_WIDTH_PX as u8,
+            sprites,
+            ..Default::default()
+        }
+    }
+
+    /// Run mode 3 for one dot. At most one pixel is drawn
+    pub(super) fn step_fifo(&mut self, fifo: &mut PixelFifo, system_state: &SystemState) {
+        if fifo.startup_dots > 0 {
+            fifo.startup_dots -= 1;
+            return;
+        }
+
+        if let Some((dots, oam_index)) = fifo.sprite_fetch {
+            if dots > 1 {
+                fifo.sprite_fetch = Some((dots - 1, oam_index));
+            } else {
+                fifo.sprite_fetch = None;
+                self.fetch_sprite(fifo, system_state, oam_index);
+            }
+            return;
+        }
+
+        if fifo.discard == 0 && fifo.window_y.is_none() && self.window_starts_at(fifo, system_state)
+        {
+            self.start_window(fifo);
+        }
+
+        self.step_fetcher(fifo, system_state);
+
+        if fifo.discard == 0 && self.lcdc.sprites_enabled() {
+            // Sprites whose position was passed while sprites were disabled are not drawn
+            while let Some(&oam_index) = fifo.sprites.first() {
+                if self.sprite_start_x(oam_index) >= fifo.lx {
+                    break;
+                }
+                fifo.sprites.remove(0);
+            }
+
+            if let Some(&oam_index) = fifo.sprites.first() {
+                if self.sprite_start_x(oam_index) == fifo.lx {
+                    // No pixel is drawn until the sprite is fetched. The fetch starts once the BG
+                    // FIFO has pixels and takes over the last dot of the current tile fetch
+                    if fifo.fetcher_dots >= TILE_FETCH_DOTS - 1 && !fifo.bg.is_empty() {
+                        fifo.sprites.remove(0);
+                        // This dot is the first one of the fetch
+                        fifo.sprite_fetch = Some((SPRITE_FETCH_DOTS - 1, oam_index));
+                    }
+                    return;
+                }
+            }
+        }
+
+        if let Some(bg) = fifo.bg.pop_front() {
+            let obj = fifo.obj.pop_front();
+            if fifo.discard > 0 {
+                fifo.discard -= 1;
+            } else {
+                if !self.frame_blank {
+                    let color = self.mix_pixel(system_state, bg, obj);
+                    self.frame.row_mut(self.ly as usize)[fifo.lx as usize] = color;
+                }
+                fifo.lx += 1;
+            }
+        }
+    }
+
+    fn oam_x(&self, oam_index: u8) -> u8 {
+        self.oam[oam_index as usize * 4 + 1]
+    }
+
+    /// Column of the LCD at which the sprite is fetched. Sprites partially left of the screen are
+    /// fetched at the first column
+    fn sprite_start_x(&self, oam_index: u8) -> u8 {
+        self.oam_x(oam_index).saturating_sub(TILE_WIDTH_PX as u8)
+    }
+
+    fn window_starts_at(&self, fifo: &PixelFifo, system_state: &SystemState) -> bool {
+        // Both BG/Window and Window should be enabled to draw window pixels in DMG mode
+        let enabled = self.lcdc.window_enabled()
+            && (!system_state.dmg_mode() || self.lcdc.bg_and_window_enabled());
+
+        enabled
+            && self.ly >= self.wy
+            && (self.wx as usize) < LCD_WIDTH + 7
+            && fifo.lx == self.wx.saturating_sub(7)
+    }
+
+    /// Throw away the BG pixels and fetch the window from its first tile
+    fn start_window(&mut self, fifo: &mut PixelFifo) {
+        // This is the value of the internal Window counter in the Game boy hardware for this LY
+        let window_y = self.window_internal_counter.unwrap_or(0);
+        self.window_internal_counter = Some(window_y + 1);
+
+        fifo.window_y = Some(window_y);
+        fifo.bg.clear();
+        fifo.fetcher_x = 0;
+        fifo.fetcher_dots = 0;
+        // The columns of a window placed at WX < 7 are left of the screen
+        fifo.discard = 7u8.saturating_sub(self.wx);
+    }
+
+    fn step_fetcher(&mut self, fifo: &mut PixelFifo, system_state: &SystemState) {
+        if fifo.fetcher_dots < TILE_FETCH_DOTS {
+            fifo.fetcher_dots += 1;
+            match fifo.fetcher_dots {
+                2 => self.fetch_tile_id(fifo, system_state),
+                4 => fifo.tile_low = self.vram[self.tile_row_index(fifo)],
+                6 => fifo.tile_high = self.vram[self.tile_row_index(fifo) + 1],
+                _ => {}
+            }
+        } else if fifo.bg.is_empty() {
+            for column in 0..TILE_WIDTH_PX as u8 {
+                // Horizontal flip
+                let bit = if fifo.tile_attrs & 0x20 != 0 {
+                    column
+                } else {
+                    7 - column
+                };
+                fifo.bg.push_back(BgPixel {
+                    color_index: color_index(fifo.tile_low, fifo.tile_high, bit),
+                    attrs: fifo.tile_attrs,
+                });
+            }
+            fifo.fetcher_x = fifo.fetcher_x.wrapping_add(1);
+            fifo.fetcher_dots = 0;
+        }
+    }
+
+    /// Line of the BG map or of the window the fetcher reads from
+    fn fetcher_y(&self, fifo: &PixelFifo) -> usize {
+        match fifo.window_y {
+            Some(window_y) => window_y as usize,
+            None => self.ly.wrapping_add(self.scy) as usize,
+        }
+    }
+
+    fn fetch_tile_id(&self, fifo: &mut PixelFifo, system_state: &SystemState) {
+        let (tilemap_address, tile_x) = match fifo.window_y {
+            Some(_) => (self.lcdc.window_tilemap_area() as usize, fifo.fetcher_x),
+            None => (
+                self.lcdc.bg_tilemap_area() as usize,
+                (self.scx / TILE_WIDTH_PX as u8).wrapping_add(fifo.fetcher_x),
+            ),
+        };
+
+        let tile_y = self.fetcher_y(fifo) / TILE_HEIGHT_PX;
+        let tile_index = tile_y * TILES_PER_LINE + tile_x as usize % TILES_PER_LINE;
+        let tile_index_address = (tilemap_address + tile_index) as u16;
+
+        fifo.tile_id = self.vram[vram_index(tile_index_address, 0)];
+        fifo.tile_attrs = if system_state.dmg_mode() {
+            0x00
+        } else {
+            self.vram[vram_index(tile_index_address, 1)]
+        };
+    }
+
+    /// Index in VRAM of the first byte of the tile row being fetched
+    fn tile_row_index(&self, fifo: &PixelFifo) -> usize {
+        // Vertical flip
+        let tile_pixel_y = if fifo.tile_attrs & 0x40 != 0 {
+            TILE_HEIGHT_PX - (self.fetcher_y(fifo) % TILE_HEIGHT_PX) - 1
+        } else {
+            self.fetcher_y(fifo) % TILE_HEIGHT_PX
+        };
+
+        let tileset_address = self.lcdc.bg_and_window_tiledata_area() as usize;
+        let tiledata_mem_offset = match self.lcdc.bg_and_window_tiledata_area() {
+            TiledataAddressingMode::Signed => {
+                (fifo.tile_id as i8 as i16 + 128) as usize * SIZEOF_TILE
+            }
+            TiledataAddressingMode::Unsigned => fifo.tile_id as usize * SIZEOF_TILE,
+        };
+        let address = tileset_address + tiledata_mem_offset + tile_pixel_y * 2;
+
+        let tile_data_vram_bank = ((fifo.tile_attrs & 8) >> 3) as usize;
+        vram_index(address as u16, tile_data_vram_bank)
+    }
+
+    /// Read the tile row of a sprite and merge it into the sprite FIFO
+    fn fetch_sprite(&self, fifo: &mut PixelFifo, system_state: &SystemState, oam_index: u8) {
+        let entry = &self.oam[oam_index as usize * 4..oam_index as usize * 4 + 4];
+        let sprite = Sprite::new(entry[0], entry[1], entry[2], entry[3]);
+        let sprite_height = self.lcdc.sprite_height() as u8;
+
+        // Sprites always use the 0x8000 unsigned addressing mode
+        let sprite_tile_address = match self.lcdc.sprite_height() {
+            super::SpriteHeight::Short => sprite.tile_index,
+            // Bit-0 of tile-index should be ignored for tall sprites
+            super::SpriteHeight::Tall => sprite.tile_index & 0xFE,
+        } as u16
+            * SIZEOF_TILE as u16
+            + TiledataAddressingMode::Unsigned as u16;
+
+        // We offset LY by 16 to ease the following calculations and prevent overflow checks
+        let sprite_line = (self.ly + 16).wrapping_sub(sprite.y) & (sprite_height - 1);
+        let sprite_line_offset = if sprite.flip_y() {
+            sprite_height - sprite_line - 1
+        } else {
+            sprite_line
+        };
+        let tile_line_data_start_address = sprite_tile_address + sprite_line_offset as u16 * 2;
+
+        // Sprites can only take their tiles from the second VRAM bank in CGB mode
+        let vram_bank = if system_state.dmg_mode() {
+            0
+        } else {
+            sprite.vram_bank()
+        };
+        let low = self.vram[vram_index(tile_line_data_start_address, vram_bank)];
+        let high = self.vram[vram_index(tile_line_data_start_address + 1, vram_bank)];
+
+        // The columns of a sprite partially left of the screen are skipped
+        let hidden_columns = (TILE_WIDTH_PX as u8).saturating_sub(sprite.x);
+        for column in hidden_columns..TILE_WIDTH_PX as u8 {
+            let bit = if sprite.flip_x() { column } else { 7 - column };
+            let pixel = ObjPixel {
+                color_index: color_index(low, high, bit),
+                palette: sprite.palette(system_state.dmg_mode()),
+                bg_window_over_sprite: sprite.bg_window_over_sprite(),
+                oam_index,
+            };
+
+            // On DMG a pixel already in the FIFO comes from a sprite with a smaller X, or an equal
+            // X and an earlier OAM entry, so it is kept. On CGB only the OAM order matters
+            let slot = (column - hidden_columns) as usize;
+            match fifo.obj.get_mut(slot) {
+                Some(existing) => {
+                    if pixel.color_index != 0
+                        && (existing.color_index == 0
+                            || (!system_state.dmg_mode() && oam_index < existing.oam_index))
+                    {
+                        *existing = pixel;
+                    }
+                }
+                None => fifo.obj.push_back(pixel),
+            }
+        }
+    }
+
+    /// Color shown for a BG pixel and the sprite pixel at the same position
+    fn mix_pixel(&self, system_state: &SystemState, bg: BgPixel, obj: Option<ObjPixel>) -> RGBA {
+        let bg_enabled = self.lcdc.bg_and_window_enabled();
+        let (bg_color_index, bg_color) = if system_state.dmg_mode() && !bg_enabled {
+            (0, self.blank_color(system_state))
+        } else {
+            let palette = self.bg_palette(system_state, bg.attrs);
+            (
+                bg.color_index,
+                palette.actual_color_from_index(bg.color_index),
+            )
+        };
+
+        let obj = match obj {
+            // Color ID 00 is transparent for sprites
+            Some(obj) if obj.color_index != 0 && self.lcdc.sprites_enabled() => obj,
+            _ => return bg_color,
+        };
+
+        let obj_visible = if system_state.dmg_mode() {
+            !obj.bg_window_over_sprite || bg_color_index == 0
+        } else {
+            // If the BG color index is 0 or LCDC bit 0 is clear, the OBJ always has priority.
+            // Otherwise both the BG attributes and the OAM attributes need bit 7 clear
+            let bg_priority = bg.attrs & 0x80 != 0;
+            bg_color_index == 0 || !bg_enabled || (!bg_priority && !obj.bg_window_over_sprite)
+        };
+
+        if obj_visible {
+            self.obj_palette(system_state, obj.palette)
+                .actual_color_from_index(obj.color_index)
+        } else {
+            bg_color
+        }
+    }
+}
+
+fn color_index(low: u8, high: u8, bit: u8) -> u8 {
+    (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use crate::palettes::RGBA_WHITE;
+    use crate::HardwareModel;
+
+    /// LCD and BG on, tile data at 0x8000, tile map at 0x9800
+    const LCDC: u8 = 0x91;
+
+    fn dmg_state() -> SystemState {
+        SystemState {
+            model: HardwareModel::Dmg,
+            ..Default::default()
+        }
+    }
+
+    /// Run mode 3 of LY 0 and count its dots
+    fn mode_3_dots(ppu: &mut Ppu, system_state: &SystemState) -> usize {
+        let mut fifo = ppu.new_fifo();
+        let mut dots = 0;
+        while !fifo.line_done() {
+            ppu.step_fifo(&mut fifo, system_state);
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn test_mode_3_length_follows_fine_scroll() {
+        let system_state = dmg_state();
+        let mut ppu = Ppu::new();
+        ppu.write(0xFF40, LCDC);
+        assert_eq!(mode_3_dots(&mut ppu, &system_state), 172);
+
+        ppu.write(0xFF43, 0x0B);
+        assert_eq!(mode_3_dots(&mut ppu, &system_state), 175);
+    }
+
+    #[test]
+    fn test_sprites_and_window_lengthen_mode_3() {
+        let system_state = dmg_state();
+        let mut ppu = Ppu::new();
+        // One sprite on LY 0 and the others below the screen. OAM is written with the LCD off
+        for address in (0xFE00..=0xFE9F).step_by(4) {
+            ppu.write(address, 0xA0);
+        }
+        ppu.write(0xFE00, 16);
+        ppu.write(0xFE01, 40);
+        ppu.write(0xFF40, LCDC | 0x02);
+        let with_sprite = mode_3_dots(&mut ppu, &system_state);
+        assert!((172 + 6..=172 + 11).contains(&with_sprite));
+
+        ppu.write(0xFF40, LCDC | 0x02 | 0x20);
+        ppu.write(0xFF4B, 7 + 80);
+        assert!(mode_3_dots(&mut ppu, &system_state) > with_sprite);
+    }
+
+    #[test]
+    fn test_mid_scanline_palette_write_is_visible() {
+        let system_state = dmg_state();
+        let mut ppu = Ppu::new();
+        // VRAM is filled with 0xFF, so every BG pixel has color index 3
+        ppu.write(0xFF40, LCDC);
+        ppu.write(0xFF47, 0xFF);
+
+        let mut fifo = ppu.new_fifo();
+        while fifo.lx < 80 {
+            ppu.step_fifo(&mut fifo, &system_state);
+        }
+        ppu.write(0xFF47, 0x00);
+        while !fifo.line_done() {
+            ppu.step_fifo(&mut fifo, &system_state);
+        }
+
+        let row = ppu.frame().row(0);
+        assert_ne!(row[0].full(), RGBA_WHITE.full());
+        assert_eq!(row[79].full(), row[0].full());
+        assert_eq!(row[80].full(), RGBA_WHITE.full());
+        assert_eq!(row[159].full(), RGBA_WHITE.full());
+    }
+}
@@ -0,0 +1,70 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::cartridge::CartridgeHeader;
+
+/// Every save state starts with these bytes
+pub const SAVE_STATE_MAGIC: [u8; 4] = *b"GIBI";
+/// Bumped whenever the layout of any of the serialized components changes. States with a
+/// different version are rejected instead of being loaded into a garbled machine
+pub const SAVE_STATE_VERSION: u32 = 13;
+
+#[derive(Error, Debug)]
+pub enum SaveStateError {
+    #[error("Not a save state")]
+    InvalidMagic,
+    #[error("Unsupported save state version '{0}' (expected '{SAVE_STATE_VERSION}')")]
+    UnsupportedVersion(u32),
+    #[error("Save state was made with a different cartridge: '{0}'")]
+    CartridgeMismatch(String),
+    #[error("Invalid save state data. Error: '{0}'")]
+    Encoding(#[from] bincode::Error),
+}
+
+/// Written before the machine state. Identifies the format and the cartridge the state belongs to.
+/// The ROM itself is not part of the state
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct SaveStateHeader {
+    magic: [u8; 4],
+    version: u32,
+    title: String,
+    global_checksum: u16,
+}
+
+impl SaveStateHeader {
+    pub(crate) fn new(cart_header: &CartridgeHeader) -> Self {
+        Self {
+            magic: SAVE_STATE_MAGIC,
+            version: SAVE_STATE_VERSION,
+            title: cart_header.title.clone(),
+            global_checksum: cart_header.global_checksum,
+        }
+    }
+
+    pub(crate) fn validate(&self, cart_header: &CartridgeHeader) -> Result<(), SaveStateError> {
+        if self.magic != SAVE_STATE_MAGIC {
+            return Err(SaveStateError::InvalidMagic);
+        }
+
+        if self.version != SAVE_STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(self.version));
+        }
+
+        if self.title != cart_header.title || self.global_checksum != cart_header.global_checksum {
+            return Err(SaveStateError::CartridgeMismatch(self.title.clone()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Serialize a component in the save state binary format
+pub(crate) fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, SaveStateError> {
+    Ok(bincode::serialize(value)?)
+}
+
+/// Deserialize a component from the save state binary format
+pub(crate) fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, SaveStateError> {
+    Ok(bincode::deserialize(bytes)?)
+}
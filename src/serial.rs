@@ -1,20 +1,226 @@
+use serde::{Deserialize, Serialize};
+
+use crate::interrupts::{InterruptHandler, InterruptType};
 use crate::memory::Memory;
 
 pub const SERIAL_START: u16 = 0xFF01;
 pub const SERIAL_END: u16 = 0xFF02;
 
-pub(crate) struct Serial {}
+const SB_ADDRESS: u16 = 0xFF01;
+const SC_ADDRESS: u16 = 0xFF02;
+
+/// Machine cycles per bit with the internal clock at 8192Hz. The serial clock follows the CPU
+/// clock, so double speed transfers twice as fast
+const INTERNAL_CLOCK_CYCLES: u32 = 128;
+/// Machine cycles per bit with the CGB fast clock at 262144Hz
+const FAST_CLOCK_CYCLES: u32 = 4;
+
+/// Whatever is on the other end of the link cable. Bits are exchanged most significant bit first
+pub trait LinkPeer: Send {
+    /// Exchange a bit on a pulse of the internal clock. Returns the bit the peer sends back
+    fn exchange_bit(&mut self, outgoing: bool) -> bool;
+
+    /// Polled every machine cycle while a transfer waits on the external clock. Returns the bit
+    /// the peer sends when it pulses the clock
+    fn external_clock(&mut self, outgoing: bool) -> Option<bool>;
+}
+
+/// Nothing connected. The input line is pulled high, so 0xFF is shifted in, and the external
+/// clock never pulses
+#[derive(Default)]
+pub struct Disconnected;
+
+impl LinkPeer for Disconnected {
+    fn exchange_bit(&mut self, _outgoing: bool) -> bool {
+        true
+    }
+
+    fn external_clock(&mut self, _outgoing: bool) -> Option<bool> {
+        None
+    }
+}
+
+fn disconnected() -> Box<dyn LinkPeer> {
+    Box::new(Disconnected)
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Serial {
+    sb: u8,
+    sc: u8,
+    /// The fast clock bit of SC only exists on the CGB
+    cgb: bool,
+    /// Machine cycles since the last bit with the internal clock
+    cycles: u32,
+    /// Bits shifted in the current transfer
+    bits: u8,
+    // The peer is not part of the machine state
+    #[serde(skip, default = "disconnected")]
+    peer: Box<dyn LinkPeer>,
+}
 
 impl Serial {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(cgb: bool) -> Self {
+        Self {
+            sb: 0x00,
+            sc: 0x00,
+            cgb,
+            cycles: 0,
+            bits: 0,
+            peer: disconnected(),
+        }
+    }
+
+    pub(crate) fn set_peer(&mut self, peer: Box<dyn LinkPeer>) {
+        self.peer = peer;
+    }
+
+    pub(crate) fn take_peer(&mut self) -> Box<dyn LinkPeer> {
+        std::mem::replace(&mut self.peer, disconnected())
+    }
+
+    fn bit_cycles(&self) -> u32 {
+        if self.sc & 0b10 != 0 {
+            FAST_CLOCK_CYCLES
+        } else {
+            INTERNAL_CLOCK_CYCLES
+        }
+    }
+
+    pub fn tick(&mut self, interrupts: &mut InterruptHandler) {
+        if self.sc & 0x80 == 0 {
+            return;
+        }
+
+        let outgoing = self.sb & 0x80 != 0;
+        let incoming = if self.sc & 0b1 != 0 {
+            self.cycles += 1;
+            if self.cycles < self.bit_cycles() {
+                return;
+            }
+            self.cycles = 0;
+            self.peer.exchange_bit(outgoing)
+        } else {
+            match self.peer.external_clock(outgoing) {
+                Some(incoming) => incoming,
+                None => return,
+            }
+        };
+
+        self.sb = (self.sb << 1) | u8::from(incoming);
+        self.bits += 1;
+        if self.bits == 8 {
+            // Transfer complete
+            self.sc &= 0x7F;
+            interrupts.request_interrupt(InterruptType::Serial);
+        }
     }
 }
 
 impl Memory for Serial {
-    fn read(&mut self, _address: u16) -> u8 {
-        0xFF
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            SB_ADDRESS => self.sb,
+            // Unused bits read as 1
+            SC_ADDRESS if self.cgb => self.sc | 0x7C,
+            SC_ADDRESS => self.sc | 0x7E,
+            _ => panic!("Invalid address {:#06X} to Serial::read", address),
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        match address {
+            SB_ADDRESS => self.sb = data,
+            SC_ADDRESS => {
+                self.sc = data & if self.cgb { 0x83 } else { 0x81 };
+                if self.sc & 0x80 != 0 {
+                    self.cycles = 0;
+                    self.bits = 0;
+                }
+            }
+            _ => panic!("Invalid address {:#06X} to Serial::write", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(serial: &mut Serial, interrupts: &mut InterruptHandler, cycles: u32) {
+        for _ in 0..cycles {
+            serial.tick(interrupts);
+        }
+    }
+
+    /// Peer sending a byte
+    struct Sender {
+        outgoing: u8,
     }
 
-    fn write(&mut self, _address: u16, _data: u8) {}
+    impl LinkPeer for Sender {
+        fn exchange_bit(&mut self, _outgoing: bool) -> bool {
+            let bit = self.outgoing & 0x80 != 0;
+            self.outgoing <<= 1;
+            bit
+        }
+
+        fn external_clock(&mut self, outgoing: bool) -> Option<bool> {
+            Some(self.exchange_bit(outgoing))
+        }
+    }
+
+    #[test]
+    fn test_internal_clock_transfer_with_nothing_connected() {
+        let mut interrupts = InterruptHandler::default();
+        let mut serial = Serial::new(false);
+        serial.write(SB_ADDRESS, 0x5A);
+        serial.write(SC_ADDRESS, 0x81);
+        assert_eq!(serial.read(SC_ADDRESS), 0xFF);
+
+        run(&mut serial, &mut interrupts, 8 * INTERNAL_CLOCK_CYCLES - 1);
+        assert!(!interrupts.is_requested(InterruptType::Serial));
+        assert_eq!(serial.read(SB_ADDRESS), 0x7F);
+
+        run(&mut serial, &mut interrupts, 1);
+        assert!(interrupts.is_requested(InterruptType::Serial));
+        assert_eq!(serial.read(SB_ADDRESS), 0xFF);
+        assert_eq!(serial.read(SC_ADDRESS), 0x7F);
+    }
+
+    #[test]
+    fn test_cgb_fast_clock() {
+        let mut interrupts = InterruptHandler::default();
+        let mut serial = Serial::new(true);
+        serial.set_peer(Box::new(Sender { outgoing: 0x3C }));
+        serial.write(SB_ADDRESS, 0xA5);
+        serial.write(SC_ADDRESS, 0x83);
+        assert_eq!(serial.read(SC_ADDRESS), 0xFF);
+
+        run(&mut serial, &mut interrupts, 8 * FAST_CLOCK_CYCLES);
+        assert!(interrupts.is_requested(InterruptType::Serial));
+        assert_eq!(serial.read(SB_ADDRESS), 0x3C);
+        assert_eq!(serial.read(SC_ADDRESS), 0x7F);
+
+        // The DMG has no fast clock
+        let mut serial = Serial::new(false);
+        serial.write(SC_ADDRESS, 0x83);
+        run(&mut serial, &mut interrupts, 8 * FAST_CLOCK_CYCLES);
+        assert_eq!(serial.read(SC_ADDRESS), 0xFF);
+    }
+
+    #[test]
+    fn test_external_clock_waits_for_the_peer() {
+        let mut interrupts = InterruptHandler::default();
+        let mut serial = Serial::new(false);
+        serial.write(SC_ADDRESS, 0x80);
+        run(&mut serial, &mut interrupts, 8 * INTERNAL_CLOCK_CYCLES);
+        assert!(!interrupts.is_requested(InterruptType::Serial));
+        assert_eq!(serial.read(SC_ADDRESS), 0xFE);
+
+        serial.set_peer(Box::new(Sender { outgoing: 0x81 }));
+        run(&mut serial, &mut interrupts, 8);
+        assert!(interrupts.is_requested(InterruptType::Serial));
+        assert_eq!(serial.read(SB_ADDRESS), 0x81);
+    }
 }
@@ -0,0 +1,489 @@
+use serde::{Deserialize, Serialize};
+
+use crate::palettes::{dmg_shade, rgb555_to_rgba};
+use crate::ppu::{Ppu, LCD_HEIGHT, LCD_WIDTH};
+use crate::textures::Texture;
+use crate::GameFrame;
+
+/// Size of the picture the Super Game Boy sends to the TV, border included
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+/// Position of the Game Boy picture inside the border
+const GAME_X: usize = 48;
+const GAME_Y: usize = 40;
+
+/// The Game Boy picture is colored in cells of 8x8 pixels
+const CELLS_X: usize = LCD_WIDTH / 8;
+const CELLS_Y: usize = LCD_HEIGHT / 8;
+
+/// Size of the data sent with the `_TRN` commands
+pub(crate) const SGB_TRANSFER_SIZE: usize = 0x1000;
+
+/// The border is made of 256 SNES tiles with 4 bits per pixel
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_HEIGHT: usize = 28;
+/// The border map is followed by palettes 4 to 7 in PCT_TRN data
+const BORDER_PALETTES_OFFSET: usize = 0x800;
+
+/// Colors used before the game sends its own
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+/// Commands sent in the first byte of a packet, shifted left by 3. The lower 3 bits are the
+/// number of packets
+mod command {
+    pub const PAL01: u8 = 0x00;
+    pub const PAL23: u8 = 0x01;
+    pub const PAL03: u8 = 0x02;
+    pub const PAL12: u8 = 0x03;
+    pub const ATTR_BLK: u8 = 0x04;
+    pub const ATTR_LIN: u8 = 0x05;
+    pub const ATTR_DIV: u8 = 0x06;
+    pub const ATTR_CHR: u8 = 0x07;
+    pub const MLT_REQ: u8 = 0x11;
+    pub const CHR_TRN: u8 = 0x13;
+    pub const PCT_TRN: u8 = 0x14;
+    pub const MASK_EN: u8 = 0x17;
+}
+
+/// What MASK_EN shows in place of the Game Boy picture
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+enum Mask {
+    #[default]
+    Cancel,
+    /// Keep showing the picture from when the mask was set
+    Freeze,
+    Black,
+    /// Color 0 of the first palette
+    Color0,
+}
+
+/// The Super Game Boy colors the DMG picture with four palettes picked per 8x8 cell, and draws a
+/// border around it. Games control it with the command packets received by the `Joypad`
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Sgb {
+    /// Colors in the RGB555 format. Color 0 is shared by all palettes
+    palettes: [[u16; 4]; 4],
+    /// Palette of each cell, row by row
+    attributes: Vec<u8>,
+    mask: Mask,
+    frozen: Option<GameFrame>,
+
+    border_tiles: Vec<u8>,
+    /// Tile number, palette and flips of every tile of the border
+    border_map: Vec<u16>,
+    /// Palettes 4 to 7 of the border. Color 0 is transparent
+    border_palettes: Vec<[u16; 16]>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Self {
+            palettes: [DEFAULT_PALETTE; 4],
+            attributes: vec![0; CELLS_X * CELLS_Y],
+            mask: Mask::Cancel,
+            frozen: None,
+            border_tiles: vec![0x00; BORDER_TILES * BORDER_TILE_SIZE],
+            border_map: vec![0x0000; BORDER_MAP_WIDTH * BORDER_MAP_HEIGHT],
+            border_palettes: vec![[0x0000; 16]; 4],
+        }
+    }
+
+    /// Run a command made of one or more packets. VRAM transfers take their data from what the
+    /// PPU shows
+    pub(crate) fn command(&mut self, data: &[u8], ppu: &Ppu) {
+        match data[0] >> 3 {
+            command::PAL01 => self.set_palettes(0, 1, data),
+            command::PAL23 => self.set_palettes(2, 3, data),
+            command::PAL03 => self.set_palettes(0, 3, data),
+            command::PAL12 => self.set_palettes(1, 2, data),
+            command::ATTR_BLK => self.attr_blk(data),
+            command::ATTR_LIN => self.attr_lin(data),
+            command::ATTR_DIV => self.attr_div(data),
+            command::ATTR_CHR => self.attr_chr(data),
+            // Handled by the `Joypad`
+            command::MLT_REQ => {}
+            command::CHR_TRN => {
+                let start = if data[1] & 0x01 == 0 {
+                    0
+                } else {
+                    SGB_TRANSFER_SIZE
+                };
+                self.border_tiles[start..start + SGB_TRANSFER_SIZE]
+                    .copy_from_slice(&ppu.sgb_transfer_data());
+            }
+            command::PCT_TRN => {
+                let transfer = ppu.sgb_transfer_data();
+                for (entry, bytes) in self.border_map.iter_mut().zip(transfer.chunks_exact(2)) {
+                    *entry = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+                let palettes = transfer[BORDER_PALETTES_OFFSET..].chunks_exact(2);
+                for (color, bytes) in self.border_palettes.iter_mut().flatten().zip(palettes) {
+                    *color = u16::from_le_bytes([bytes[0], bytes[1]]);
+                }
+            }
+            command::MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    0x00 => Mask::Cancel,
+                    0x01 => Mask::Freeze,
+                    0x02 => Mask::Black,
+                    _ => Mask::Color0,
+                };
+                self.frozen = (self.mask == Mask::Freeze).then(|| ppu.frame().clone());
+            }
+            unsupported => log::debug!("Unsupported SGB command {:#04X}", unsupported),
+        }
+    }
+
+    /// PALxx: color 0 for all palettes followed by colors 1 to 3 of two palettes
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        let mut colors = data[1..15]
+            .chunks_exact(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+
+        let color0 = colors.next().unwrap();
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color0;
+        }
+        for palette in [first, second] {
+            for color in self.palettes[palette][1..].iter_mut() {
+                *color = colors.next().unwrap();
+            }
+        }
+    }
+
+    fn set_attribute(&mut self, x: usize, y: usize, palette: u8) {
+        self.attributes[y * CELLS_X + x] = palette & 0x03;
+    }
+
+    /// ATTR_BLK: color the inside, the border and the outside of rectangles
+    fn attr_blk(&mut self, data: &[u8]) {
+        for set in data[2..].chunks_exact(6).take(data[1] as usize) {
+            let control = set[0] & 0x07;
+            let (inside, border, outside) = (set[1], set[1] >> 2, set[1] >> 4);
+            let (left, top) = ((set[2] & 0x1F) as usize, (set[3] & 0x1F) as usize);
+            let (right, bottom) = ((set[4] & 0x1F) as usize, (set[5] & 0x1F) as usize);
+
+            // Setting only the inside or only the outside also colors the border
+            let border = match control {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                _ => (control & 0x02 != 0).then_some(border),
+            };
+
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let palette = if x > left && x < right && y > top && y < bottom {
+                        (control & 0x01 != 0).then_some(inside)
+                    } else if x < left || x > right || y < top || y > bottom {
+                        (control & 0x04 != 0).then_some(outside)
+                    } else {
+                        border
+                    };
+
+                    if let Some(palette) = palette {
+                        self.set_attribute(x, y, palette);
+                    }
+                }
+            }
+        }
+    }
+
+    /// ATTR_LIN: color whole rows or columns
+    fn attr_lin(&mut self, data: &[u8]) {
+        for &line in data[2..].iter().take(data[1] as usize) {
+            let number = (line & 0x1F) as usize;
+            let palette = line >> 5;
+            if line & 0x80 != 0 {
+                if number < CELLS_Y {
+                    (0..CELLS_X).for_each(|x| self.set_attribute(x, number, palette));
+                }
+            } else if number < CELLS_X {
+                (0..CELLS_Y).for_each(|y| self.set_attribute(number, y, palette));
+            }
+        }
+    }
+
+    /// ATTR_DIV: split the screen in two along a row or a column
+    fn attr_div(&mut self, data: &[u8]) {
+        let (after, before, line) = (data[1], data[1] >> 2, data[1] >> 4);
+        let horizontal = data[1] & 0x40 != 0;
+        let coordinate = (data[2] & 0x1F) as usize;
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+                let palette = match position.cmp(&coordinate) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => line,
+                    std::cmp::Ordering::Greater => after,
+                };
+                self.set_attribute(x, y, palette);
+            }
+        }
+    }
+
+    /// ATTR_CHR: color cells one by one, 4 per byte, starting at a cell
+    fn attr_chr(&mut self, data: &[u8]) {
+        let (mut x, mut y) = ((data[1] & 0x1F) as usize, (data[2] & 0x1F) as usize);
+        let count = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let vertical = data[5] & 0x01 != 0;
+
+        for i in 0..count.min(CELLS_X * CELLS_Y) {
+            let Some(byte) = data.get(6 + i / 4) else {
+                break;
+            };
+            if x >= CELLS_X || y >= CELLS_Y {
+                break;
+            }
+
+            self.set_attribute(x, y, byte >> (6 - 2 * (i % 4)));
+            if vertical {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    /// Draw the colored Game Boy picture inside the border. `output` is resized to
+    /// `SGB_SCREEN_WIDTH` x `SGB_SCREEN_HEIGHT`
+    pub(crate) fn render(&self, frame: &GameFrame, output: &mut Texture) {
+        if output.width() != SGB_SCREEN_WIDTH || output.height() != SGB_SCREEN_HEIGHT {
+            *output = Texture::new(SGB_SCREEN_WIDTH, SGB_SCREEN_HEIGHT);
+        }
+
+        let backdrop = rgb555_to_rgba(self.palettes[0][0]);
+        output.data.fill(backdrop);
+
+        let frame = match (self.mask, self.frozen.as_ref()) {
+            (Mask::Freeze, Some(frozen)) => frozen,
+            _ => frame,
+        };
+        for y in 0..LCD_HEIGHT {
+            let row = &mut output.row_mut(GAME_Y + y)[GAME_X..GAME_X + LCD_WIDTH];
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = match self.mask {
+                    Mask::Black => rgb555_to_rgba(0x0000),
+                    Mask::Color0 => backdrop,
+                    Mask::Cancel | Mask::Freeze => {
+                        let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                        rgb555_to_rgba(self.palettes[palette][dmg_shade(frame.row(y)[x])])
+                    }
+                };
+            }
+        }
+
+        // The border is drawn over the Game Boy picture
+        for (index, &entry) in self.border_map.iter().enumerate() {
+            let (map_x, map_y) = (index % BORDER_MAP_WIDTH, index / BORDER_MAP_WIDTH);
+            let tile_start = (entry & 0xFF) as usize * BORDER_TILE_SIZE;
+            let tile = &self.border_tiles[tile_start..tile_start + BORDER_TILE_SIZE];
+            let palette = &self.border_palettes[((entry >> 10) & 0x03) as usize];
+            let (flip_x, flip_y) = (entry & 0x4000 != 0, entry & 0x8000 != 0);
+
+            for row in 0..8 {
+                let tile_row = if flip_y { 7 - row } else { row };
+                // Bitplanes 0 and 1 come first for all rows, followed by bitplanes 2 and 3
+                let planes = [
+                    tile[tile_row * 2],
+                    tile[tile_row * 2 + 1],
+                    tile[16 + tile_row * 2],
+                    tile[16 + tile_row * 2 + 1],
+                ];
+
+                let output_row = &mut output.row_mut(map_y * 8 + row)[map_x * 8..map_x * 8 + 8];
+                for (column, pixel) in output_row.iter_mut().enumerate() {
+                    let bit = if flip_x { column } else { 7 - column };
+                    let color = planes.iter().enumerate().fold(0, |color, (plane, data)| {
+                        color | (((data >> bit) & 0x01) as usize) << plane
+                    });
+
+                    if color != 0 {
+                        *pixel = rgb555_to_rgba(palette[color]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::{Joypad, JOYP_ADDRESS, SGB_PACKET_SIZE};
+    use crate::memory::Memory;
+    use crate::palettes::{RGBA_BLACK, RGBA_WHITE};
+
+    /// Pulse P14 and P15 the way SGB games send packets
+    fn send_packets(joypad: &mut Joypad, data: &[u8]) {
+        for packet in data.chunks(SGB_PACKET_SIZE) {
+            joypad.write(JOYP_ADDRESS, 0x00);
+            joypad.write(JOYP_ADDRESS, 0x30);
+            for bit in 0..SGB_PACKET_SIZE * 8 {
+                let one = packet[bit / 8] & (1 << (bit % 8)) != 0;
+                joypad.write(JOYP_ADDRESS, if one { 0x10 } else { 0x20 });
+                joypad.write(JOYP_ADDRESS, 0x30);
+            }
+            // Stop bit
+            joypad.write(JOYP_ADDRESS, 0x20);
+            joypad.write(JOYP_ADDRESS, 0x30);
+        }
+    }
+
+    fn packet(command: u8, packets: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x00; SGB_PACKET_SIZE * packets as usize];
+        packet[0] = (command << 3) | packets;
+        packet[1..=data.len()].copy_from_slice(data);
+        packet
+    }
+
+    fn rendered_cell(sgb: &Sgb, frame: &GameFrame, x: usize, y: usize) -> u32 {
+        let mut output = Texture::default();
+        sgb.render(frame, &mut output);
+        output.row(GAME_Y + y * 8)[GAME_X + x * 8].full()
+    }
+
+    #[test]
+    fn test_packets_are_received_through_joyp() {
+        let mut joypad = Joypad::new(true);
+        let pal01 = packet(command::PAL01, 1, &[0x12, 0x34]);
+        send_packets(&mut joypad, &pal01);
+        assert_eq!(joypad.take_sgb_commands(), vec![pal01.clone()]);
+
+        // Commands of several packets are only complete after the last one
+        let attr_blk = packet(command::ATTR_BLK, 2, &[0x03]);
+        send_packets(&mut joypad, &attr_blk[..SGB_PACKET_SIZE]);
+        assert!(joypad.take_sgb_commands().is_empty());
+        send_packets(&mut joypad, &attr_blk[SGB_PACKET_SIZE..]);
+        assert_eq!(joypad.take_sgb_commands(), vec![attr_blk]);
+
+        // Packets are ignored when the game does not support the SGB
+        let mut joypad = Joypad::new(false);
+        send_packets(&mut joypad, &pal01);
+        assert!(joypad.take_sgb_commands().is_empty());
+    }
+
+    #[test]
+    fn test_mlt_req_cycles_through_controllers() {
+        let mut joypad = Joypad::new(true);
+        send_packets(&mut joypad, &packet(command::MLT_REQ, 1, &[0x01]));
+
+        joypad.write(JOYP_ADDRESS, 0x30);
+        assert_eq!(joypad.read(JOYP_ADDRESS) & 0x0F, 0x0F);
+
+        // Controllers change when P15 goes back high
+        for expected in [0x0E, 0x0F, 0x0E] {
+            joypad.write(JOYP_ADDRESS, 0x10);
+            joypad.write(JOYP_ADDRESS, 0x30);
+            assert_eq!(joypad.read(JOYP_ADDRESS) & 0x0F, expected);
+        }
+
+        // The second controller has no keys pressed
+        joypad.keydown(crate::joypad::JoypadKeys::A);
+        joypad.write(JOYP_ADDRESS, 0x10);
+        assert_eq!(joypad.read(JOYP_ADDRESS) & 0x0F, 0x0F);
+    }
+
+    #[test]
+    fn test_palettes_and_attributes_color_the_picture() {
+        let ppu = Ppu::new();
+        let mut sgb = Sgb::new();
+        let mut frame = GameFrame::new(LCD_WIDTH, LCD_HEIGHT);
+        frame.data.fill(RGBA_BLACK);
+
+        // Color 0 is shared, colors 1 to 3 of palettes 0 and 1 follow
+        #[rustfmt::skip]
+        let colors = [
+            0x00, 0x00,
+            0x01, 0x00, 0x02, 0x00, 0x1F, 0x00,
+            0x01, 0x00, 0x02, 0x00, 0xE0, 0x03,
+        ];
+        sgb.command(&packet(command::PAL01, 1, &colors), &ppu);
+        assert_eq!(sgb.palettes[1][0], 0x0000);
+        assert_eq!(sgb.palettes[0][3], 0x001F);
+        assert_eq!(
+            rendered_cell(&sgb, &frame, 0, 0),
+            rgb555_to_rgba(0x001F).full()
+        );
+
+        // Everything right of column 10 uses palette 1, the column itself palette 2
+        sgb.command(&packet(command::ATTR_DIV, 1, &[0b10_00_01, 10]), &ppu);
+        assert_eq!(sgb.attributes[9], 0);
+        assert_eq!(sgb.attributes[10], 2);
+        assert_eq!(sgb.attributes[11], 1);
+        assert_eq!(
+            rendered_cell(&sgb, &frame, 11, 5),
+            rgb555_to_rgba(0x03E0).full()
+        );
+
+        // Inside only, which also colors the border of the block
+        let block = [0x01, 0x01, 0b11, 1, 1, 3, 3];
+        sgb.command(&packet(command::ATTR_BLK, 1, &block), &ppu);
+        assert_eq!(sgb.attributes[CELLS_X + 1], 3);
+        assert_eq!(sgb.attributes[2 * CELLS_X + 2], 3);
+        assert_eq!(sgb.attributes[4 * CELLS_X + 4], 0);
+
+        sgb.command(
+            &packet(command::ATTR_LIN, 1, &[0x01, 0x80 | (2 << 5) | 17]),
+            &ppu,
+        );
+        assert!(sgb.attributes[17 * CELLS_X..]
+            .iter()
+            .all(|&palette| palette == 2));
+
+        sgb.command(
+            &packet(command::ATTR_CHR, 1, &[19, 0, 2, 0, 1, (3 << 6) | (1 << 4)]),
+            &ppu,
+        );
+        assert_eq!(sgb.attributes[19], 3);
+        assert_eq!(sgb.attributes[CELLS_X + 19], 1);
+
+        // The screen can be blanked or frozen
+        sgb.command(&packet(command::MASK_EN, 1, &[0x02]), &ppu);
+        assert_eq!(
+            rendered_cell(&sgb, &frame, 0, 0),
+            rgb555_to_rgba(0x0000).full()
+        );
+        sgb.command(&packet(command::MASK_EN, 1, &[0x01]), &ppu);
+        frame.data.fill(RGBA_WHITE);
+        assert_eq!(
+            rendered_cell(&sgb, &frame, 11, 5),
+            rgb555_to_rgba(0x0000).full()
+        );
+        sgb.command(&packet(command::MASK_EN, 1, &[0x00]), &ppu);
+        assert_eq!(
+            rendered_cell(&sgb, &frame, 0, 0),
+            rgb555_to_rgba(0x0000).full()
+        );
+    }
+
+    #[test]
+    fn test_border_is_drawn_around_the_picture() {
+        let mut sgb = Sgb::new();
+        // Tile 1 is color 1 on its first row, and color 0 elsewhere
+        sgb.border_tiles[BORDER_TILE_SIZE] = 0xFF;
+        sgb.border_palettes[1][1] = 0x7C00;
+        // Tile 1 with palette 5 in the top left corner, flipped vertically
+        sgb.border_map[0] = 0x8000 | (5 << 10) | 0x01;
+
+        let mut output = Texture::default();
+        sgb.render(&GameFrame::new(LCD_WIDTH, LCD_HEIGHT), &mut output);
+        assert_eq!(output.width(), SGB_SCREEN_WIDTH);
+        assert_eq!(output.height(), SGB_SCREEN_HEIGHT);
+
+        let backdrop = rgb555_to_rgba(DEFAULT_PALETTE[0]).full();
+        assert_eq!(output.row(0)[0].full(), backdrop);
+        assert_eq!(output.row(7)[0].full(), rgb555_to_rgba(0x7C00).full());
+        assert_eq!(output.row(7)[8].full(), backdrop);
+    }
+}
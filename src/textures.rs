@@ -1,4 +1,6 @@
-#[derive(Clone, Copy, Debug, Default)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
 #[repr(C, packed)]
 pub struct RGBA(pub u8, pub u8, pub u8, pub u8);
 
@@ -29,39 +31,56 @@ impl RGBA {
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct Texture<const WIDTH: usize, const HEIGHT: usize> {
-    pub data: [[RGBA; WIDTH]; HEIGHT],
+/// Image with its size picked at runtime, stored row by row
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Texture {
+    width: usize,
+    height: usize,
+    pub data: Vec<RGBA>,
 }
 
-impl<const WIDTH: usize, const HEIGHT: usize> Texture<WIDTH, HEIGHT> {
+impl Texture {
+    /// Black texture of the given size
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![RGBA::default(); width * height],
+        }
+    }
+
     pub fn pitch(&self) -> usize {
-        WIDTH * std::mem::size_of::<RGBA>()
+        self.width * std::mem::size_of::<RGBA>()
     }
 
     pub const fn width(&self) -> usize {
-        WIDTH
+        self.width
     }
 
     pub const fn height(&self) -> usize {
-        HEIGHT
+        self.height
+    }
+
+    pub fn row(&self, y: usize) -> &[RGBA] {
+        &self.data[y * self.width..(y + 1) * self.width]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [RGBA] {
+        &mut self.data[y * self.width..(y + 1) * self.width]
     }
 }
 
 pub trait TextureInfo: Default {
-    const HEIGHT: usize;
-    const WIDTH: usize;
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
 }
 
-impl<const WIDTH: usize, const HEIGHT: usize> Default for Texture<WIDTH, HEIGHT> {
-    fn default() -> Self {
-        Self {
-            data: [[Default::default(); WIDTH]; HEIGHT],
-        }
+impl TextureInfo for Texture {
+    fn width(&self) -> usize {
+        self.width
     }
-}
 
-impl<const WIDTH: usize, const HEIGHT: usize> TextureInfo for Texture<WIDTH, HEIGHT> {
-    const HEIGHT: usize = HEIGHT;
-    const WIDTH: usize = WIDTH;
+    fn height(&self) -> usize {
+        self.height
+    }
 }
@@ -1,15 +1,18 @@
-use crate::{ExecutionState, SystemState};
+use serde::{Deserialize, Serialize};
+
 use crate::interrupts::{InterruptHandler, InterruptType};
 use crate::memory::Memory;
+use crate::{ExecutionState, SystemState};
 
 pub const TIMER_START: u16 = 0xFF04;
 pub const TIMER_END: u16 = 0xFF07;
 
-const DIV_ADDRESS: u16 = 0xFF04;
+pub(crate) const DIV_ADDRESS: u16 = 0xFF04;
 const TIMA_ADDRESS: u16 = 0xFF05;
 const TMA_ADDRESS: u16 = 0xFF06;
 const TIMER_CONTROL: u16 = 0xFF07;
 
+#[derive(Serialize, Deserialize)]
 pub(crate) struct Timer {
     div: u16,
     tima: u8,
@@ -22,6 +25,9 @@ pub(crate) struct Timer {
     /// t-cycle within that m-cycle TIMA overflowed and exactly 4 t-cycles later
     /// reset it with TMA
     tima_overflowed_last_cycle: Option<i32>,
+    /// TIMA was reset with TMA during the current m-cycle. Writes to TIMA are ignored in that
+    /// m-cycle and writes to TMA also go to TIMA
+    tima_reloaded: bool,
 }
 
 impl Timer {
@@ -34,6 +40,7 @@ impl Timer {
 
             previous_tima_inc_result: false,
             tima_overflowed_last_cycle: None,
+            tima_reloaded: false,
         }
     }
 
@@ -44,12 +51,14 @@ impl Timer {
             -1
         };
 
+        self.tima_reloaded = false;
         for i in 0..4 {
             self.div = self.div.wrapping_add(1);
             if self.div == 0x0000
                 && system_state.execution_state == ExecutionState::PreparingSpeedSwitch
             {
-                // DIV overflowed. Complete speed switch
+                // DIV overflowed. Complete speed switch. STOP reset DIV, so this takes 0x10000
+                // t-cycles of the old speed
                 system_state.key1 ^= 0x81; // Toggle speed and reset switch request
                 system_state.execution_state = ExecutionState::ExecutingProgram;
             }
@@ -59,25 +68,31 @@ impl Timer {
                 self.tima = self.tma;
                 interrupts.request_interrupt(InterruptType::Timer);
                 self.tima_overflowed_last_cycle = None;
+                self.tima_reloaded = true;
             }
 
-            let tima_increment_bit = self.div & tima_bit_position(self.tac) != 0;
-            let timer_enabled_bit = self.tac & 0b100 != 0;
-
-            let tima_inc_result = tima_increment_bit && timer_enabled_bit;
-            // Check for falling edge
-            if self.previous_tima_inc_result && !tima_inc_result {
-                let (inc_tima, overflow) = self.tima.overflowing_add(1);
-                self.tima = inc_tima;
-                if overflow {
-                    self.tima_overflowed_last_cycle = Some(i);
-                    // TIMA reads 0x00 for the 4 t-cycles before it is reset
-                    self.tima = 0x00;
-                }
-            }
+            self.check_for_falling_edge(i);
+        }
+    }
+
+    /// Increment TIMA on a falling edge of the selected DIV bit ANDed with the timer enable bit.
+    /// Besides DIV counting, writes to DIV and TAC can cause one too
+    fn check_for_falling_edge(&mut self, t_cycle: i32) {
+        let tima_increment_bit = self.div & tima_bit_position(self.tac) != 0;
+        let timer_enabled_bit = self.tac & 0b100 != 0;
 
-            self.previous_tima_inc_result = tima_inc_result;
+        let tima_inc_result = tima_increment_bit && timer_enabled_bit;
+        if self.previous_tima_inc_result && !tima_inc_result {
+            let (inc_tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = inc_tima;
+            if overflow {
+                self.tima_overflowed_last_cycle = Some(t_cycle);
+                // TIMA reads 0x00 for the 4 t-cycles before it is reset
+                self.tima = 0x00;
+            }
         }
+
+        self.previous_tima_inc_result = tima_inc_result;
     }
 }
 
@@ -94,10 +109,28 @@ impl Memory for Timer {
 
     fn write(&mut self, address: u16, data: u8) {
         match address {
-            DIV_ADDRESS => self.div = 0x0000,
-            TIMA_ADDRESS => self.tima = data,
-            TMA_ADDRESS => self.tma = data,
-            TIMER_CONTROL => self.tac = data & 0b111,
+            // Writes land at the end of the m-cycle, after its last t-cycle
+            DIV_ADDRESS => {
+                self.div = 0x0000;
+                self.check_for_falling_edge(3);
+            }
+            TIMA_ADDRESS if self.tima_reloaded => {}
+            TIMA_ADDRESS => {
+                // Writing TIMA between its overflow and the reset cancels the reset and the
+                // interrupt
+                self.tima = data;
+                self.tima_overflowed_last_cycle = None;
+            }
+            TMA_ADDRESS => {
+                self.tma = data;
+                if self.tima_reloaded {
+                    self.tima = data;
+                }
+            }
+            TIMER_CONTROL => {
+                self.tac = data & 0b111;
+                self.check_for_falling_edge(3);
+            }
             _ => panic!("Invalid address {:#6X} to Timer::write", address),
         }
     }
@@ -112,3 +145,104 @@ fn tima_bit_position(tac: u8) -> u16 {
         _ => panic!("This is not possible"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(timer: &mut Timer, interrupts: &mut InterruptHandler, cycles: usize) {
+        let mut system_state = SystemState::default();
+        for _ in 0..cycles {
+            timer.tick(&mut system_state, interrupts);
+        }
+    }
+
+    /// Timer counting every 16 t-cycles with TIMA about to overflow. Returns at the end of the
+    /// m-cycle TIMA overflowed in
+    fn overflowed_timer(interrupts: &mut InterruptHandler) -> Timer {
+        let mut timer = Timer::new();
+        timer.write(TMA_ADDRESS, 0x42);
+        timer.write(TIMA_ADDRESS, 0xFF);
+        timer.write(TIMER_CONTROL, 0b101);
+        run(&mut timer, interrupts, 4);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x00);
+        assert!(!interrupts.is_requested(InterruptType::Timer));
+        timer
+    }
+
+    #[test]
+    fn test_div_write_increments_tima_on_a_falling_edge() {
+        let mut interrupts = InterruptHandler::default();
+        let mut timer = Timer::new();
+        timer.write(TIMER_CONTROL, 0b101);
+
+        // The selected bit 3 of DIV is set after 8 t-cycles
+        run(&mut timer, &mut interrupts, 2);
+        timer.write(DIV_ADDRESS, 0x00);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x01);
+
+        // No falling edge with the bit clear
+        run(&mut timer, &mut interrupts, 1);
+        timer.write(DIV_ADDRESS, 0x00);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x01);
+    }
+
+    #[test]
+    fn test_tac_write_increments_tima_on_a_falling_edge() {
+        let mut interrupts = InterruptHandler::default();
+        let mut timer = Timer::new();
+        timer.write(TIMER_CONTROL, 0b101);
+        run(&mut timer, &mut interrupts, 2);
+
+        // Disabling the timer while the selected bit is set
+        timer.write(TIMER_CONTROL, 0b001);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x01);
+
+        // Selecting bit 9 of DIV, which is clear, instead of bit 3
+        timer.write(TIMER_CONTROL, 0b101);
+        timer.write(TIMER_CONTROL, 0b100);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x02);
+    }
+
+    #[test]
+    fn test_tima_write_during_reload() {
+        // Written in the m-cycle after the overflow, TIMA keeps the value and no interrupt is
+        // requested
+        let mut interrupts = InterruptHandler::default();
+        let mut timer = overflowed_timer(&mut interrupts);
+        timer.write(TIMA_ADDRESS, 0x10);
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x10);
+        assert!(!interrupts.is_requested(InterruptType::Timer));
+
+        // Written in the m-cycle TMA is loaded, the write is ignored
+        let mut interrupts = InterruptHandler::default();
+        let mut timer = overflowed_timer(&mut interrupts);
+        run(&mut timer, &mut interrupts, 1);
+        timer.write(TIMA_ADDRESS, 0x10);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x42);
+        assert!(interrupts.is_requested(InterruptType::Timer));
+
+        // The m-cycle after that, TIMA can be written again
+        run(&mut timer, &mut interrupts, 1);
+        timer.write(TIMA_ADDRESS, 0x10);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x10);
+    }
+
+    #[test]
+    fn test_tma_write_during_reload() {
+        // Written in the m-cycle TMA is loaded, TIMA gets the new value too
+        let mut interrupts = InterruptHandler::default();
+        let mut timer = overflowed_timer(&mut interrupts);
+        run(&mut timer, &mut interrupts, 1);
+        timer.write(TMA_ADDRESS, 0x99);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x99);
+
+        // Written before, TIMA is loaded with the new value
+        let mut interrupts = InterruptHandler::default();
+        let mut timer = overflowed_timer(&mut interrupts);
+        timer.write(TMA_ADDRESS, 0x99);
+        run(&mut timer, &mut interrupts, 1);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x99);
+    }
+}
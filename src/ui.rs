@@ -1,26 +1,30 @@
-use crate::GameFrame;
 use eframe::egui::load::SizedTexture;
 use eframe::egui::{menu, Color32, ColorImage, ImageSource, Key, RichText, TextureOptions};
 use eframe::epaint::ImageDelta;
 use eframe::glow::Context;
 use eframe::{self, egui, CreationContext};
-use gibi::cartridge::CartridgeHeader;
+use gibi::audio::{AudioOutput, AudioSink, CpalSink, NullSink};
+use gibi::cartridge::{CartridgeHeader, RtcMode, SensorImage};
 use gibi::cpu::Registers;
 use gibi::debug::{CpuDebug, ExecutedOpcode};
 use gibi::framebuffer::access;
-use gibi::gameboy::Gameboy;
+use gibi::gameboy::rewind::{Rewind, DEFAULT_MEMORY_BUDGET, DEFAULT_SNAPSHOT_INTERVAL};
+use gibi::gameboy::{Gameboy, GameboyOptions, FRAME_DURATION};
 use gibi::joypad::JoypadKeys;
 use gibi::{
     framebuffer,
-    ppu::{LCD_HEIGHT, LCD_WIDTH},
-    EmulatorEvent,
+    ppu::{Renderer, LCD_HEIGHT, LCD_WIDTH},
+    textures::Texture,
+    EmulatorEvent, HardwareModel,
 };
 use std::collections::HashMap;
 use std::default::Default;
 use std::io;
 use std::path::PathBuf;
 use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
 use std::thread::JoinHandle;
+use std::time::Instant;
 
 fn format_opcode(opcode: u8, arg1: u8, arg2: u8) -> String {
     match opcode {
@@ -283,6 +287,23 @@ fn format_opcode(opcode: u8, arg1: u8, arg2: u8) -> String {
     }
 }
 
+/// How strongly the fill level of the audio buffer speeds up or slows down emulation. At `0.1` an
+/// empty or full buffer changes the frame rate by 5%
+const FRAME_PACING_GAIN: f64 = 0.1;
+/// Number of frames the emulation can fall behind before giving up on catching up
+const MAX_FRAMES_BEHIND: u32 = 5;
+
+/// Gameplay is rewound while this key is held
+const REWIND_KEY: Key = Key::Backspace;
+const MAX_REWIND_BUDGET_MB: usize = 1024;
+/// Keys tilting the Game Boy left, right, up and down when tilting with the arrow keys
+const TILT_KEYS: [Key; 4] = [
+    Key::ArrowLeft,
+    Key::ArrowRight,
+    Key::ArrowUp,
+    Key::ArrowDown,
+];
+
 // Nearest neighbor filtering for the nice pixelated look
 const TEXTURE_OPTIONS: TextureOptions = TextureOptions {
     magnification: egui::TextureFilter::Nearest,
@@ -295,16 +316,22 @@ struct EmulatorCommCtx {
     command_tx: mpsc::SyncSender<EmulatorCommand>,
     event_rc: mpsc::Receiver<EmulatorEvent>,
     tex: egui::TextureHandle,
-    frame_reader: access::AccessR<GameFrame>,
+    frame_reader: access::AccessR<Texture>,
 }
 
 struct UiCommCtx {
-    frame_writer: access::AccessW<GameFrame>,
+    frame_writer: access::AccessW<Texture>,
     command_rc: mpsc::Receiver<EmulatorCommand>,
     event_tx: mpsc::Sender<EmulatorEvent>,
 }
 
-fn spawn(rom_path: &PathBuf, ctx: &egui::Context) -> io::Result<EmulatorCommCtx> {
+fn spawn(
+    rom_path: &PathBuf,
+    ctx: &egui::Context,
+    options: GameboyOptions,
+    rewind_budget_mb: usize,
+    rtc_mode: RtcMode,
+) -> io::Result<EmulatorCommCtx> {
     let tex = ctx.load_texture(
         "game-image",
         ColorImage::new([LCD_WIDTH, LCD_HEIGHT], Color32::BLACK),
@@ -315,7 +342,7 @@ fn spawn(rom_path: &PathBuf, ctx: &egui::Context) -> io::Result<EmulatorCommCtx>
     let save_file_path = rom_path.with_extension(".sav");
     let ram = std::fs::read(&save_file_path).ok();
 
-    let (frame_reader, frame_writer) = framebuffer::buffers::triple::new::<GameFrame>();
+    let (frame_reader, frame_writer) = framebuffer::buffers::triple::new::<Texture>();
     let (command_tx, command_rc) = mpsc::sync_channel(0);
     let (event_tx, event_rc) = mpsc::channel();
     let emulation_thread = {
@@ -331,7 +358,10 @@ fn spawn(rom_path: &PathBuf, ctx: &egui::Context) -> io::Result<EmulatorCommCtx>
                     },
                     rom,
                     ram,
+                    options,
                     save_file_path,
+                    rewind_budget_mb * 1024 * 1024,
+                    rtc_mode,
                 )
                 .run();
                 log::info!("Terminating emulation thread");
@@ -358,15 +388,50 @@ enum Panel {
     Cartridge,
 }
 
+/// Source of the tilt seen by the accelerometer of MBC7 cartridges
+#[derive(Default, Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+enum TiltInput {
+    /// Position of the mouse relative to the center of the game image
+    #[default]
+    Mouse,
+    /// The arrow keys tilt the Game Boy instead of pressing the D-pad
+    ArrowKeys,
+}
+
 #[derive(Default, serde::Deserialize, serde::Serialize)]
 pub struct GameboyApp {
     game_scale_factor: f32,
     recent_roms: Vec<PathBuf>,
     open_panel: Panel,
+    #[serde(default = "default_rewind_budget_mb")]
+    rewind_budget_mb: usize,
+    #[serde(default)]
+    rtc_mode: RtcMode,
+    #[serde(default)]
+    tilt_input: TiltInput,
+    /// Boot ROM run before the cartridge. The boot ROM is skipped when not set
+    #[serde(default)]
+    boot_rom_path: Option<PathBuf>,
+    #[serde(default)]
+    model: HardwareModel,
+    #[serde(default)]
+    renderer: Renderer,
 
     #[serde(skip)]
     paused: bool,
+    /// Last tilt sent to the emulation thread
+    #[serde(skip)]
+    tilt: (f32, f32),
+    /// Whether the arrow keys tilted the cartridge instead of pressing the D-pad last frame
+    #[serde(skip)]
+    arrow_keys_tilt: bool,
+    /// Area of the screen covered by the game image in the last frame
+    #[serde(skip)]
+    game_rect: Option<egui::Rect>,
 
+    /// Set when the game locked up the CPU, until another ROM is loaded
+    #[serde(skip)]
+    lockup: Option<(u16, u8)>,
     #[serde(skip)]
     cpu_debug: Option<CpuDebug>,
     #[serde(skip)]
@@ -375,6 +440,10 @@ pub struct GameboyApp {
     comm_ctx: Option<EmulatorCommCtx>,
 }
 
+fn default_rewind_budget_mb() -> usize {
+    DEFAULT_MEMORY_BUDGET / (1024 * 1024)
+}
+
 impl GameboyApp {
     pub fn new(cc: &CreationContext) -> Self {
         if let Some(storage) = cc.storage {
@@ -383,11 +452,31 @@ impl GameboyApp {
 
         Self {
             game_scale_factor: 5.0,
+            rewind_budget_mb: default_rewind_budget_mb(),
             paused: true,
             ..Self::default()
         }
     }
 
+    fn gameboy_options(&self) -> GameboyOptions {
+        let boot_rom = self
+            .boot_rom_path
+            .as_ref()
+            .and_then(|path| match std::fs::read(path) {
+                Ok(boot_rom) => Some(boot_rom),
+                Err(err) => {
+                    log::error!("Failed to load boot ROM file: {:?}", err);
+                    None
+                }
+            });
+
+        GameboyOptions {
+            model: self.model,
+            boot_rom,
+            renderer: self.renderer,
+        }
+    }
+
     fn send_command(&self, msg: EmulatorCommand) {
         if let Some(comm_ctx) = self.comm_ctx.as_ref() {
             comm_ctx
@@ -398,7 +487,7 @@ impl GameboyApp {
     }
 
     fn handle_input(&mut self, ctx: &egui::Context) {
-        let joypad_keymap: HashMap<Key, JoypadKeys> = HashMap::from([
+        let mut joypad_keymap: HashMap<Key, JoypadKeys> = HashMap::from([
             (Key::Z, JoypadKeys::B),
             (Key::X, JoypadKeys::A),
             (Key::N, JoypadKeys::Select),
@@ -409,6 +498,42 @@ impl GameboyApp {
             (Key::ArrowRight, JoypadKeys::Right),
         ]);
 
+        // Games without an accelerometer keep their D-pad
+        let arrow_keys_tilt = self.tilt_input == TiltInput::ArrowKeys
+            && self
+                .cart_header
+                .as_ref()
+                .is_some_and(|header| header.has_accelerometer());
+        let tilt = if arrow_keys_tilt {
+            for key in TILT_KEYS {
+                if let Some(joypad_key) = joypad_keymap.remove(&key) {
+                    // Release what the D-pad held, its key releases are not seen anymore
+                    if !self.arrow_keys_tilt {
+                        self.send_command(EmulatorCommand::KeyReleased(joypad_key));
+                    }
+                }
+            }
+            let [left, right, up, down] = TILT_KEYS.map(|key| ctx.input(|i| i.key_down(key)));
+            (
+                right as u8 as f32 - left as u8 as f32,
+                down as u8 as f32 - up as u8 as f32,
+            )
+        } else {
+            let pointer = ctx.input(|i| i.pointer.hover_pos());
+            match (self.game_rect, pointer) {
+                (Some(rect), Some(pos)) if rect.contains(pos) => {
+                    let offset = (pos - rect.center()) / (rect.size() / 2.0);
+                    (offset.x.clamp(-1.0, 1.0), offset.y.clamp(-1.0, 1.0))
+                }
+                _ => (0.0, 0.0),
+            }
+        };
+        self.arrow_keys_tilt = arrow_keys_tilt;
+        if tilt != self.tilt {
+            self.tilt = tilt;
+            self.send_command(EmulatorCommand::SetAccelerometer(tilt.0, tilt.1));
+        }
+
         for (key, joypad_key) in joypad_keymap {
             if ctx.input(|i| i.key_down(key)) {
                 self.send_command(EmulatorCommand::KeyPressed(joypad_key));
@@ -418,6 +543,14 @@ impl GameboyApp {
                 self.send_command(EmulatorCommand::KeyReleased(joypad_key));
             }
         }
+
+        if ctx.input(|i| i.key_pressed(REWIND_KEY)) {
+            self.send_command(EmulatorCommand::SetRewinding(true));
+        }
+
+        if ctx.input(|i| i.key_released(REWIND_KEY)) {
+            self.send_command(EmulatorCommand::SetRewinding(false));
+        }
     }
 
     fn show_debug_ui(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
@@ -437,6 +570,7 @@ impl GameboyApp {
                 ui.horizontal(|ui| {
                     if ui.button(if self.paused { "▶" } else { "⏸" }).clicked() {
                         self.paused = !self.paused;
+                        self.send_command(EmulatorCommand::SetPaused(self.paused));
                     }
                     if ui
                         .add_enabled(self.paused, egui::Button::new("Step"))
@@ -455,6 +589,12 @@ impl GameboyApp {
                         self.paused = true;
                     }
                 });
+                if let Some((address, opcode)) = self.lockup {
+                    ui.colored_label(
+                        ui.visuals().error_fg_color,
+                        format!("CPU locked up on illegal opcode {opcode:#04X} at {address:#06X}"),
+                    );
+                }
                 ui.separator();
 
                 egui::ScrollArea::vertical().show(ui, |ui| {
@@ -483,10 +623,11 @@ impl GameboyApp {
             ui.vertical_centered(|ui| {
                 if let Some(comm_ctx) = self.comm_ctx.as_ref() {
                     let tex = &comm_ctx.tex;
-                    ui.add(egui::Image::new(ImageSource::Texture(SizedTexture::new(
+                    let image = ui.add(egui::Image::new(ImageSource::Texture(SizedTexture::new(
                         tex,
                         tex.size_vec2() * self.game_scale_factor,
                     ))));
+                    self.game_rect = Some(image.rect);
                 }
             })
         });
@@ -504,10 +645,12 @@ impl GameboyApp {
                     self.send_command(EmulatorCommand::Exit);
                     self.paused = true;
                     if let Some(path) = rfd::FileDialog::new().pick_file() {
-                        match spawn(&path, ctx) {
+                        let options = self.gameboy_options();
+                        match spawn(&path, ctx, options, self.rewind_budget_mb, self.rtc_mode) {
                             Ok(comm_ctx) => self.comm_ctx = Some(comm_ctx),
                             Err(err) => log::error!("Failed to load ROM file: {:?}", err),
                         }
+                        self.lockup = None;
                         self.recent_roms.push(path);
                     }
                 }
@@ -516,10 +659,12 @@ impl GameboyApp {
                         if (ui.button(path.file_name().unwrap().to_str().unwrap())).clicked() {
                             self.send_command(EmulatorCommand::Exit);
                             self.paused = true;
-                            match spawn(path, ctx) {
+                            let options = self.gameboy_options();
+                            match spawn(path, ctx, options, self.rewind_budget_mb, self.rtc_mode) {
                                 Ok(comm_ctx) => self.comm_ctx = Some(comm_ctx),
                                 Err(err) => log::error!("Failed to load ROM file: {:?}", err),
                             }
+                            self.lockup = None;
                         }
                     }
                 });
@@ -528,7 +673,79 @@ impl GameboyApp {
                 }
             });
 
-            // ui.menu_button("Emulation", |_| {});
+            ui.menu_button("Emulation", |ui| {
+                let boot_rom = match self
+                    .boot_rom_path
+                    .as_ref()
+                    .and_then(|path| path.file_name())
+                {
+                    Some(name) => name.to_string_lossy().to_string(),
+                    None => "None".to_string(),
+                };
+                ui.label(format!("Boot ROM: {boot_rom}"));
+                ui.horizontal(|ui| {
+                    if ui.button("Select").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().pick_file() {
+                            self.boot_rom_path = Some(path);
+                        }
+                    }
+                    if ui.button("Clear").clicked() {
+                        self.boot_rom_path = None;
+                    }
+                });
+                ui.menu_button(format!("Model: {}", self.model), |ui| {
+                    for model in HardwareModel::ALL {
+                        ui.radio_value(&mut self.model, model, model.to_string());
+                    }
+                });
+                ui.label("PPU renderer");
+                ui.radio_value(&mut self.renderer, Renderer::PixelFifo, "Pixel FIFO");
+                ui.radio_value(&mut self.renderer, Renderer::Scanline, "Scanline (fast)");
+                ui.label("Applies to the next ROM opened");
+                ui.separator();
+
+                ui.label(format!("Hold {} to rewind", REWIND_KEY.name()));
+                let budget = ui.add(
+                    egui::Slider::new(&mut self.rewind_budget_mb, 0..=MAX_REWIND_BUDGET_MB)
+                        .text("Rewind memory (MB)"),
+                );
+                if budget.changed() {
+                    self.send_command(EmulatorCommand::SetRewindBudget(
+                        self.rewind_budget_mb * 1024 * 1024,
+                    ));
+                }
+                ui.separator();
+
+                ui.label("Cartridge clock");
+                let wall_clock =
+                    ui.radio_value(&mut self.rtc_mode, RtcMode::WallClock, "Wall clock");
+                let emulated = ui.radio_value(&mut self.rtc_mode, RtcMode::Emulated, "Emulated");
+                if wall_clock.changed() || emulated.changed() {
+                    self.send_command(EmulatorCommand::SetRtcMode(self.rtc_mode));
+                }
+                ui.separator();
+
+                ui.label("Camera sensor");
+                if ui.button("Load Image").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("PNG image", &["png"])
+                        .pick_file()
+                    {
+                        match SensorImage::load(&path) {
+                            Ok(image) => self.send_command(EmulatorCommand::SetCameraImage(image)),
+                            Err(err) => log::error!("Failed to load camera image: {err}"),
+                        }
+                    }
+                }
+                if ui.button("Test Pattern").clicked() {
+                    self.send_command(EmulatorCommand::SetCameraImage(SensorImage::test_pattern()));
+                }
+                ui.separator();
+
+                ui.label("Tilt sensor");
+                ui.radio_value(&mut self.tilt_input, TiltInput::Mouse, "Mouse");
+                ui.radio_value(&mut self.tilt_input, TiltInput::ArrowKeys, "Arrow keys");
+            });
 
             ui.menu_button("View", |ui| {
                 ui.menu_button("Scale", |ui| {
@@ -696,6 +913,14 @@ impl GameboyApp {
                 });
                 ui.end_row();
 
+                ui.label("Super Game Boy");
+                ui.label(if cart_header.sgb_supported {
+                    "Yes"
+                } else {
+                    "No"
+                });
+                ui.end_row();
+
                 ui.label("MBC Configuration");
                 ui.label(&cart_header.cart_type);
                 ui.end_row();
@@ -723,24 +948,20 @@ impl eframe::App for GameboyApp {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         self.handle_input(ctx);
 
+        // The emulation thread runs at its own pace. The UI only displays the latest frame
         if !self.paused {
-            self.send_command(EmulatorCommand::RunUntil(RunUntil::FrameEnd));
             self.send_command(EmulatorCommand::QueryDebug(self.open_panel));
         }
 
         if let Some(comm_ctx) = self.comm_ctx.as_mut() {
+            let mut frame_completed = false;
             while let Ok(event) = comm_ctx.event_rc.try_recv() {
                 match event {
-                    EmulatorEvent::CompletedFrame => {
-                        let frame = comm_ctx.frame_reader.get().read().data.as_slice();
-                        let frame_slice = unsafe { to_byte_slice(frame) };
-                        let image = ColorImage::from_rgba_unmultiplied(
-                            [LCD_WIDTH, LCD_HEIGHT],
-                            frame_slice,
-                        );
-                        let delta = ImageDelta::full(image, TEXTURE_OPTIONS);
-                        ctx.tex_manager().write().set(comm_ctx.tex.id(), delta);
+                    EmulatorEvent::CompletedFrame => frame_completed = true,
+                    EmulatorEvent::CpuLockedUp { address, opcode } => {
+                        self.lockup = Some((address, opcode))
                     }
+                    EmulatorEvent::CpuResumed => self.lockup = None,
                     EmulatorEvent::CpuRegisters(cpu_registers) => {
                         self.cpu_debug = Some(cpu_registers)
                     }
@@ -749,6 +970,19 @@ impl eframe::App for GameboyApp {
                     }
                 }
             }
+
+            // Several frames may have completed since the last repaint on slow monitors
+            if frame_completed {
+                // The size changes with the model: the SGB draws a border around the LCD image
+                let frame = comm_ctx.frame_reader.get().read();
+                let frame_slice = unsafe { to_byte_slice(frame.data.as_slice()) };
+                let image = ColorImage::from_rgba_unmultiplied(
+                    [frame.width(), frame.height()],
+                    frame_slice,
+                );
+                let delta = ImageDelta::full(image, TEXTURE_OPTIONS);
+                ctx.tex_manager().write().set(comm_ctx.tex.id(), delta);
+            }
         }
 
         self.show_debug_ui(ctx, frame);
@@ -787,6 +1021,15 @@ enum RunUntil {
 #[derive(Debug)]
 enum EmulatorCommand {
     RunUntil(RunUntil),
+    SetPaused(bool),
+
+    // Rewind
+    SetRewinding(bool),
+    SetRewindBudget(usize),
+
+    SetRtcMode(RtcMode),
+    SetCameraImage(SensorImage),
+    SetAccelerometer(f32, f32),
 
     // Debug
     QueryDebug(Panel),
@@ -802,7 +1045,15 @@ enum EmulatorCommand {
 struct EmulationThread {
     gameboy: Gameboy,
     comm_ctx: UiCommCtx,
+    audio: AudioOutput,
     save_file_path: PathBuf,
+    rewind: Rewind,
+
+    paused: bool,
+    /// Whether the lock-up of the CPU has been reported to the UI
+    locked_up: bool,
+    rewinding: bool,
+    next_frame_deadline: Instant,
 }
 
 impl EmulationThread {
@@ -810,49 +1061,163 @@ impl EmulationThread {
         comm_ctx: UiCommCtx,
         rom: Vec<u8>,
         ram: Option<Vec<u8>>,
+        options: GameboyOptions,
         save_file_path: PathBuf,
+        rewind_budget: usize,
+        rtc_mode: RtcMode,
     ) -> Self {
-        let (gameboy, cart_header) = Gameboy::new(rom, ram);
+        let (mut gameboy, cart_header) = Gameboy::new(rom, ram, options);
+        gameboy.set_rtc_mode(rtc_mode);
         comm_ctx
             .event_tx
             .send(EmulatorEvent::CartridgeInfo(cart_header))
             .unwrap();
+
+        // The audio stream has to be created on the thread that owns it
+        let sink: Box<dyn AudioSink> = match CpalSink::new() {
+            Ok(sink) => Box::new(sink),
+            Err(err) => {
+                log::error!("Failed to open audio device: {err}. Audio is disabled");
+                Box::new(NullSink::default())
+            }
+        };
+
         Self {
             comm_ctx,
             gameboy,
+            audio: AudioOutput::new(sink),
             save_file_path,
+            rewind: Rewind::new(DEFAULT_SNAPSHOT_INTERVAL, rewind_budget),
+            paused: true,
+            locked_up: false,
+            rewinding: false,
+            next_frame_deadline: Instant::now(),
         }
     }
 
     fn run(&mut self) {
         loop {
-            match self.comm_ctx.command_rc.recv() {
-                Ok(m) => match m {
-                    EmulatorCommand::RunUntil(RunUntil::FrameEnd) => {
-                        self.gameboy.run_one_frame();
-                        self.gameboy.write_frame(&mut self.comm_ctx.frame_writer);
-                        self.send_event(EmulatorEvent::CompletedFrame);
-                    }
-                    EmulatorCommand::RunUntil(_) => {
-                        todo!("Emulator run until commands");
+            let command = if self.paused {
+                match self.comm_ctx.command_rc.recv() {
+                    Ok(command) => Some(command),
+                    Err(e) => {
+                        log::error!("{}", e);
+                        break;
                     }
-                    EmulatorCommand::QueryDebug(panel) => self.send_debug_for_panel(panel),
-                    EmulatorCommand::KeyPressed(key) => self.gameboy.keydown(key),
-                    EmulatorCommand::KeyReleased(key) => self.gameboy.keyup(key),
-                    EmulatorCommand::Exit => {
-                        match self.gameboy.save(&self.save_file_path) {
-                            Ok(msg) => log::info!("{msg}"),
-                            Err(err) => log::error!("{err:?}"),
-                        }
-                        log::info!("Received request to quit. Terminate emulation thread");
+                }
+            } else {
+                // Wait for commands until it is time to run the next frame
+                let timeout = self
+                    .next_frame_deadline
+                    .saturating_duration_since(Instant::now());
+                match self.comm_ctx.command_rc.recv_timeout(timeout) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        log::error!("UI disconnected from the emulation thread");
                         break;
                     }
-                },
-                Err(e) => log::error!("{}", e),
+                }
+            };
+
+            match command {
+                Some(EmulatorCommand::Exit) => {
+                    match self.gameboy.save(&self.save_file_path) {
+                        Ok(msg) => log::info!("{msg}"),
+                        Err(err) => log::error!("{err:?}"),
+                    }
+                    log::info!("Received request to quit. Terminate emulation thread");
+                    break;
+                }
+                Some(command) => self.handle_command(command),
+                None => self.run_paced_frame(),
             }
         }
     }
 
+    fn handle_command(&mut self, command: EmulatorCommand) {
+        match command {
+            EmulatorCommand::RunUntil(RunUntil::FrameEnd) => self.run_frame(),
+            EmulatorCommand::RunUntil(_) => {
+                todo!("Emulator run until commands");
+            }
+            EmulatorCommand::SetPaused(paused) => {
+                self.paused = paused;
+                self.next_frame_deadline = Instant::now();
+            }
+            EmulatorCommand::SetRewinding(rewinding) => self.rewinding = rewinding,
+            EmulatorCommand::SetRewindBudget(budget) => self.rewind.set_memory_budget(budget),
+            EmulatorCommand::SetRtcMode(mode) => self.gameboy.set_rtc_mode(mode),
+            EmulatorCommand::SetCameraImage(image) => self.gameboy.set_camera_image(image),
+            EmulatorCommand::SetAccelerometer(x, y) => self.gameboy.set_accelerometer(x, y),
+            EmulatorCommand::QueryDebug(panel) => self.send_debug_for_panel(panel),
+            EmulatorCommand::KeyPressed(key) => self.gameboy.keydown(key),
+            EmulatorCommand::KeyReleased(key) => self.gameboy.keyup(key),
+            EmulatorCommand::Exit => {}
+        }
+    }
+
+    fn run_frame(&mut self) {
+        if let Err(err) = self.rewind.record_frame(&self.gameboy) {
+            log::error!("Failed to record frame for rewinding: {err}");
+        }
+
+        self.gameboy.run_one_frame();
+        self.audio.queue(&self.gameboy.take_audio_samples());
+        self.gameboy.write_frame(&mut self.comm_ctx.frame_writer);
+        self.send_event(EmulatorEvent::CompletedFrame);
+        self.report_lockup();
+    }
+
+    /// Tell the UI when the CPU locks up, and when rewinding undoes it
+    fn report_lockup(&mut self) {
+        let lockup = self.gameboy.lockup();
+        match (lockup, self.locked_up) {
+            (Some((address, opcode)), false) => {
+                self.send_event(EmulatorEvent::CpuLockedUp { address, opcode })
+            }
+            (None, true) => self.send_event(EmulatorEvent::CpuResumed),
+            _ => {}
+        }
+        self.locked_up = lockup.is_some();
+    }
+
+    /// Step back one frame. The last frame stays on screen once the start of the history is
+    /// reached
+    fn rewind_frame(&mut self) {
+        match self.rewind.step_back(&mut self.gameboy) {
+            Ok(true) => {
+                self.gameboy.write_frame(&mut self.comm_ctx.frame_writer);
+                self.send_event(EmulatorEvent::CompletedFrame);
+                self.report_lockup();
+            }
+            Ok(false) => {}
+            Err(err) => log::error!("Failed to rewind: {err}"),
+        }
+    }
+
+    /// Run a frame and schedule the next one. The emulation keeps its own ~59.73Hz clock which is
+    /// slaved to the fill level of the audio buffer: frames are run slightly faster when the
+    /// buffer is running low and slightly slower when it is filling up
+    fn run_paced_frame(&mut self) {
+        if self.rewinding {
+            self.rewind_frame();
+        } else {
+            self.run_frame();
+        }
+
+        let correction = 1.0 + FRAME_PACING_GAIN * (self.audio.fill_level() - 0.5);
+        self.next_frame_deadline += FRAME_DURATION.mul_f64(correction);
+
+        // Don't try to catch up if we fell behind by a lot, e.g., after the thread was descheduled
+        let now = Instant::now();
+        if now.saturating_duration_since(self.next_frame_deadline)
+            > FRAME_DURATION * MAX_FRAMES_BEHIND
+        {
+            self.next_frame_deadline = now;
+        }
+    }
+
     fn send_event(&mut self, event: EmulatorEvent) {
         self.comm_ctx.event_tx.send(event).unwrap_or_else(|_| {
             log::error!("Failed to send emulator event");